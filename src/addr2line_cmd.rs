@@ -5,7 +5,7 @@ use std::str::FromStr;
 use std::str;
 use itertools::Itertools;
 
-#[derive(Debug,Clone,PartialEq,Eq,Hash)]
+#[derive(Debug,Clone,PartialEq,Eq,Hash,Serialize,Deserialize)]
 pub struct StackFrameInfo {
     pub function_name: String,
     pub file_path_str: String,
@@ -40,27 +40,6 @@ named!(parse_one_stackframe<StackFrameInfo>, do_parse!(
 
 named!(parse_addr2line_output<&[u8], Vec<StackFrameInfo>>, many0!(parse_one_stackframe));
 
-pub fn get_addr2line_stack(lib_path: &str, addr: u64) -> Result<Vec<StackFrameInfo>, io::Error> {
-    let addr2line_output = Command::new("addr2line")
-        .args(
-            &[
-                "--functions",
-                "--demangle",
-                "--inlines",
-                &format!("--exe={}", lib_path),
-                &format!("0x{:x}", addr),
-            ],
-        )
-        .output()?
-        .stdout;
-    if let IResult::Done(_, mut result) = parse_addr2line_output(&addr2line_output) {
-        result.reverse();
-        Ok(result)
-    } else {
-        Ok(Vec::new())
-    }
-}
-
 pub fn get_addr2line_symbols_with_inline(
     lib_path: &str,
     addrs: &Vec<u64>,
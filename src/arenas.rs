@@ -1,6 +1,7 @@
 use ranges::Ranges;
 use std::collections::{HashMap, HashSet};
 
+#[derive(Clone, Serialize, Deserialize)]
 struct Thing {
     ident: String,
     associated_things: HashMap<String, String>,
@@ -48,6 +49,7 @@ impl Thing {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 struct Arena {
     isa: Thing,
     memory_ranges: Ranges,
@@ -89,6 +91,12 @@ impl Arena {
     }
 }
 
+/// The association with the alphabetically first type.
+fn first_association(thing: &Thing) -> Option<(&String, &String)> {
+    thing.associated_things.iter().min()
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Arenas {
     arenas: HashMap<String, Arena>,
     things: HashMap<String, Thing>,
@@ -156,6 +164,61 @@ impl Arenas {
             .description(&|thing_ident| self.get_thing_already_exists(thing_ident))
     }
 
+    /// Describes the arena by its type and the types of the things it's
+    /// associated with, e.g. "ArenaAllocator (nsDisplayListBuilder)". Unlike
+    /// the description, this doesn't contain any addresses, so it can be used
+    /// to match up arenas from different logs.
+    pub fn arena_kind(&self, ident: &str) -> String {
+        let arena_type = match ident.find(':') {
+            Some(pos) => &ident[..pos],
+            None => ident,
+        };
+        let owner_types = self.arena_owner_types(ident);
+        if owner_types.is_empty() {
+            return arena_type.to_owned();
+        }
+        format!("{} ({})", arena_type, owner_types.join(", "))
+    }
+
+    /// The type of the thing that ultimately owns the arena, found by
+    /// following the associations from the arena, e.g. "PresShell" for an
+    /// arena that belongs to the nsPresArena of a PresShell. None if the
+    /// arena isn't associated with anything.
+    pub fn arena_owner(&self, ident: &str) -> Option<String> {
+        let arena = self.arenas.get(ident)?;
+        let mut seen = HashSet::new();
+        seen.insert(ident.to_owned());
+        let mut owner = None;
+        let mut next = first_association(&arena.isa);
+        while let Some((thing_type, thing_ident)) = next {
+            if !seen.insert(thing_ident.to_owned()) {
+                break;
+            }
+            owner = Some(thing_type.to_owned());
+            next = self.things.get(thing_ident).and_then(|thing| {
+                // Things are associated in both directions, so skip the ones
+                // we came from.
+                let mut associations: Vec<(&String, &String)> = thing.associated_things
+                    .iter()
+                    .filter(|&(_, ident)| !seen.contains(ident))
+                    .collect();
+                associations.sort();
+                associations.into_iter().next()
+            });
+        }
+        owner
+    }
+
+    /// The sorted types of the things that the arena is associated with.
+    pub fn arena_owner_types<'a>(&'a self, ident: &str) -> Vec<&'a str> {
+        let mut owner_types: Vec<&str> = match self.arenas.get(ident) {
+            Some(arena) => arena.isa.associated_things.keys().map(|t| t.as_str()).collect(),
+            None => Vec::new(),
+        };
+        owner_types.sort();
+        owner_types
+    }
+
     #[allow(dead_code)]
     pub fn arena_ranges<'a>(&'a self, ident: &str) -> Option<&'a Ranges> {
         self.arenas.get(ident).map(|arena| arena.ranges())
@@ -183,6 +246,12 @@ fn test_arenas() {
     arenas.associate_arena_with_thing("ArenaAllocator:0x3", "nsPresArena", "nsPresArena:0x8");
     arenas.associate_arena_with_thing("ArenaAllocator:0x3", "nsPresArena", "nsPresArena:0x4");
     assert_eq!(arenas.arena_description("ArenaAllocator:0x3"), "{ nsPresArena:0x4: { PresShell:0x5: { url: dl-test.html } } }");
+    assert_eq!(arenas.arena_kind("ArenaAllocator:0x1"), "ArenaAllocator (nsDisplayListBuilder)");
+    assert_eq!(arenas.arena_kind("ArenaAllocator:0x3"), "ArenaAllocator (nsPresArena)");
+    assert_eq!(arenas.arena_kind("ArenaAllocator:0x9"), "ArenaAllocator");
+    assert_eq!(arenas.arena_owner("ArenaAllocator:0x1"), Some("nsDisplayListBuilder".to_owned()));
+    assert_eq!(arenas.arena_owner("ArenaAllocator:0x3"), Some("PresShell".to_owned()));
+    assert_eq!(arenas.arena_owner("ArenaAllocator:0x9"), None);
 
     arenas.allocate_chunk("ArenaAllocator:0x3", 100, 30);
     arenas.allocate_chunk("ArenaAllocator:0x3", 140, 10);
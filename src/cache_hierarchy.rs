@@ -0,0 +1,471 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::iter;
+use pretty_bytes::converter::convert;
+use cache_log_info::{AccessKind, CacheLineRead, ReadsCollector};
+use cache_simulation::percentage;
+use cpucache::{CacheConfig, CPUCache};
+use log_events::consume_log;
+
+/// How the levels of a cache hierarchy share lines.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Inclusion {
+    /// Every line of a level is also in all outer levels, so a line that an
+    /// outer level evicts is invalidated in the inner levels.
+    Inclusive,
+    /// Every line is in at most one level. Lines from memory only go to L1,
+    /// lines that L1 evicts go to L2, and so on, and a line that's found in
+    /// an outer level moves to L1.
+    Exclusive,
+    /// Lines from memory go to every level, and every level evicts lines
+    /// independently.
+    NonInclusive,
+}
+
+impl Inclusion {
+    pub fn parse(s: &str) -> Option<Inclusion> {
+        match s {
+            "inclusive" => Some(Inclusion::Inclusive),
+            "exclusive" => Some(Inclusion::Exclusive),
+            "non-inclusive" => Some(Inclusion::NonInclusive),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Inclusion::Inclusive => "inclusive",
+            Inclusion::Exclusive => "exclusive",
+            Inclusion::NonInclusive => "non-inclusive",
+        }
+    }
+}
+
+/// Parses the size and associativity of a cache level, e.g. "32K/8".
+pub fn parse_cache_level(s: &str, line_size: u32) -> Option<CacheConfig> {
+    let mut parts = s.split('/');
+    let size = ::cache_simulation::parse_cache_size(parts.next()?)?;
+    let assoc = parts.next()?.trim().parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(CacheConfig { size, line_size, assoc })
+}
+
+/// The geometries of the levels of a hierarchy. All levels have the line
+/// size of the recorded cache.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct HierarchyConfig {
+    pub l1d: CacheConfig,
+    pub l1i: CacheConfig,
+    pub l2: CacheConfig,
+    pub llc: CacheConfig,
+}
+
+const L1D: usize = 0;
+const L1I: usize = 1;
+const L2: usize = 2;
+const LLC: usize = 3;
+const LEVEL_NAMES: [&str; 4] = ["L1D", "L1I", "L2", "LLC"];
+
+/// The levels that are closer to the CPU than the given one.
+fn inner_levels(level: usize) -> &'static [usize] {
+    match level {
+        L2 => &[L1D, L1I],
+        LLC => &[L1D, L1I, L2],
+        _ => &[],
+    }
+}
+
+struct Level {
+    config: CacheConfig,
+    cache: CPUCache,
+    accesses: u64,
+    hits: u64,
+    fills: u64,
+    /// The most bytes that were used of each cached line during one
+    /// recorded read, by line address.
+    used_bytes_per_line: HashMap<u64, u8>,
+    used_bytes: u64,
+}
+
+impl Level {
+    fn new(config: CacheConfig) -> Level {
+        Level {
+            config,
+            cache: CPUCache::from_config(&config),
+            accesses: 0,
+            hits: 0,
+            fills: 0,
+            used_bytes_per_line: HashMap::new(),
+            used_bytes: 0,
+        }
+    }
+
+    fn contains(&self, line: u64) -> bool {
+        self.used_bytes_per_line.contains_key(&line)
+    }
+
+    fn lookup(&mut self, line: u64) -> bool {
+        self.accesses += 1;
+        let is_hit = self.cache.lookup(line);
+        if is_hit {
+            self.hits += 1;
+        }
+        is_hit
+    }
+
+    /// Returns the evicted line, if any, with its used bytes.
+    fn fill(&mut self, line: u64, used_bytes: u8) -> Option<(u64, u8)> {
+        self.fills += 1;
+        let evicted = self.cache.fill(line).map(|evicted| (evicted, self.remove(evicted)));
+        self.used_bytes_per_line.insert(line, used_bytes);
+        evicted
+    }
+
+    fn invalidate(&mut self, line: u64) -> Option<u8> {
+        if self.cache.invalidate(line) {
+            Some(self.remove(line))
+        } else {
+            None
+        }
+    }
+
+    fn remove(&mut self, line: u64) -> u8 {
+        let used_bytes = self.used_bytes_per_line.remove(&line).unwrap_or(0);
+        self.used_bytes += used_bytes as u64;
+        used_bytes
+    }
+
+    fn record_use(&mut self, line: u64, used_bytes: u8) {
+        if let Some(line_used_bytes) = self.used_bytes_per_line.get_mut(&line) {
+            *line_used_bytes = cmp::max(*line_used_bytes, used_bytes);
+        }
+    }
+
+    /// The used bytes include those of the lines that this level still
+    /// holds.
+    fn into_result(self, name: &str) -> LevelResult {
+        let used_bytes = self.used_bytes + self.used_bytes_per_line.values().map(|&used| used as u64).sum::<u64>();
+        LevelResult {
+            name: name.to_owned(),
+            config: self.config,
+            accesses: self.accesses,
+            hits: self.hits,
+            misses: self.accesses - self.hits,
+            bytes_transferred: self.fills * self.config.line_size as u64,
+            used_bytes,
+        }
+    }
+}
+
+/// How many accesses were served by L1, L2, LLC and memory.
+#[derive(Default, Serialize)]
+pub struct AccessesServedBy {
+    pub l1: u64,
+    pub l2: u64,
+    pub llc: u64,
+    pub memory: u64,
+}
+
+impl AccessesServedBy {
+    fn add(&mut self, level: usize) {
+        match level {
+            L1D | L1I => self.l1 += 1,
+            L2 => self.l2 += 1,
+            LLC => self.llc += 1,
+            _ => self.memory += 1,
+        }
+    }
+}
+
+const MEMORY: usize = 4;
+
+struct Hierarchy {
+    inclusion: Inclusion,
+    levels: Vec<Level>,
+    data: AccessesServedBy,
+    instructions: AccessesServedBy,
+}
+
+impl Hierarchy {
+    fn new(config: &HierarchyConfig, inclusion: Inclusion) -> Hierarchy {
+        Hierarchy {
+            inclusion,
+            levels: [config.l1d, config.l1i, config.l2, config.llc]
+                .iter()
+                .map(|&config| Level::new(config))
+                .collect(),
+            data: AccessesServedBy::default(),
+            instructions: AccessesServedBy::default(),
+        }
+    }
+
+    fn access(&mut self, read: &CacheLineRead) {
+        let line = read.address;
+        let used_bytes = read.used_bytes.unwrap_or(read.size);
+        let kind = read.kind.unwrap_or(AccessKind::Data);
+        let l1 = if kind == AccessKind::Instruction { L1I } else { L1D };
+        let served_by = match self.inclusion {
+            Inclusion::Exclusive => self.access_exclusive(l1, line, used_bytes),
+            _ => self.access_non_exclusive(l1, line, used_bytes),
+        };
+        match kind {
+            AccessKind::Data => self.data.add(served_by),
+            AccessKind::Instruction => self.instructions.add(served_by),
+        }
+        for level in &mut self.levels {
+            level.record_use(line, used_bytes);
+        }
+    }
+
+    /// Looks for the line from L1 outwards, and fills it into the levels
+    /// that didn't have it. Returns the level that had it.
+    fn access_non_exclusive(&mut self, l1: usize, line: u64, used_bytes: u8) -> usize {
+        let path = [l1, L2, LLC];
+        let hit_index = path
+            .iter()
+            .position(|&level| self.levels[level].lookup(line))
+            .unwrap_or(path.len());
+        // Fill the levels in the order in which the line travels from
+        // memory to the CPU.
+        for &level in path[..hit_index].iter().rev() {
+            if let Some((evicted, _)) = self.levels[level].fill(line, used_bytes) {
+                if self.inclusion == Inclusion::Inclusive {
+                    for &inner_level in inner_levels(level) {
+                        self.levels[inner_level].invalidate(evicted);
+                    }
+                }
+            }
+        }
+        path.get(hit_index).cloned().unwrap_or(MEMORY)
+    }
+
+    /// Moves the line to L1 from the level that has it, or from memory, and
+    /// passes the lines that are evicted on the way down to the next level.
+    fn access_exclusive(&mut self, l1: usize, line: u64, used_bytes: u8) -> usize {
+        if self.levels[l1].lookup(line) {
+            return l1;
+        }
+        let served_by = [L2, LLC]
+            .iter()
+            .cloned()
+            .find(|&level| self.levels[level].lookup(line))
+            .unwrap_or(MEMORY);
+        if served_by != MEMORY {
+            self.levels[served_by].invalidate(line);
+        }
+        let mut victim = self.levels[l1].fill(line, used_bytes);
+        for &level in &[L2, LLC] {
+            victim = match victim {
+                // The other L1 can have the line too, e.g. if it's code that
+                // was also read as data.
+                Some((evicted, evicted_used_bytes)) if self.levels[level].contains(evicted) => {
+                    self.levels[level].record_use(evicted, evicted_used_bytes);
+                    None
+                }
+                Some((evicted, evicted_used_bytes)) => self.levels[level].fill(evicted, evicted_used_bytes),
+                None => None,
+            };
+        }
+        served_by
+    }
+
+    fn into_result(self) -> HierarchySimulationResult {
+        HierarchySimulationResult {
+            inclusion: self.inclusion,
+            levels: self
+                .levels
+                .into_iter()
+                .zip(LEVEL_NAMES.iter())
+                .map(|(level, name)| level.into_result(name))
+                .collect(),
+            data: self.data,
+            instructions: self.instructions,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LevelResult {
+    pub name: String,
+    pub config: CacheConfig,
+    /// The lookups in this level, i.e. the accesses that missed all levels
+    /// between it and the CPU. In exclusive hierarchies, the lines that the
+    /// level above evicts into this one aren't lookups.
+    pub accesses: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// The bytes of the lines that were put into this level, from memory or,
+    /// in exclusive hierarchies, from the level above.
+    pub bytes_transferred: u64,
+    /// How many of the transferred bytes were used while the lines were in
+    /// this level.
+    pub used_bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct HierarchySimulationResult {
+    pub inclusion: Inclusion,
+    pub levels: Vec<LevelResult>,
+    pub data: AccessesServedBy,
+    pub instructions: AccessesServedBy,
+}
+
+impl HierarchySimulationResult {
+    fn print(&self) {
+        println!("{}:", self.inclusion.name());
+        println!(
+            "  {:<6} {:>8} {:>6} {:>10} {:>10} {:>10} {:>12} {:>7}",
+            "Level", "Size", "Assoc", "Accesses", "Hits", "Misses", "Transferred", "Used"
+        );
+        for level in &self.levels {
+            println!(
+                "  {:<6} {:>7}K {:>6} {:>10} {:>10} {:>10} {:>12} {:>6.1}%",
+                level.name,
+                level.config.size / 1024,
+                format!("{}-way", level.config.assoc),
+                level.accesses,
+                level.hits,
+                level.misses,
+                convert(level.bytes_transferred as f64),
+                percentage(level.used_bytes, level.bytes_transferred)
+            );
+        }
+        println!("  {:<14} {:>10} {:>10} {:>10} {:>10}", "Served by", "L1", "L2", "LLC", "Memory");
+        for &(name, served_by) in &[("Data", &self.data), ("Instructions", &self.instructions)] {
+            println!(
+                "  {:<14} {:>10} {:>10} {:>10} {:>10}",
+                name, served_by.l1, served_by.l2, served_by.llc, served_by.memory
+            );
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct HierarchySimulationReport {
+    pub config: HierarchyConfig,
+    pub results: Vec<HierarchySimulationResult>,
+}
+
+impl HierarchySimulationReport {
+    pub fn print(&self) {
+        for result in &self.results {
+            result.print();
+            println!();
+        }
+    }
+}
+
+/// Replays the cache lines that were read between from_line and to_line in
+/// a hierarchy of L1D, L1I, L2 and LLC, once for every inclusion mode.
+/// Instruction fetches go to L1I and data accesses to L1D, depending on the
+/// reason of the miss in the log. The log only has the accesses that
+/// missed the recorded cache, and the recorded cache is the only one that
+/// the valgrind patch simulates, so the replay misses all accesses that hit
+/// it; the inner levels only get realistic numbers if the log was recorded
+/// with a small cache.
+pub fn simulate_hierarchy<T>(
+    pid: i32,
+    iter: T,
+    from_line: usize,
+    to_line: usize,
+    config: &HierarchyConfig,
+    inclusions: &[Inclusion],
+) -> HierarchySimulationReport
+where
+    T: iter::Iterator<Item = (usize, String)>,
+{
+    let mut reads_info = ReadsCollector::for_line_range(from_line, to_line);
+    consume_log(pid, iter, to_line, &mut [&mut reads_info]);
+    simulate_hierarchy_for_reads(&reads_info.into_reads(), config, inclusions)
+}
+
+fn simulate_hierarchy_for_reads(
+    reads: &[CacheLineRead],
+    config: &HierarchyConfig,
+    inclusions: &[Inclusion],
+) -> HierarchySimulationReport {
+    let mut hierarchies: Vec<Hierarchy> = inclusions
+        .iter()
+        .map(|&inclusion| Hierarchy::new(config, inclusion))
+        .collect();
+    for read in reads {
+        for hierarchy in &mut hierarchies {
+            hierarchy.access(read);
+        }
+    }
+    HierarchySimulationReport {
+        config: *config,
+        results: hierarchies.into_iter().map(Hierarchy::into_result).collect(),
+    }
+}
+
+#[test]
+fn test_simulate_hierarchy() {
+    assert_eq!(
+        parse_cache_level("32K/8", 64),
+        Some(CacheConfig { size: 32768, line_size: 64, assoc: 8 })
+    );
+    assert_eq!(parse_cache_level("32K", 64), None);
+
+    let read = |address, kind| ::cache_log_info::test_read(address, 16, Some(kind));
+    // One line per L1, two in L2 and four in the LLC, all fully associative.
+    let level = |lines| CacheConfig { size: 64 * lines, line_size: 64, assoc: lines };
+    let config = HierarchyConfig {
+        l1d: level(1),
+        l1i: level(1),
+        l2: level(2),
+        llc: level(4),
+    };
+    let mut reads = vec![
+        read(0x1000, AccessKind::Data),
+        read(0x2000, AccessKind::Data),
+        read(0x3000, AccessKind::Instruction),
+        read(0x4000, AccessKind::Data),
+        read(0x1000, AccessKind::Data),
+    ];
+    let inclusions = [Inclusion::Inclusive, Inclusion::Exclusive, Inclusion::NonInclusive];
+    let report = simulate_hierarchy_for_reads(&reads, &config, &inclusions);
+    let served_by = |result: &HierarchySimulationResult| {
+        (result.data.l1, result.data.l2, result.data.llc, result.data.memory, result.instructions.memory)
+    };
+    let transferred = |result: &HierarchySimulationResult| -> Vec<u64> {
+        result.levels.iter().map(|level| level.bytes_transferred / 64).collect()
+    };
+    // L2 only keeps 0x3000 and 0x4000, but the LLC still has 0x1000.
+    assert_eq!(served_by(&report.results[0]), (0, 0, 1, 3, 1));
+    assert_eq!(transferred(&report.results[0]), vec![4, 1, 5, 4]);
+    // Lines from memory only go to L1, and the lines that L1D evicts go to
+    // L2, which still has 0x1000 because it doesn't hold copies of the
+    // lines in L1. Nothing is evicted from L2, so the LLC stays empty.
+    assert_eq!(served_by(&report.results[1]), (0, 1, 0, 3, 1));
+    assert_eq!(transferred(&report.results[1]), vec![4, 1, 3, 0]);
+    assert_eq!(served_by(&report.results[2]), (0, 0, 1, 3, 1));
+    assert_eq!(report.results[2].levels[LLC].used_bytes, 4 * 16);
+
+    reads.push(read(0x1000, AccessKind::Data));
+    reads.push(read(0x2000, AccessKind::Data));
+    let report = simulate_hierarchy_for_reads(&reads, &config, &inclusions);
+    for result in &report.results {
+        let levels = &result.levels;
+        // Every level is only asked for the lines that all levels between it
+        // and the CPU missed, and each hit is an access served by it.
+        assert_eq!(levels[L1D].accesses + levels[L1I].accesses, reads.len() as u64);
+        assert_eq!(levels[L1D].hits + levels[L1I].hits, result.data.l1 + result.instructions.l1);
+        assert_eq!(levels[L2].accesses, levels[L1D].misses + levels[L1I].misses);
+        assert_eq!(levels[L2].hits, result.data.l2 + result.instructions.l2);
+        assert_eq!(levels[LLC].accesses, levels[L2].misses);
+        assert_eq!(levels[LLC].hits, result.data.llc + result.instructions.llc);
+        assert_eq!(levels[LLC].misses, result.data.memory + result.instructions.memory);
+        for level in levels {
+            assert_eq!(level.hits + level.misses, level.accesses);
+        }
+    }
+    // In the exclusive hierarchy, the second read of 0x1000 moved it from L2
+    // to L1D, where the third one hits. 0x2000 was evicted from L1D into L2
+    // and is found there.
+    let counts: Vec<(u64, u64)> = report.results[1].levels.iter().map(|level| (level.accesses, level.hits)).collect();
+    assert_eq!(counts, vec![(6, 1), (1, 0), (6, 2), (4, 0)]);
+}
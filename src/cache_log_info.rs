@@ -1,20 +1,24 @@
 use std::iter;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Display;
 use cache_log_parsing::{parse_line_of_pid, LineContent};
+use log_events::{consume_log, LogEventConsumer};
+use log_index::SectionBoundaries;
 use ranges::Ranges;
-use cpucache::CPUCache;
+use cpucache::CacheConfig;
+use cache_simulation::percentage;
 use stack_table::StackTable;
 use shared_libraries::SharedLibraries;
 use arenas::Arenas;
-use profile::ProfileBuilder;
-use rand::{self, Rng};
+use profile::{ProfileBuilder, ProfileFormat};
 use pretty_bytes::converter::convert;
 use fixed_circular_buffer::CircularBuffer;
 use addr2line_cmd::StackFrameInfo;
 use std::path::Path;
 use std::io::{self, BufRead, BufReader};
-use std::fs::File;
+use std::fs::{self, File};
+use serde::Serialize;
+use serde_json;
 
 #[derive(Debug)]
 struct PIDs {
@@ -33,8 +37,77 @@ impl PIDs {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).expect("serializing to JSON failed"));
+}
+
+#[derive(Serialize)]
+struct ProcessLineCount {
+    pid: i32,
+    line_count: usize,
+}
+
+/// The parent process is the first process in the log, and the primary
+/// content process is the child process with the most log lines.
+#[derive(Serialize)]
+struct ProcessList {
+    parent_process: Option<ProcessLineCount>,
+    primary_content_process: Option<ProcessLineCount>,
+    other_child_processes: Vec<ProcessLineCount>,
+}
+
+impl ProcessList {
+    pub fn from_pids(pids: PIDs) -> ProcessList {
+        let mut pid_iter = pids.pids.into_iter();
+        let parent_process = pid_iter.next();
+        let mut remaining_pids: Vec<(i32, usize)> = pid_iter.collect();
+        remaining_pids.sort_by(|&(_, ref a), &(_, b)| b.cmp(a));
+        let mut pid_iter = remaining_pids.into_iter();
+        let primary_content_process = pid_iter.next();
+        let to_line_count = |(pid, line_count)| ProcessLineCount { pid, line_count };
+        ProcessList {
+            parent_process: parent_process.map(to_line_count),
+            primary_content_process: primary_content_process.map(to_line_count),
+            other_child_processes: pid_iter.map(to_line_count).collect(),
+        }
+    }
+
+    pub fn print(&self) {
+        if let Some(ref parent_process) = self.parent_process {
+            println!(
+                "Parent process: {} ({} log lines)",
+                parent_process.pid,
+                parent_process.line_count
+            );
+            if let Some(ref primary_content_process) = self.primary_content_process {
+                println!(
+                    "Primary content process: {} ({} log lines)",
+                    primary_content_process.pid,
+                    primary_content_process.line_count
+                );
+                if !self.other_child_processes.is_empty() {
+                    println!("Other child processes:");
+                    for other_process in &self.other_child_processes {
+                        println!(" - {} ({} log lines)", other_process.pid, other_process.line_count);
+                    }
+                }
+            } else {
+                println!("No child process found.");
+            }
+        } else {
+            println!("Did not find any processes in the log.");
+        }
+    }
+}
+
 #[allow(dead_code)]
-pub fn print_process_info<T>(iter: T)
+pub fn print_process_info<T>(iter: T, format: OutputFormat)
 where
     T: iter::Iterator<Item = (usize, String)>,
 {
@@ -44,47 +117,37 @@ where
             pids.increment(pid);
         }
     }
-    let mut pid_iter = pids.pids.into_iter();
-    if let Some((parent_process_pid, line_count)) = pid_iter.next() {
-        println!(
-            "Parent process: {} ({} log lines)",
-            parent_process_pid,
-            line_count
-        );
-        let mut remaining_pids: Vec<(i32, usize)> = pid_iter.collect();
-        remaining_pids.sort_by(|&(_, ref a), &(_, b)| b.cmp(a));
-        let mut pid_iter = remaining_pids.into_iter().peekable();
-        if let Some((primary_content_process, line_count)) = pid_iter.next() {
-            println!(
-                "Primary content process: {} ({} log lines)",
-                primary_content_process,
-                line_count
-            );
-            if let Some(_) = pid_iter.peek() {
-                println!("Other child processes:");
-                for (other_pid, line_count) in pid_iter {
-                    println!(" - {} ({} log lines)", other_pid, line_count);
-                }
-            }
-        } else {
-            println!("No child process found.");
-        }
-    } else {
-        println!("Did not find any processes in the log.");
+    let process_list = ProcessList::from_pids(pids);
+    match format {
+        OutputFormat::Text => process_list.print(),
+        OutputFormat::Json => print_json(&process_list),
     }
 }
 
-struct DisplayListBuildingSection {
+struct Section {
+    name: String,
+    /// None if the markers didn't specify a thread, in which case the section
+    /// covers the reads from all threads.
+    tid: Option<u32>,
+    /// How many sections of the same thread were open when this one began.
+    depth: usize,
     start_line_index: usize,
     end_line_index: Option<usize>,
+    /// The line ranges during which nested sections were open. Reads in these
+    /// ranges don't count towards the exclusive bytes of this section.
+    nested_line_ranges: Vec<(usize, usize)>,
     reads_info: ReadsCollector,
 }
 
-impl DisplayListBuildingSection {
-    pub fn new(start_line_index: usize) -> DisplayListBuildingSection {
-        DisplayListBuildingSection {
+impl Section {
+    pub fn new(name: &str, tid: Option<u32>, depth: usize, start_line_index: usize) -> Section {
+        Section {
+            name: name.to_owned(),
+            tid,
+            depth,
             start_line_index,
             end_line_index: None,
+            nested_line_ranges: Vec::new(),
             reads_info: ReadsCollector::new(),
         }
     }
@@ -95,38 +158,128 @@ impl DisplayListBuildingSection {
 
     pub fn process_line(&mut self, line_index: usize, line_contents: &LineContent) {
         assert!(line_index >= self.start_line_index);
-        self.reads_info.process_line(
-            line_index,
-            self.end_line_index == None,
-            line_contents,
-        );
+        self.reads_info.process_line(line_index, line_contents);
     }
 
     pub fn found_section_end(&mut self, line_index: usize) {
         self.end_line_index = Some(line_index);
+        self.reads_info.set_end_line_index(line_index);
+    }
+
+    pub fn found_nested_section(&mut self, start_line_index: usize, end_line_index: usize) {
+        self.nested_line_ranges.push((start_line_index, end_line_index));
     }
 
-    pub fn print_info(self) {
+    pub fn into_summary(mut self) -> SectionSummary {
         let mut bytes_read: u64 = 0;
         let mut bytes_used: u64 = 0;
+        let mut exclusive_bytes_read: u64 = 0;
+        let mut exclusive_bytes_used: u64 = 0;
         let mut ranges_read = Ranges::new();
 
+        // Nested sections can overlap if their markers weren't balanced, so
+        // merge the ranges before looking up reads in them.
+        self.nested_line_ranges.sort();
+        let mut nested_line_ranges: Vec<(usize, usize)> = Vec::new();
+        for (start, end) in self.nested_line_ranges {
+            match nested_line_ranges.last_mut() {
+                Some(&mut (_, ref mut last_end)) if start <= *last_end => {
+                    *last_end = (*last_end).max(end);
+                }
+                _ => nested_line_ranges.push((start, end)),
+            }
+        }
+        let is_in_nested_section = |line_index: usize| match nested_line_ranges
+            .binary_search_by_key(&line_index, |&(start, _)| start)
+        {
+            Ok(_) => true,
+            Err(0) => false,
+            Err(i) => line_index < nested_line_ranges[i - 1].1,
+        };
+
+        let tid = self.tid;
         for CacheLineRead {
-            line_index: _,
+            line_index,
             address,
             size,
             used_bytes,
             stack: _,
-        } in self.reads_info.into_reads()
+            tid: _,
+            kind: _,
+        } in self.reads_info
+            .into_reads()
+            .into_iter()
+            .filter(|read| tid.is_none() || read.tid == tid)
         {
+            let used_bytes = used_bytes.unwrap_or(size) as u64;
             bytes_read += size as u64;
-            bytes_used += used_bytes.unwrap_or(size) as u64;
+            bytes_used += used_bytes;
             ranges_read.add(address, size as u64);
+            if !is_in_nested_section(line_index) {
+                exclusive_bytes_read += size as u64;
+                exclusive_bytes_used += used_bytes;
+            }
         }
 
+        let unique_bytes_read = ranges_read.cumulative_size();
+        SectionSummary {
+            name: self.name,
+            number: 0,
+            tid: self.tid,
+            depth: self.depth,
+            start_line_index: self.start_line_index,
+            end_line_index: self.end_line_index,
+            bytes_read,
+            unique_bytes_read,
+            bytes_used,
+            exclusive_bytes_read,
+            exclusive_bytes_used,
+            multi_read_overhead_percent: percentage(bytes_read.saturating_sub(unique_bytes_read), unique_bytes_read),
+            cache_line_overhead_percent: percentage(bytes_read.saturating_sub(bytes_used), bytes_used),
+        }
+    }
+}
+
+/// The numbers that list-sections prints for each section. The reads
+/// themselves are dropped once a section is summarized. The bytes_* fields
+/// include the reads in nested sections, the exclusive_bytes_* fields don't.
+#[derive(Serialize)]
+pub struct SectionSummary {
+    name: String,
+    /// Sections with the same name are numbered in the order in which they
+    /// begin, starting at 1, so that they can be referred to as <name>#<n>.
+    number: usize,
+    tid: Option<u32>,
+    depth: usize,
+    start_line_index: usize,
+    end_line_index: Option<usize>,
+    pub bytes_read: u64,
+    pub unique_bytes_read: u64,
+    pub bytes_used: u64,
+    exclusive_bytes_read: u64,
+    exclusive_bytes_used: u64,
+    pub multi_read_overhead_percent: f64,
+    pub cache_line_overhead_percent: f64,
+}
+
+impl SectionSummary {
+    pub fn print_info(&self) {
+        let thread = match self.tid {
+            Some(tid) => format!(" on thread {}", tid),
+            None => "".to_owned(),
+        };
+        let nesting = match self.depth {
+            0 => "".to_owned(),
+            1 => " (nested in 1 other section)".to_owned(),
+            depth => format!(" (nested in {} other sections)", depth),
+        };
         println!(
-            "  - DisplayList section which contains {} of memory reads",
-            convert(bytes_read as f64)
+            "  - {}#{} section{}{} which contains {} of memory reads",
+            self.name,
+            self.number,
+            thread,
+            nesting,
+            convert(self.bytes_read as f64)
         );
         if let Some(end_line_index) = self.end_line_index {
             println!(
@@ -142,124 +295,514 @@ impl DisplayListBuildingSection {
             );
         }
 
-        let ranges_read_size = ranges_read.cumulative_size();
-        let multi_read_overhead = (bytes_read as f64 / ranges_read_size as f64 - 1.0) * 100.0;
-        let cache_line_overhead = (bytes_read as f64 / bytes_used as f64 - 1.0) * 100.0;
         println!(
             "      - read {} bytes from memory into the LL cache in total",
-            bytes_read,
+            self.bytes_read
         );
         println!(
             "      - read {} bytes of unique address ranges into the cache",
-            ranges_read_size,
+            self.unique_bytes_read
         );
         println!(
             "         => {:.0}% overhead due to memory ranges that were read more than once",
-            multi_read_overhead
+            self.multi_read_overhead_percent
         );
         println!(
             "      - accessed {} bytes",
-            bytes_used,
+            self.bytes_used
         );
         println!(
             "         => {:.0}% overhead from unused parts of cache lines",
-            cache_line_overhead
+            self.cache_line_overhead_percent
+        );
+        println!(
+            "      - read {} bytes and accessed {} bytes outside of nested sections",
+            self.exclusive_bytes_read,
+            self.exclusive_bytes_used
         );
         println!("");
     }
 }
 
+#[derive(Serialize)]
+struct SectionList {
+    sections: Vec<SectionSummary>,
+}
+
+/// Prints the summaries grouped by section name, with the names in the order
+/// in which they first appeared in the log.
+fn print_section_summaries(summaries: Vec<SectionSummary>) {
+    let mut names: Vec<String> = Vec::new();
+    let mut summaries_by_name: HashMap<String, Vec<SectionSummary>> = HashMap::new();
+    for summary in summaries {
+        if !summaries_by_name.contains_key(&summary.name) {
+            names.push(summary.name.clone());
+        }
+        summaries_by_name
+            .entry(summary.name.clone())
+            .or_default()
+            .push(summary);
+    }
+    for name in names {
+        let summaries = summaries_by_name.remove(&name).unwrap();
+        let total_bytes_read: u64 = summaries.iter().map(|summary| summary.bytes_read).sum();
+        println!(
+            "{} ({} {}, {} of memory reads in total):",
+            name,
+            summaries.len(),
+            if summaries.len() == 1 { "section" } else { "sections" },
+            convert(total_bytes_read as f64)
+        );
+        for summary in summaries {
+            summary.print_info();
+        }
+    }
+}
+
+/// Keeps track of the sections between "Begin <name>" and "End <name>"
+/// markers. Sections can nest, and markers with a tid only nest with other
+/// markers of the same thread. Unbalanced markers are reported as warnings.
+struct SectionTracker<'a> {
+    section_name: Option<&'a str>,
+    open_sections: HashMap<Option<u32>, Vec<Section>>,
+    /// Sections that have ended but whose reads are still waiting for
+    /// used_bytes or stack information from later lines.
+    pending_sections: Vec<Section>,
+    summaries: Vec<SectionSummary>,
+}
+
+impl<'a> SectionTracker<'a> {
+    /// If section_name is given, only sections with that name are tracked.
+    pub fn new(section_name: Option<&'a str>) -> SectionTracker<'a> {
+        SectionTracker {
+            section_name,
+            open_sections: HashMap::new(),
+            pending_sections: Vec::new(),
+            summaries: Vec::new(),
+        }
+    }
+
+    fn begin_section(&mut self, line_index: usize, name: &str, tid: Option<u32>) {
+        let open_sections = self.open_sections.entry(tid).or_default();
+        let depth = open_sections.len();
+        open_sections.push(Section::new(name, tid, depth, line_index));
+    }
+
+    fn end_section(&mut self, line_index: usize, name: &str, tid: Option<u32>) {
+        let open_sections = self.open_sections.entry(tid).or_default();
+        let position = match open_sections.iter().rposition(|section| section.name == name) {
+            Some(position) => position,
+            None => {
+                eprintln!(
+                    "Warning: line {}: End {} without a matching Begin, ignoring it.",
+                    line_index,
+                    name
+                );
+                return;
+            }
+        };
+        let mut section = open_sections.remove(position);
+        for still_open_section in &open_sections[position..] {
+            eprintln!(
+                "Warning: line {}: End {} while {} (begun at line {}) is still open; the sections overlap.",
+                line_index,
+                name,
+                still_open_section.name,
+                still_open_section.start_line_index
+            );
+            section.found_nested_section(still_open_section.start_line_index, line_index);
+        }
+        if position > 0 {
+            open_sections[position - 1].found_nested_section(section.start_line_index, line_index);
+        }
+        section.found_section_end(line_index);
+        self.pending_sections.push(section);
+    }
+
+    /// Warns about the sections that never ended, and returns the summaries
+    /// of all sections, ordered by their start and numbered.
+    pub fn finish(mut self) -> Vec<SectionSummary> {
+        if !self.pending_sections.is_empty() {
+            eprintln!(
+                "Have sections for which I don't know all the bytes_used information. Going to assume that the full cache line was used."
+            );
+            self.summaries.extend(self.pending_sections.into_iter().map(|section| section.into_summary()));
+        }
+        for section in self.open_sections.into_values().flat_map(|sections| sections.into_iter()) {
+            eprintln!(
+                "Warning: {} section begun at line {} has no End.",
+                section.name,
+                section.start_line_index
+            );
+            self.summaries.push(section.into_summary());
+        }
+        self.summaries.sort_by_key(|summary| summary.start_line_index);
+        let mut section_count_for_name: HashMap<String, usize> = HashMap::new();
+        for summary in &mut self.summaries {
+            let count = section_count_for_name.entry(summary.name.clone()).or_insert(0);
+            *count += 1;
+            summary.number = *count;
+        }
+        self.summaries
+    }
+}
+
+impl<'a> LogEventConsumer for SectionTracker<'a> {
+    fn process_line(&mut self, line_index: usize, line_contents: &LineContent) {
+        match *line_contents {
+            LineContent::BeginSection { name, .. } |
+            LineContent::EndSection { name, .. } if self.section_name.is_some_and(|n| n != name) => {}
+            LineContent::BeginSection { name, tid } => self.begin_section(line_index, name, tid),
+            LineContent::EndSection { name, tid } => self.end_section(line_index, name, tid),
+            ref line_contents => {
+                for section in self.open_sections.values_mut().flat_map(|sections| sections.iter_mut()) {
+                    section.process_line(line_index, line_contents);
+                }
+                let mut still_pending_sections = Vec::new();
+                for mut section in self.pending_sections.drain(..) {
+                    section.process_line(line_index, line_contents);
+                    if section.needs_more_lines() {
+                        still_pending_sections.push(section);
+                    } else {
+                        self.summaries.push(section.into_summary());
+                    }
+                }
+                self.pending_sections = still_pending_sections;
+            }
+        }
+    }
+}
+
+/// Finds the sections between "Begin <name>" and "End <name>" markers and
+/// prints how much memory they read. If section_name is given, only sections
+/// with that name are considered.
 #[allow(dead_code)]
-pub fn print_display_list_info<T>(pid: i32, iter: T)
+pub fn print_section_info<T>(pid: i32, iter: T, section_name: Option<&str>, format: OutputFormat)
 where
     T: iter::Iterator<Item = (usize, String)>,
 {
-    let mut pending_dlb_sections: Vec<DisplayListBuildingSection> = Vec::new();
-    let mut current_dlb_section: Option<DisplayListBuildingSection> = None;
-    for (line_index, line) in iter {
-        if let Some((p, line_contents)) = parse_line_of_pid(&line) {
-            if p != pid {
-                continue;
-            }
-            match line_contents {
-                LineContent::BeginDisplayList => {
-                    current_dlb_section = Some(DisplayListBuildingSection::new(line_index));
-                }
-                LineContent::EndDisplayList => {
-                    current_dlb_section
-                        .as_mut()
-                        .expect("Unbalanced End DisplayList")
-                        .found_section_end(line_index);
-                    pending_dlb_sections.push(current_dlb_section.take().unwrap());
+    let mut section_tracker = SectionTracker::new(section_name);
+    consume_log(pid, iter, usize::MAX, &mut [&mut section_tracker]);
+    let summaries = section_tracker.finish();
+    match format {
+        OutputFormat::Text => print_section_summaries(summaries),
+        OutputFormat::Json => print_json(&SectionList { sections: summaries }),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SectionSelection {
+    /// The n-th section with the given name, counting from 1.
+    Numbered(String, usize),
+    /// The section that read the most bytes.
+    Largest,
+    All,
+}
+
+impl SectionSelection {
+    /// Parses "<name>#<n>", e.g. "DisplayList building#3".
+    pub fn parse_numbered(s: &str) -> Option<SectionSelection> {
+        let pos = s.rfind('#')?;
+        let number = s[pos + 1..].parse().ok()?;
+        Some(SectionSelection::Numbered(s[..pos].to_owned(), number))
+    }
+}
+
+/// The line range of a section, with a label of the form <name>#<n>.
+pub struct SectionRange {
+    pub label: String,
+    pub start_line_index: usize,
+    /// usize::MAX if the section never ended.
+    pub end_line_index: usize,
+}
+
+/// Finds the start and the end of the n-th section with the given name,
+/// matching the markers like SectionTracker does.
+struct NumberedSectionFinder<'a> {
+    name: &'a str,
+    number: usize,
+    begun_sections: usize,
+    /// The start lines of the open sections with the name, per thread.
+    open_sections: HashMap<Option<u32>, Vec<usize>>,
+    /// The start line and the thread of the n-th section, once it has begun.
+    section: Option<(usize, Option<u32>)>,
+    end_line_index: Option<usize>,
+}
+
+impl<'a> NumberedSectionFinder<'a> {
+    fn new(name: &'a str, number: usize) -> NumberedSectionFinder<'a> {
+        NumberedSectionFinder {
+            name,
+            number,
+            begun_sections: 0,
+            open_sections: HashMap::new(),
+            section: None,
+            end_line_index: None,
+        }
+    }
+
+    fn into_section_range(self) -> Option<SectionRange> {
+        let (start_line_index, _) = self.section?;
+        Some(SectionRange {
+            label: format!("{}#{}", self.name, self.number),
+            start_line_index,
+            end_line_index: self.end_line_index.unwrap_or(usize::MAX),
+        })
+    }
+}
+
+impl<'a> LogEventConsumer for NumberedSectionFinder<'a> {
+    fn process_line(&mut self, line_index: usize, line_contents: &LineContent) {
+        match *line_contents {
+            LineContent::BeginSection { name, tid } if name == self.name => {
+                self.begun_sections += 1;
+                if self.begun_sections == self.number {
+                    self.section = Some((line_index, tid));
                 }
-                line_contents => {
-                    if let Some(current_dlb_section) = current_dlb_section.as_mut() {
-                        current_dlb_section.process_line(line_index, &line_contents);
-                    }
-                    pending_dlb_sections = pending_dlb_sections
-                        .into_iter()
-                        .flat_map(|mut section| {
-                            section.process_line(line_index, &line_contents);
-                            if section.needs_more_lines() {
-                                return Some(section);
-                            }
-
-                            section.print_info();
-                            None
-                        })
-                        .collect();
+                self.open_sections.entry(tid).or_default().push(line_index);
+            }
+            LineContent::EndSection { name, tid } if name == self.name => {
+                let start_line_index = self.open_sections.get_mut(&tid).and_then(|open_sections| open_sections.pop());
+                if start_line_index.is_some() && start_line_index.map(|start| (start, tid)) == self.section {
+                    self.end_line_index = Some(line_index);
                 }
             }
-        };
+            _ => {}
+        }
     }
-    if !pending_dlb_sections.is_empty() {
-        println!(
-            "Have sections for which I don't know all the bytes_used information. Going to assume that the full cache line was used."
-        );
-        for section in pending_dlb_sections.into_iter() {
-            section.print_info();
+
+    fn needs_more_lines(&self) -> bool {
+        self.end_line_index.is_none()
+    }
+}
+
+/// Finds the sections in the log with the same detection as list-sections
+/// and returns the line ranges of the selected ones. If section_name is
+/// given, only sections with that name are considered. A numbered section
+/// only needs the log up to its end.
+pub fn select_sections<T>(
+    pid: i32,
+    iter: T,
+    section_name: Option<&str>,
+    selection: &SectionSelection,
+) -> Vec<SectionRange>
+where
+    T: iter::Iterator<Item = (usize, String)>,
+{
+    if let SectionSelection::Numbered(ref name, number) = *selection {
+        let mut finder = NumberedSectionFinder::new(name, number);
+        // All lines are after the empty range 0..0, so consume_log stops as
+        // soon as the finder has seen the end of the section.
+        consume_log(pid, iter, 0, &mut [&mut finder]);
+        return finder.into_section_range().into_iter().collect();
+    }
+    let mut section_tracker = SectionTracker::new(section_name);
+    consume_log(pid, iter, usize::MAX, &mut [&mut section_tracker]);
+    let summaries = section_tracker.finish();
+    let selected: Vec<&SectionSummary> = match *selection {
+        SectionSelection::Numbered(..) => unreachable!(),
+        SectionSelection::Largest => {
+            // max_by_key returns the last maximum, but we want the first one.
+            summaries.iter().rev().max_by_key(|s| s.bytes_read).into_iter().collect()
+        }
+        SectionSelection::All => summaries.iter().collect(),
+    };
+    selected
+        .into_iter()
+        .map(|summary| SectionRange {
+            label: format!("{}#{}", summary.name, summary.number),
+            start_line_index: summary.start_line_index,
+            end_line_index: summary.end_line_index.unwrap_or(usize::MAX),
+        })
+        .collect()
+}
+
+/// Like select_sections, but with the section boundaries from the log index
+/// instead of a pass over the log. The index doesn't know how many bytes the
+/// sections read, so this returns None for SectionSelection::Largest.
+pub fn select_indexed_sections(
+    sections: &[SectionBoundaries],
+    section_name: Option<&str>,
+    selection: &SectionSelection,
+) -> Option<Vec<SectionRange>> {
+    let section_name = match *selection {
+        SectionSelection::Numbered(ref name, _) => Some(name.as_str()),
+        SectionSelection::Largest => return None,
+        SectionSelection::All => section_name,
+    };
+    // The index has the sections in the order of their start, like the
+    // summaries of SectionTracker, so they're numbered the same way.
+    let mut section_count_for_name: HashMap<&str, usize> = HashMap::new();
+    let mut ranges = Vec::new();
+    for section in sections {
+        if section_name.is_some_and(|name| name != section.name) {
+            continue;
+        }
+        let count = section_count_for_name.entry(&section.name).or_insert(0);
+        *count += 1;
+        if let SectionSelection::Numbered(_, number) = *selection {
+            if *count != number {
+                continue;
+            }
         }
+        ranges.push(SectionRange {
+            label: format!("{}#{}", section.name, count),
+            start_line_index: section.start_line_index,
+            end_line_index: section.end_line_index.unwrap_or(usize::MAX),
+        });
     }
+    Some(ranges)
 }
 
+/// Computes the same numbers as list-sections for an arbitrary line range, as
+/// if it were a section without nested sections.
+pub fn summarize_line_range<T>(pid: i32, iter: T, from_line: usize, to_line: usize) -> SectionSummary
+where
+    T: iter::Iterator<Item = (usize, String)>,
+{
+    let mut summarizer = LineRangeSummarizer::new(from_line, to_line);
+    consume_log(pid, iter, to_line, &mut [&mut summarizer]);
+    summarizer.into_summary()
+}
+
+/// The consumer behind summarize_line_range.
+pub struct LineRangeSummarizer {
+    section: Section,
+    to_line: usize,
+}
+
+impl LineRangeSummarizer {
+    pub fn new(from_line: usize, to_line: usize) -> LineRangeSummarizer {
+        LineRangeSummarizer {
+            section: Section::new("", None, 0, from_line),
+            to_line,
+        }
+    }
+
+    pub fn into_summary(self) -> SectionSummary {
+        self.section.into_summary()
+    }
+}
+
+impl LogEventConsumer for LineRangeSummarizer {
+    fn process_line(&mut self, line_index: usize, line_contents: &LineContent) {
+        if line_index < self.section.start_line_index {
+            return;
+        }
+        if line_index >= self.to_line && self.section.end_line_index.is_none() {
+            self.section.found_section_end(self.to_line);
+        }
+        self.section.process_line(line_index, line_contents);
+    }
+
+    /// The end of the range is only known once a line after it comes along.
+    fn needs_more_lines(&self) -> bool {
+        self.section.end_line_index.is_none() || self.section.needs_more_lines()
+    }
+}
+
+/// Whether a cache miss was an instruction fetch or a data access, from
+/// the "why" of the LLMiss line.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Data,
+    Instruction,
+}
+
+impl AccessKind {
+    /// I1_NoX and I1_Gen are instruction fetches, D1 is a data access.
+    pub fn from_miss_reason(why: &str) -> AccessKind {
+        if why.trim().starts_with("I1") {
+            AccessKind::Instruction
+        } else {
+            AccessKind::Data
+        }
+    }
+}
+
+/// A cache line that was read into the LL cache.
 #[derive(Debug)]
-struct CacheLineRead {
-    line_index: usize,
-    address: u64,
-    size: u8,
-    used_bytes: Option<u8>,
-    stack: Option<usize>,
+pub struct CacheLineRead {
+    pub line_index: usize,
+    pub address: u64,
+    pub size: u8,
+    /// How many bytes of the cache line were accessed before it was evicted,
+    /// or None if it wasn't evicted within the lines that were processed.
+    pub used_bytes: Option<u8>,
+    pub stack: Option<usize>,
+    pub tid: Option<u32>,
+    pub kind: Option<AccessKind>,
 }
 
-struct ReadsCollector {
+/// A read of a whole 64 byte line for the tests of the cache simulations.
+#[cfg(test)]
+pub fn test_read(address: u64, used_bytes: u8, kind: Option<AccessKind>) -> CacheLineRead {
+    CacheLineRead {
+        line_index: 0,
+        address,
+        size: 64,
+        used_bytes: Some(used_bytes),
+        stack: None,
+        tid: None,
+        kind,
+    }
+}
+
+/// Collects the cache lines that were read in a line range, together with
+/// their stacks and used bytes. The used bytes are only known when the cache
+/// line is evicted, which can be long after the end of the range, so this
+/// needs more lines until all reads have them.
+#[derive(Default)]
+pub struct ReadsCollector {
     reads: Vec<CacheLineRead>,
     reads_with_pending_used_bytes: HashMap<u64, usize>,
     reads_with_pending_stacks: Vec<usize>,
+    start_line_index: usize,
+    end_line_index: Option<usize>,
 }
 
 impl ReadsCollector {
+    /// Collects all reads until set_end_line_index is called.
     pub fn new() -> ReadsCollector {
         ReadsCollector {
             reads: Vec::new(),
             reads_with_pending_used_bytes: HashMap::new(),
             reads_with_pending_stacks: Vec::new(),
+            start_line_index: 0,
+            end_line_index: None,
         }
     }
 
-    pub fn needs_more_lines(&self) -> bool {
-        !self.reads_with_pending_used_bytes.is_empty() || !self.reads_with_pending_stacks.is_empty()
+    /// Collects the reads from the lines from from_line up to, but not
+    /// including, to_line.
+    pub fn for_line_range(from_line: usize, to_line: usize) -> ReadsCollector {
+        ReadsCollector {
+            start_line_index: from_line,
+            end_line_index: Some(to_line),
+            ..ReadsCollector::new()
+        }
+    }
+
+    /// Reads from this line on aren't collected anymore.
+    pub fn set_end_line_index(&mut self, line_index: usize) {
+        self.end_line_index = Some(line_index);
     }
 
     pub fn into_reads(self) -> Vec<CacheLineRead> {
         self.reads
     }
+}
 
-    pub fn process_line(
-        &mut self,
-        line_index: usize,
-        within_interesting_section: bool,
-        line_contents: &LineContent,
-    ) {
+impl LogEventConsumer for ReadsCollector {
+    fn needs_more_lines(&self) -> bool {
+        !self.reads_with_pending_used_bytes.is_empty() || !self.reads_with_pending_stacks.is_empty()
+    }
+
+    fn process_line(&mut self, line_index: usize, line_contents: &LineContent) {
+        if line_index < self.start_line_index {
+            return;
+        }
+        let within_interesting_section = self.end_line_index.is_none_or(|end| line_index < end);
         match line_contents {
             &LineContent::LLCacheLineSwap {
                 new_start,
@@ -283,9 +826,17 @@ impl ReadsCollector {
                         size,
                         used_bytes: None,
                         stack: None,
+                        tid: None,
+                        kind: None,
                     });
                 }
             }
+            &LineContent::LLMiss { why, tid, .. } => {
+                for &read_index in &self.reads_with_pending_stacks {
+                    self.reads[read_index].tid = Some(tid);
+                    self.reads[read_index].kind = Some(AccessKind::from_miss_reason(why));
+                }
+            }
             &LineContent::StackForLLMiss(stack) => {
                 for read_index in self.reads_with_pending_stacks.drain(..) {
                     self.reads[read_index].stack = Some(stack);
@@ -296,65 +847,107 @@ impl ReadsCollector {
     }
 }
 
-#[allow(dead_code)]
-pub fn print_cache_line_wastage<T>(pid: i32, iter: T, from_line: usize, to_line: usize)
+/// Where generate-profiles writes its profiles, and which ones.
+pub struct ProfileOutput<'a> {
+    pub output_dir: &'a Path,
+    pub file_prefix: &'a str,
+    /// Whether to write one profile per arena in addition to the combined one.
+    pub per_arena: bool,
+    /// Every profile is saved in each of these formats.
+    pub formats: &'a [ProfileFormat],
+}
+
+/// The cache line usage profiles of one line range.
+pub struct CacheLineUsageProfiles {
+    pub profile_builder: ProfileBuilder,
+    /// With per_arena, one profile per arena, and one for the reads outside
+    /// of any arena, keyed by their file prefix, e.g. "arena_PresShell_0x1".
+    pub arena_profile_builders: Vec<(String, ProfileBuilder)>,
+    /// The bytes wasted per stack, most first.
+    pub wasted_bytes_per_stack: Vec<(usize, u64)>,
+    pub stack_table: StackTable,
+}
+
+pub fn build_cache_line_usage_profiles<T>(
+    pid: i32,
+    iter: T,
+    state: ProcessState,
+    from_line: usize,
+    to_line: usize,
+    per_arena: bool,
+) -> CacheLineUsageProfiles
 where
     T: iter::Iterator<Item = (usize, String)>,
 {
-    let mut stack_info = StackInfoCollector::new();
-    let mut reads_info = ReadsCollector::new();
+    let ProcessState {
+        mut stack_info,
+        mut arena_info,
+    } = state;
+    let mut reads_info = ReadsCollector::for_line_range(from_line, to_line);
+    // The arena that covered the address of each read at the time of the
+    // read, in the same order as the reads.
+    let mut read_arenas: Vec<Option<String>> = Vec::new();
+    let mut sections = OverlappingSections::new(from_line);
     for (line_index, line) in iter {
         if let Some((p, line_contents)) = parse_line_of_pid(&line) {
             if p != pid {
                 continue;
             }
             if line_index < to_line {
-                stack_info.process_line(&line_contents);
+                stack_info.process_line(line_index, &line_contents);
+                arena_info.process_line(line_index, &line_contents);
+                sections.process_line(line_index, &line_contents);
             } else if !reads_info.needs_more_lines() {
                 break;
             }
             if line_index >= from_line {
-                reads_info.process_line(line_index, line_index < to_line, &line_contents);
+                reads_info.process_line(line_index, &line_contents);
+                if let LineContent::LLCacheLineSwap { new_start, .. } = line_contents {
+                    if line_index < to_line {
+                        read_arenas.push(arena_info.arenas().arena_covering_address(new_start));
+                    }
+                }
             }
         }
     }
 
     let reads = reads_info.into_reads();
+    let arenas = arena_info.into_arenas();
 
+    // The profile's time axis counts the bytes that were read, 1KB per ms.
     let bytes_per_ms: u32 = 1024;
-    let bytes_per_sample: u32 = 64;
+    let bytes_per_cache_line: u32 = 64;
 
-    let mut stack_table = stack_info.get_stack_table();
+    let stack_table = stack_info.get_stack_table();
 
-    let mut read_bytes_profile_builder = ProfileBuilder::new(
+    let mut profile_builder = ProfileBuilder::new(
         stack_table.clone(),
-        bytes_per_sample as f64 / bytes_per_ms as f64,
+        bytes_per_cache_line as f64 / bytes_per_ms as f64,
+        &["Read bytes", "Used bytes", "Wasted bytes"],
     );
 
-    let mut used_bytes_profile_builder = ProfileBuilder::new(
-        stack_table.clone(),
-        bytes_per_sample as f64 / bytes_per_ms as f64,
-    );
+    // With per_arena, every arena gets its own profile in addition to the
+    // combined one, keyed by arena ident, with None for reads outside of any
+    // arena. They use the same time axis as the combined profile.
+    let mut arena_profile_builders: BTreeMap<Option<String>, ProfileBuilder> = BTreeMap::new();
 
-    let mut wasted_bytes_profile_builder = ProfileBuilder::new(
-        stack_table.clone(),
-        bytes_per_sample as f64 / bytes_per_ms as f64,
-    );
-
-    let mut rng = rand::weak_rng();
     let mut read_bytes_cumulative = 0;
-    let mut used_bytes_cumulative = 0;
-    let mut wasted_bytes_cumulative = 0;
+    // The line index of each sample and the bytes read before it, for
+    // placing the section markers on the same axis.
+    let mut read_bytes_before_line: Vec<(usize, u64)> = Vec::new();
+    let mut category_for_arena: HashMap<Option<String>, usize> = HashMap::new();
 
     let mut wasted_bytes_cumulative_per_stack = HashMap::new();
 
-    for CacheLineRead {
-        line_index: _,
+    for (CacheLineRead {
+        line_index,
         address: _,
         size: read_bytes,
         used_bytes,
         stack,
-    } in reads.into_iter()
+        tid: _,
+        kind: _,
+    }, arena) in reads.into_iter().zip(read_arenas)
     {
         if let (Some(used_bytes), Some(stack)) = (used_bytes, stack) {
             let wasted_bytes = read_bytes - used_bytes;
@@ -362,45 +955,100 @@ where
                 0u64,
             ) += wasted_bytes as u64;
 
-            if rng.next_u32() % bytes_per_sample < read_bytes as u32 {
-                read_bytes_profile_builder.add_sample(
-                    stack,
-                    read_bytes_cumulative as f64 /
-                        bytes_per_ms as f64,
-                );
-            }
-            if rng.next_u32() % bytes_per_sample < used_bytes as u32 {
-                used_bytes_profile_builder.add_sample(
-                    stack,
-                    used_bytes_cumulative as f64 /
-                        bytes_per_ms as f64,
-                );
-            }
-            if rng.next_u32() % bytes_per_sample < wasted_bytes as u32 {
-                wasted_bytes_profile_builder.add_sample(
-                    stack,
-                    wasted_bytes_cumulative as f64 /
-                        bytes_per_ms as f64,
-                );
+            let category = match category_for_arena.get(&arena) {
+                Some(&category) => category,
+                None => {
+                    let category = profile_builder.category(&arena_category_name(&arenas, arena.as_ref()));
+                    category_for_arena.insert(arena.clone(), category);
+                    category
+                }
+            };
+            read_bytes_before_line.push((line_index, read_bytes_cumulative));
+            let time = read_bytes_cumulative as f64 / bytes_per_ms as f64;
+            let weights = [read_bytes as u64, used_bytes as u64, wasted_bytes as u64];
+            profile_builder.add_sample(stack, time, category, &weights);
+            if per_arena {
+                let arena_profile_builder = arena_profile_builders.entry(arena).or_insert_with(|| {
+                    ProfileBuilder::new(
+                        stack_table.clone(),
+                        bytes_per_cache_line as f64 / bytes_per_ms as f64,
+                        &["Read bytes", "Used bytes", "Wasted bytes"],
+                    )
+                });
+                arena_profile_builder.add_sample(stack, time, 0, &weights);
             }
             read_bytes_cumulative += read_bytes as u64;
-            used_bytes_cumulative += used_bytes as u64;
-            wasted_bytes_cumulative += wasted_bytes as u64;
-        }
-    }
-    read_bytes_profile_builder
-        .save_to_file("/home/mstange/Desktop/read_bytes_profile.sps.json")
-        .expect("JSON file writing went wrong");
-    used_bytes_profile_builder
-        .save_to_file("/home/mstange/Desktop/used_bytes_profile.sps.json")
-        .expect("JSON file writing went wrong");
-    wasted_bytes_profile_builder
-        .save_to_file("/home/mstange/Desktop/wasted_bytes_profile.sps.json")
-        .expect("JSON file writing went wrong");
-    let mut wasted_bytes_cumulative_per_stack: Vec<(usize, u64)> =
+        }
+    }
+    let time_at_line = |line_index: Option<usize>, default_bytes: u64| {
+        let bytes = match line_index {
+            Some(line_index) => {
+                match read_bytes_before_line.iter().position(|&(l, _)| l >= line_index) {
+                    Some(i) => read_bytes_before_line[i].1,
+                    None => read_bytes_cumulative,
+                }
+            }
+            None => default_bytes,
+        };
+        bytes as f64 / bytes_per_ms as f64
+    };
+    for (name, begin_line_index, end_line_index) in sections.finish() {
+        let start_time = time_at_line(begin_line_index, 0);
+        let end_time = time_at_line(end_line_index, read_bytes_cumulative);
+        profile_builder.add_marker(&name, start_time, end_time);
+        for arena_profile_builder in arena_profile_builders.values_mut() {
+            arena_profile_builder.add_marker(&name, start_time, end_time);
+        }
+    }
+    let mut wasted_bytes_per_stack: Vec<(usize, u64)> =
         wasted_bytes_cumulative_per_stack.into_iter().collect();
-    wasted_bytes_cumulative_per_stack.sort_by(|&(_, ref wb1), &(_, wb2)| wb2.cmp(wb1));
-    for (stack, wasted_bytes) in wasted_bytes_cumulative_per_stack.into_iter().take(10) {
+    wasted_bytes_per_stack.sort_by(|&(s1, wb1), &(s2, wb2)| wb2.cmp(&wb1).then(s1.cmp(&s2)));
+    CacheLineUsageProfiles {
+        profile_builder,
+        arena_profile_builders: arena_profile_builders
+            .into_iter()
+            .map(|(arena, arena_profile_builder)| (arena_file_prefix(&arenas, arena.as_ref()), arena_profile_builder))
+            .collect(),
+        wasted_bytes_per_stack,
+        stack_table,
+    }
+}
+
+pub fn print_cache_line_wastage<T>(
+    pid: i32,
+    iter: T,
+    state: ProcessState,
+    from_line: usize,
+    to_line: usize,
+    output: &ProfileOutput,
+) -> Result<(), io::Error>
+where
+    T: iter::Iterator<Item = (usize, String)>,
+{
+    let ProfileOutput {
+        output_dir,
+        file_prefix,
+        per_arena,
+        formats,
+    } = *output;
+    fs::create_dir_all(output_dir)?;
+
+    let CacheLineUsageProfiles {
+        profile_builder,
+        arena_profile_builders,
+        wasted_bytes_per_stack,
+        mut stack_table,
+    } = build_cache_line_usage_profiles(pid, iter, state, from_line, to_line, per_arena);
+    save_profile(&profile_builder, formats, output_dir, file_prefix)?;
+    for (arena_file_prefix, arena_profile_builder) in arena_profile_builders {
+        let arena_file_prefix = if file_prefix.is_empty() {
+            arena_file_prefix
+        } else {
+            format!("{}_{}", file_prefix, arena_file_prefix)
+        };
+        save_profile(&arena_profile_builder, formats, output_dir, &arena_file_prefix)?;
+    }
+    for (stack, wasted_bytes) in wasted_bytes_per_stack.into_iter().take(10) {
         println!(
             "Wasted {} at stack {}.",
             convert(wasted_bytes as f64),
@@ -408,35 +1056,194 @@ where
         );
         stack_table.print_stack(stack, 4);
     }
+    Ok(())
 }
 
-#[allow(dead_code)]
-pub fn print_wastage_source_code<T>(pid: i32, iter: T, from_line: usize, to_line: usize)
-where
-    T: iter::Iterator<Item = (usize, String)>,
-{
-    let mut stack_info = StackInfoCollector::new();
-    let mut reads_info = ReadsCollector::new();
-    for (line_index, line) in iter {
-        if let Some((p, line_contents)) = parse_line_of_pid(&line) {
-            if p != pid {
-                continue;
-            }
-            if line_index < to_line {
-                stack_info.process_line(&line_contents);
-            } else if !reads_info.needs_more_lines() {
-                break;
+fn save_profile(
+    profile_builder: &ProfileBuilder,
+    formats: &[ProfileFormat],
+    output_dir: &Path,
+    file_prefix: &str,
+) -> Result<(), io::Error> {
+    for &format in formats {
+        for path in profile_builder.save(format, output_dir, file_prefix, "cache_line_usage")? {
+            println!("Wrote {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// The profile category for reads from the given arena, e.g. "nsPresArena"
+/// or "nsDisplayListBuilder arena".
+fn arena_category_name(arenas: &Arenas, arena: Option<&String>) -> String {
+    let arena = match arena {
+        Some(arena) => arena,
+        None => return "Outside any arena".to_owned(),
+    };
+    match arenas.arena_owner_types(arena).as_slice() {
+        [] => type_from_ident(arena).to_owned(),
+        [owner_type] if owner_type.ends_with("Arena") => owner_type.to_string(),
+        owner_types => format!("{} arena", owner_types.join(", ")),
+    }
+}
+
+/// Names the per-arena profile of the given arena after the arena's owner
+/// and address, e.g. "arena_PresShell_0x2fcedb48", or "outside_arenas".
+fn arena_file_prefix(arenas: &Arenas, arena: Option<&String>) -> String {
+    let arena = match arena {
+        Some(arena) => arena,
+        None => return "outside_arenas".to_owned(),
+    };
+    let owner = arenas
+        .arena_owner(arena)
+        .unwrap_or_else(|| type_from_ident(arena).to_owned());
+    let address = match arena.find(':') {
+        Some(pos) => &arena[pos + 1..],
+        None => arena.as_str(),
+    };
+    let file_prefix = format!("arena_{}_{}", owner, address);
+    file_prefix
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// Collects the sections that overlap the line range starting at from_line,
+/// given the lines up to the end of the range. Sections that began before
+/// the first line that was processed have no begin line, and sections that
+/// didn't end within the range have no end line.
+struct OverlappingSections {
+    from_line: usize,
+    open_sections: Vec<(String, Option<u32>, usize)>,
+    sections: Vec<(String, Option<usize>, Option<usize>)>,
+}
+
+impl OverlappingSections {
+    pub fn new(from_line: usize) -> OverlappingSections {
+        OverlappingSections {
+            from_line,
+            open_sections: Vec::new(),
+            sections: Vec::new(),
+        }
+    }
+
+    pub fn process_line(&mut self, line_index: usize, line_contents: &LineContent) {
+        match *line_contents {
+            LineContent::BeginSection { name, tid } => {
+                self.open_sections.push((name.to_owned(), tid, line_index));
             }
-            if line_index >= from_line {
-                reads_info.process_line(line_index, line_index < to_line, &line_contents);
+            LineContent::EndSection { name, tid } => {
+                let begin = self.open_sections
+                    .iter()
+                    .rposition(|&(ref n, t, _)| n == name && t == tid)
+                    .map(|i| self.open_sections.remove(i).2);
+                if line_index > self.from_line {
+                    self.sections.push((section_marker_name(name, tid), begin, Some(line_index)));
+                }
             }
+            _ => {}
         }
     }
 
-    let reads = reads_info.into_reads();
+    /// Returns (marker name, begin line, end line) for each section, in the
+    /// order in which they ended, followed by the unfinished ones.
+    pub fn finish(mut self) -> Vec<(String, Option<usize>, Option<usize>)> {
+        for (name, tid, begin) in self.open_sections {
+            self.sections.push((section_marker_name(&name, tid), Some(begin), None));
+        }
+        self.sections
+    }
+}
 
-    let mut stack_table = stack_info.get_stack_table();
+fn section_marker_name(name: &str, tid: Option<u32>) -> String {
+    match tid {
+        Some(tid) => format!("{} (thread {})", name, tid),
+        None => name.to_owned(),
+    }
+}
 
+#[derive(Serialize)]
+struct WastedSourceLine {
+    function_name: String,
+    file_path: String,
+    line_number: usize,
+    wasted_bytes: u64,
+}
+
+const WASTAGE_TOP_LINE_COUNT: usize = 25;
+
+/// All source lines that wasted bytes, attributed to the innermost
+/// (possibly inlined) function of the reading stack, sorted by wasted bytes.
+#[derive(Serialize)]
+pub struct WastageSourceReport {
+    total_wasted_bytes: u64,
+    top_lines_wasted_bytes: u64,
+    lines: Vec<WastedSourceLine>,
+}
+
+impl WastageSourceReport {
+    pub fn print(&self) {
+        println!(
+            "Of the total wastage of {}, {} ({:.0}%) were wasted by the top {} code lines.",
+            convert(self.total_wasted_bytes as f64),
+            convert(self.top_lines_wasted_bytes as f64),
+            (self.top_lines_wasted_bytes as f64 / self.total_wasted_bytes as f64) * 100f64,
+            WASTAGE_TOP_LINE_COUNT
+        );
+        println!("");
+
+        for &WastedSourceLine {
+            ref function_name,
+            ref file_path,
+            line_number,
+            wasted_bytes,
+        } in self.lines.iter().take(WASTAGE_TOP_LINE_COUNT)
+        {
+            let path = Path::new(file_path);
+            let file_name = path
+                .file_name()
+                .and_then(|osstr| osstr.to_str())
+                .map(|s| s.to_owned())
+                .unwrap_or(file_path.clone());
+            println!(
+                "{} of wastage at {} ({}:{}):",
+                convert(wasted_bytes as f64),
+                function_name,
+                file_name,
+                line_number
+            );
+            if line_number == 0 {
+                println!(" [Line number is zero, which probably indicates an error.]");
+            } else {
+                let result = print_file_context(&path, line_number - 1, 8);
+                if let Err(_) = result {
+                    println!(" [Reading file {} failed.]", file_name);
+                }
+            }
+            println!("");
+        }
+    }
+}
+
+pub fn analyze_wastage_source_code<T>(
+    pid: i32,
+    iter: T,
+    state: ProcessState,
+    from_line: usize,
+    to_line: usize,
+) -> WastageSourceReport
+where
+    T: iter::Iterator<Item = (usize, String)>,
+{
+    let mut stack_info = state.stack_info;
+    let mut reads_info = ReadsCollector::for_line_range(from_line, to_line);
+    consume_log(pid, iter, to_line, &mut [&mut stack_info, &mut reads_info]);
+    wastage_source_report(reads_info.into_reads(), &mut stack_info.get_stack_table())
+}
+
+/// Attributes the wasted bytes of the given reads to the source lines that
+/// read them.
+pub fn wastage_source_report(reads: Vec<CacheLineRead>, stack_table: &mut StackTable) -> WastageSourceReport {
     let mut wasted_bytes_for_frame: HashMap<usize, u64> = HashMap::new();
     let mut wasted_bytes_total: u64 = 0;
 
@@ -446,6 +1253,8 @@ where
         size: read_bytes,
         used_bytes,
         stack,
+        tid: _,
+        kind: _,
     } in reads.into_iter()
     {
         if let (Some(used_bytes), Some(stack)) = (used_bytes, stack) {
@@ -473,48 +1282,36 @@ where
         wasted_bytes_for_stack_frame_info.into_iter().collect();
     wasted_bytes_for_stack_frame_info.sort_by(|&(_, ref wb1), &(_, wb2)| wb2.cmp(wb1));
 
-    let wasted_bytes_by_top25 = wasted_bytes_for_stack_frame_info.iter().take(25).fold(
-        0,
-        |accum, &(_, wasted_bytes)| accum + wasted_bytes,
-    );
+    let top_lines_wasted_bytes = wasted_bytes_for_stack_frame_info
+        .iter()
+        .take(WASTAGE_TOP_LINE_COUNT)
+        .fold(0, |accum, &(_, wasted_bytes)| accum + wasted_bytes);
+
+    WastageSourceReport {
+        total_wasted_bytes: wasted_bytes_total,
+        top_lines_wasted_bytes,
+        lines: wasted_bytes_for_stack_frame_info
+            .into_iter()
+            .map(|(frame_info, wasted_bytes)| WastedSourceLine {
+                function_name: frame_info.function_name,
+                file_path: frame_info.file_path_str,
+                line_number: frame_info.line_number,
+                wasted_bytes,
+            })
+            .collect(),
+    }
+}
 
-    println!(
-        "Of the total wastage of {}, {} ({:.0}%) were wasted by the top 25 code lines.",
-        convert(wasted_bytes_total as f64),
-        convert(wasted_bytes_by_top25 as f64),
-        (wasted_bytes_by_top25 as f64 / wasted_bytes_total as f64) * 100f64
-    );
-    println!("");
-
-    for (StackFrameInfo {
-             function_name,
-             file_path_str,
-             line_number,
-         },
-         wasted_bytes) in wasted_bytes_for_stack_frame_info.into_iter().take(25)
-    {
-        let path = Path::new(&file_path_str);
-        let file_name = path
-            .file_name()
-            .and_then(|osstr| osstr.to_str())
-            .map(|s| s.to_owned())
-            .unwrap_or(file_path_str.clone());
-        println!(
-            "{} of wastage at {} ({}:{}):",
-            convert(wasted_bytes as f64),
-            function_name,
-            file_name,
-            line_number
-        );
-        if line_number == 0 {
-            println!(" [Line number is zero, which probably indicates an error.]");
-        } else {
-            let result = print_file_context(&path, line_number - 1, 8);
-            if let Err(_) = result {
-                println!(" [Reading file {} failed.]", file_name);
-            }
+impl WastageSourceReport {
+    /// The wasted bytes summed up per function, over all of its lines.
+    pub fn wasted_bytes_per_function(&self) -> BTreeMap<String, u64> {
+        let mut wasted_bytes_per_function = BTreeMap::new();
+        for line in &self.lines {
+            *wasted_bytes_per_function
+                .entry(line.function_name.clone())
+                .or_insert(0) += line.wasted_bytes;
         }
-        println!("");
+        wasted_bytes_per_function
     }
 }
 
@@ -584,60 +1381,20 @@ where
     }
 }
 
-fn find_cpucache_info<T>(pid: i32, iter: &mut T) -> Option<CPUCache>
+/// The geometry of the LL cache that the log was recorded with, from the
+/// "LL cache information" line at the start of the process's log.
+pub fn find_cache_config<T>(pid: i32, iter: &mut T) -> Option<CacheConfig>
 where
     T: iter::Iterator<Item = (usize, String)>,
 {
-    loop {
-        if let Some((_, line)) = iter.next() {
-            if let Some((p, ref line_contents)) = parse_line_of_pid(&line) {
-                if p != pid {
-                    continue;
-                }
-                if let &LineContent::LLCacheInfo {
-                    size,
-                    line_size,
-                    assoc,
-                } = line_contents
-                {
-                    return Some(CPUCache::new(size, line_size, assoc));
-                }
-            }
-        } else {
-            println!("Couldn't find CPU cache info, not simulating cache.");
-            return None;
-        }
-    }
-
-}
-
-#[allow(dead_code)]
-pub fn print_cache_contents_at<T>(pid: i32, mut iter: T, at_line_index: usize)
-where
-    T: iter::Iterator<Item = (usize, String)>,
-{
-    if let Some(mut cache) = find_cpucache_info(pid, &mut iter) {
-        for (line_index, line) in iter {
-            if line_index >= at_line_index {
-                break;
+    for (_, line) in iter {
+        if let Some((p, LineContent::LLCacheInfo { size, line_size, assoc })) = parse_line_of_pid(&line) {
+            if p == pid {
+                return Some(CacheConfig { size, line_size: line_size as u32, assoc });
             }
-            if let Some((p, line_contents)) = parse_line_of_pid(&line) {
-                if p != pid {
-                    continue;
-                }
-                if let LineContent::LLCacheLineSwap {
-                    new_start,
-                    old_start,
-                    size: _,
-                    used_bytes: _,
-                } = line_contents
-                {
-                    cache.exchange(new_start, old_start);
-                }
-            };
         }
-        println!("cache ranges: {:?}", cache.get_cached_ranges());
     }
+    None
 }
 
 fn n_times(n: usize, singular: &str, plural: &str) -> String {
@@ -673,7 +1430,8 @@ fn type_from_ident(ident: &str) -> &str {
     }
 }
 
-struct ArenaInfoCollector {
+#[derive(Default)]
+pub struct ArenaInfoCollector {
     arenas: Arenas,
 }
 
@@ -682,7 +1440,21 @@ impl ArenaInfoCollector {
         ArenaInfoCollector { arenas: Arenas::new() }
     }
 
-    pub fn process_line(&mut self, line_content: &LineContent) {
+    pub fn from_arenas(arenas: Arenas) -> ArenaInfoCollector {
+        ArenaInfoCollector { arenas }
+    }
+
+    pub fn arenas(&self) -> &Arenas {
+        &self.arenas
+    }
+
+    pub fn into_arenas(self) -> Arenas {
+        self.arenas
+    }
+}
+
+impl LogEventConsumer for ArenaInfoCollector {
+    fn process_line(&mut self, _line_index: usize, line_content: &LineContent) {
         match line_content {
             &LineContent::AllocatingArenaChunk {
                 ident,
@@ -736,19 +1508,12 @@ impl ArenaInfoCollector {
             _ => {}
         }
     }
-
-    pub fn arenas(&self) -> &Arenas {
-        &self.arenas
-    }
-
-    pub fn into_arenas(self) -> Arenas {
-        self.arenas
-    }
 }
 
-struct StackInfoCollector {
-    stack_table: StackTable,
-    shared_libs_json_string: String,
+#[derive(Default)]
+pub struct StackInfoCollector {
+    pub stack_table: StackTable,
+    pub shared_libs_json_string: String,
 }
 
 impl StackInfoCollector {
@@ -759,7 +1524,29 @@ impl StackInfoCollector {
         }
     }
 
-    pub fn process_line(&mut self, line_content: &LineContent) {
+    pub fn get_stack_table(self) -> StackTable {
+        let StackInfoCollector {
+            mut stack_table,
+            shared_libs_json_string,
+        } = self;
+        if shared_libs_json_string.is_empty() {
+            eprintln!("Couldn't find any SharedLibrary information in the log.");
+        } else {
+            match SharedLibraries::from_json_string(shared_libs_json_string) {
+                Ok(shared_libraries) => {
+                    stack_table.set_libs(shared_libraries);
+                }
+                Err(e) => {
+                    eprintln!("error during json parsing: {:?}", e);
+                }
+            }
+        }
+        stack_table
+    }
+}
+
+impl LogEventConsumer for StackInfoCollector {
+    fn process_line(&mut self, _line_index: usize, line_content: &LineContent) {
         match line_content {
             &LineContent::AddFrame { index, address } => {
                 self.stack_table.add_frame(index, address);
@@ -777,25 +1564,23 @@ impl StackInfoCollector {
             _ => {}
         }
     }
+}
 
-    pub fn get_stack_table(self) -> StackTable {
-        let StackInfoCollector {
-            mut stack_table,
-            shared_libs_json_string,
-        } = self;
-        if shared_libs_json_string.is_empty() {
-            println!("Couldn't find any SharedLibrary information in the log.");
-        } else {
-            match SharedLibraries::from_json_string(shared_libs_json_string) {
-                Ok(shared_libraries) => {
-                    stack_table.set_libs(shared_libraries);
-                }
-                Err(e) => {
-                    println!("error during json parsing: {:?}", e);
-                }
-            }
+/// Everything that needs to be known about a process before any part of its
+/// log can be analyzed. This is built up from the start of the log, or
+/// restored from a checkpoint in the log index.
+#[derive(Default)]
+pub struct ProcessState {
+    pub stack_info: StackInfoCollector,
+    pub arena_info: ArenaInfoCollector,
+}
+
+impl ProcessState {
+    pub fn new() -> ProcessState {
+        ProcessState {
+            stack_info: StackInfoCollector::new(),
+            arena_info: ArenaInfoCollector::new(),
         }
-        stack_table
     }
 }
 
@@ -881,49 +1666,128 @@ impl AddressReads {
         (into_histogram(read_counts), self.reads_per_address.len())
     }
 
+    /// Describes the reads of the n cache-line sized memory ranges that were
+    /// read most often, including the stacks of the reads and evictions.
+    pub fn top_n_ranges(&self, n: usize, stack_table: &mut StackTable) -> Vec<RepeatedlyReadRange> {
+        let mut reads: Vec<(u64, Vec<AddressReadWithPotentialEviction>)> = self.reads_per_address
+            .iter()
+            .map(|(address, address_read_events)| {
+                (*address, (*address_read_events).clone())
+            })
+            .collect();
+        reads.sort_by(|&(_, ref v1), &(_, ref v2)| v2.len().cmp(&v1.len()));
+        reads
+            .into_iter()
+            .take(n)
+            .map(|(address, reads)| RepeatedlyReadRange {
+                address,
+                reads: reads
+                    .into_iter()
+                    .map(|AddressReadWithPotentialEviction { read, eviction }| ReadWithEviction {
+                        read: StackEvent {
+                            line_index: read.line_index,
+                            stack: stack_table.describe_stack(read.stack),
+                        },
+                        eviction: eviction.map(|eviction| StackEvent {
+                            line_index: eviction.line_index,
+                            stack: stack_table.describe_stack(eviction.stack),
+                        }),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    pub fn report(&self, bytes_read: u64, top_range_count: usize, stack_table: &mut StackTable) -> AddressReadsReport {
+        let (histogram, range_count) = self.histogram();
+        AddressReadsReport {
+            bytes_read,
+            range_count,
+            multiple_reads_count: self.multiple_reads_count(),
+            histogram: histogram
+                .into_iter()
+                .map(|(read_count, range_count)| HistogramEntry { read_count, range_count })
+                .collect(),
+            top_ranges: self.top_n_ranges(top_range_count, stack_table),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HistogramEntry {
+    /// How often each of these ranges was read.
+    read_count: usize,
+    range_count: usize,
+}
+
+#[derive(Serialize)]
+struct StackEvent {
+    line_index: usize,
+    /// Innermost frame first.
+    stack: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ReadWithEviction {
+    read: StackEvent,
+    eviction: Option<StackEvent>,
+}
+
+#[derive(Serialize)]
+struct RepeatedlyReadRange {
+    address: u64,
+    reads: Vec<ReadWithEviction>,
+}
+
+/// How often the cache-line sized memory ranges of some part of memory were
+/// read into the cache.
+#[derive(Serialize)]
+pub struct AddressReadsReport {
+    bytes_read: u64,
+    /// The number of distinct cache-line sized ranges that were read.
+    range_count: usize,
+    /// The number of ranges that were read at least twice.
+    multiple_reads_count: usize,
+    histogram: Vec<HistogramEntry>,
+    /// The ranges that were read most often.
+    top_ranges: Vec<RepeatedlyReadRange>,
+}
+
+impl AddressReadsReport {
     pub fn print_histogram(&self) {
-        let (histogram, total_address_count) = self.histogram();
-        for (read_count, read_count_count) in histogram {
+        for &HistogramEntry { read_count, range_count } in &self.histogram {
             println!(
                 "    {} cache-line sized memory ranges were read {} ({:.0}%)",
-                read_count_count,
+                range_count,
                 n_times(read_count, "time", "times"),
-                100f32 * read_count_count as f32 / total_address_count as f32
+                100f32 * range_count as f32 / self.range_count as f32
             );
         }
     }
 
-    pub fn print_top_n_stacks(&self, n: usize, stack_table: &mut StackTable) {
-        let mut reads: Vec<(u64, Vec<AddressReadWithPotentialEviction>)> = self.reads_per_address
-            .iter()
-            .map(|(address, address_read_events)| {
-                (*address, (*address_read_events).clone())
-            })
-            .collect();
-        reads.sort_by(|&(_, ref v1), &(_, ref v2)| v2.len().cmp(&v1.len()));
-        for (addr, reads) in reads.into_iter().take(n) {
+    pub fn print_top_ranges(&self) {
+        for &RepeatedlyReadRange { address, ref reads } in &self.top_ranges {
             println!(
                 "      * Read cache line at address 0x{:x} {}:",
-                addr,
+                address,
                 n_times(reads.len(), "time", "times")
             );
-            for (i,
-                 AddressReadWithPotentialEviction {
-                     read: AddressReadOrEvictEvent { line_index, stack },
-                     eviction,
-                 }) in reads.into_iter().enumerate()
-            {
-                println!("          {} At line {}:", i + 1, line_index);
+            for (i, ReadWithEviction { read, eviction }) in reads.iter().enumerate() {
+                println!("          {} At line {}:", i + 1, read.line_index);
                 println!("");
-                stack_table.print_stack(stack, 12);
+                for line in &read.stack {
+                    println!("            {}", line);
+                }
                 println!("");
-                if let Some(AddressReadOrEvictEvent { line_index, stack }) = eviction {
+                if let Some(ref eviction) = *eviction {
                     println!(
                         "            This cache line was subsequently evicted at line {}:",
-                        line_index
+                        eviction.line_index
                     );
                     println!("");
-                    stack_table.print_stack(stack, 12);
+                    for line in &eviction.stack {
+                        println!("            {}", line);
+                    }
                     println!("");
                 } else {
                     println!("            (No eviction)");
@@ -933,6 +1797,89 @@ impl AddressReads {
     }
 }
 
+#[derive(Serialize)]
+struct ArenaReadsReport {
+    arena: String,
+    /// The arena type and the types of its owners, without addresses.
+    kind: String,
+    /// The objects that own the arena, as printed by analyze-double-reads.
+    description: String,
+    reads: AddressReadsReport,
+}
+
+/// The result of analyze-double-reads.
+#[derive(Serialize)]
+pub struct DoubleReadsReport {
+    total_bytes_read: u64,
+    /// All reads, without top_ranges.
+    all_reads: AddressReadsReport,
+    outside_arenas: AddressReadsReport,
+    /// Sorted by bytes read, descending.
+    arenas: Vec<ArenaReadsReport>,
+}
+
+impl DoubleReadsReport {
+    pub fn print(&self) {
+        println!(
+            "Read {} cache-line sized memory ranges at least twice.",
+            self.all_reads.multiple_reads_count
+        );
+        self.all_reads.print_histogram();
+        println!("");
+
+        println!(
+            "Read {} ({:.0}%) bytes outside any arena.",
+            self.outside_arenas.bytes_read,
+            100f64 * self.outside_arenas.bytes_read as f64 / self.total_bytes_read as f64
+        );
+        self.outside_arenas.print_histogram();
+        println!("");
+        println!(
+            "    Here are the top 5 cache-line sized memory ranges from outside any arena, with their reads + evictions:"
+        );
+        self.outside_arenas.print_top_ranges();
+        println!("");
+        println!("");
+
+        for arena in &self.arenas {
+            println!(
+                "Read {} bytes ({:.0}%) from arena {}:",
+                arena.reads.bytes_read,
+                100f64 * arena.reads.bytes_read as f64 / self.total_bytes_read as f64,
+                &arena.arena
+            );
+            println!("    {}", arena.description);
+            println!("");
+            arena.reads.print_histogram();
+            println!("");
+            println!(
+                "    Here are the top 5 cache-line sized memory ranges in this arena, with their reads + evictions:"
+            );
+            arena.reads.print_top_ranges();
+            println!("");
+            println!("");
+        }
+    }
+}
+
+impl DoubleReadsReport {
+    /// The bytes read per arena kind (see Arenas::arena_kind), summed up over
+    /// all arenas of the same kind. Reads outside of any arena are listed
+    /// under "(outside any arena)".
+    pub fn bytes_read_per_arena_kind(&self) -> BTreeMap<String, u64> {
+        let mut bytes_read_per_arena_kind = BTreeMap::new();
+        if self.outside_arenas.bytes_read > 0 {
+            bytes_read_per_arena_kind.insert("(outside any arena)".to_owned(), self.outside_arenas.bytes_read);
+        }
+        for arena in &self.arenas {
+            *bytes_read_per_arena_kind
+                .entry(arena.kind.clone())
+                .or_insert(0) += arena.reads.bytes_read;
+        }
+        bytes_read_per_arena_kind
+    }
+}
+
 struct ArenaAddressReads {
     address_reads_per_arena_ident: HashMap<String, (AddressReads, u64)>,
 }
@@ -978,155 +1925,249 @@ impl ArenaAddressReads {
     }
 }
 
-#[allow(dead_code)]
-pub fn print_multiple_read_ranges<T>(pid: i32, iter: T, from_line: usize, to_line: usize)
+pub fn analyze_multiple_read_ranges<T>(
+    pid: i32,
+    iter: T,
+    state: ProcessState,
+    from_line: usize,
+    to_line: usize,
+) -> DoubleReadsReport
 where
     T: iter::Iterator<Item = (usize, String)>,
 {
-    let mut stack_info = StackInfoCollector::new();
-    let mut arena_info = ArenaInfoCollector::new();
-    let mut address_reads = AddressReads::new();
-    let mut pending_cache_line_swaps: Vec<(u64, u64, u8, usize)> = Vec::new();
+    let ProcessState {
+        mut stack_info,
+        arena_info,
+    } = state;
+    let mut double_reads = DoubleReadsCollector::new(arena_info, from_line);
+    consume_log(pid, iter, to_line, &mut [&mut stack_info, &mut double_reads]);
+    double_reads.into_report(&mut stack_info.get_stack_table())
+}
 
-    let mut outside_arena_reads = AddressReads::new();
-    let mut arena_reads = ArenaAddressReads::new();
-    let mut bytes_read_outside_arena = 0u64;
-    let mut total_bytes_read = 0u64;
+/// The consumer behind analyze_multiple_read_ranges. It keeps track of the
+/// arenas itself, because it needs to know them at the time of each read.
+pub struct DoubleReadsCollector {
+    arena_info: ArenaInfoCollector,
+    from_line: usize,
+    address_reads: AddressReads,
+    pending_cache_line_swaps: Vec<(u64, u64, u8, usize)>,
+    outside_arena_reads: AddressReads,
+    arena_reads: ArenaAddressReads,
+    bytes_read_outside_arena: u64,
+    total_bytes_read: u64,
+}
 
-    for (line_index, line) in iter.take(to_line) {
+impl DoubleReadsCollector {
+    pub fn new(arena_info: ArenaInfoCollector, from_line: usize) -> DoubleReadsCollector {
+        DoubleReadsCollector {
+            arena_info,
+            from_line,
+            address_reads: AddressReads::new(),
+            pending_cache_line_swaps: Vec::new(),
+            outside_arena_reads: AddressReads::new(),
+            arena_reads: ArenaAddressReads::new(),
+            bytes_read_outside_arena: 0,
+            total_bytes_read: 0,
+        }
+    }
 
-        if let Some((p, line_contents)) = parse_line_of_pid(&line) {
-            if p != pid {
-                continue;
+    pub fn into_report(self, stack_table: &mut StackTable) -> DoubleReadsReport {
+        let DoubleReadsCollector {
+            arena_info,
+            address_reads,
+            outside_arena_reads,
+            arena_reads,
+            bytes_read_outside_arena,
+            total_bytes_read,
+            ..
+        } = self;
+        let mut arenas = arena_info.into_arenas();
+        DoubleReadsReport {
+            total_bytes_read,
+            all_reads: address_reads.report(total_bytes_read, 0, stack_table),
+            outside_arenas: outside_arena_reads.report(bytes_read_outside_arena, 5, stack_table),
+            arenas: arena_reads
+                .into_arenas_sorted_by_most_bytes_read()
+                .into_iter()
+                .map(|(arena, (arena_address_reads, bytes))| ArenaReadsReport {
+                    kind: arenas.arena_kind(&arena),
+                    description: arenas.arena_description(&arena),
+                    reads: arena_address_reads.report(bytes, 5, stack_table),
+                    arena,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl LogEventConsumer for DoubleReadsCollector {
+    fn process_line(&mut self, line_index: usize, line_contents: &LineContent) {
+        self.arena_info.process_line(line_index, line_contents);
+        if line_index < self.from_line {
+            return;
+        }
+        match *line_contents {
+            LineContent::LLCacheLineSwap {
+                new_start,
+                old_start,
+                size,
+                used_bytes: _,
+            } => {
+                self.pending_cache_line_swaps.push((new_start, old_start, size, line_index));
             }
-            stack_info.process_line(&line_contents);
-            arena_info.process_line(&line_contents);
-            if line_index >= from_line {
-                match line_contents {
-                    LineContent::LLCacheLineSwap {
-                        new_start,
-                        old_start,
-                        size,
-                        used_bytes: _,
-                    } => {
-                        pending_cache_line_swaps.push((new_start, old_start, size, line_index));
+            LineContent::StackForLLMiss(stack_index) => {
+                for (cache_miss_addr, evicted_addr, size, cache_miss_line_index) in
+                    self.pending_cache_line_swaps.drain(..)
+                {
+                    self.address_reads.add_eviction(evicted_addr, cache_miss_line_index, stack_index);
+                    self.address_reads.add_read(cache_miss_addr, cache_miss_line_index, stack_index);
+
+                    let arenas = self.arena_info.arenas();
+                    if let Some(arena_ident) = arenas.arena_covering_address(cache_miss_addr) {
+                        self.arena_reads.add_read(
+                            &arena_ident,
+                            cache_miss_addr,
+                            size,
+                            cache_miss_line_index,
+                            stack_index,
+                        );
+                    } else {
+                        self.outside_arena_reads.add_read(cache_miss_addr, cache_miss_line_index, stack_index);
+                        self.bytes_read_outside_arena += size as u64;
                     }
-                    LineContent::StackForLLMiss(stack_index) => {
-                        for (cache_miss_addr, evicted_addr, size, cache_miss_line_index) in
-                            pending_cache_line_swaps.drain(..)
-                        {
-                            address_reads.add_eviction(
-                                evicted_addr,
-                                cache_miss_line_index,
-                                stack_index,
-                            );
-                            address_reads.add_read(
-                                cache_miss_addr,
-                                cache_miss_line_index,
-                                stack_index,
-                            );
-
-                            if let Some(arena_ident) =
-                                arena_info.arenas().arena_covering_address(cache_miss_addr)
-                            {
-                                arena_reads.add_read(
-                                    &arena_ident,
-                                    cache_miss_addr,
-                                    size,
-                                    cache_miss_line_index,
-                                    stack_index,
-                                );
-                            } else {
-                                outside_arena_reads.add_read(
-                                    cache_miss_addr,
-                                    cache_miss_line_index,
-                                    stack_index,
-                                );
-                                bytes_read_outside_arena += size as u64;
-                            }
-                            total_bytes_read += size as u64;
-
-                            if let Some(evicted_arena_ident) =
-                                arena_info.arenas().arena_covering_address(evicted_addr)
-                            {
-                                arena_reads.add_eviction(
-                                    &evicted_arena_ident,
-                                    evicted_addr,
-                                    cache_miss_line_index,
-                                    stack_index,
-                                );
-                            } else {
-                                outside_arena_reads.add_eviction(
-                                    evicted_addr,
-                                    cache_miss_line_index,
-                                    stack_index,
-                                );
-                            }
-                        }
+                    self.total_bytes_read += size as u64;
+
+                    if let Some(evicted_arena_ident) = arenas.arena_covering_address(evicted_addr) {
+                        self.arena_reads.add_eviction(
+                            &evicted_arena_ident,
+                            evicted_addr,
+                            cache_miss_line_index,
+                            stack_index,
+                        );
+                    } else {
+                        self.outside_arena_reads.add_eviction(evicted_addr, cache_miss_line_index, stack_index);
                     }
-                    _ => {}
                 }
             }
-        };
+            _ => {}
+        }
     }
+}
 
-    let mut stack_table = stack_info.get_stack_table();
-
-    println!(
-        "Read {} cache-line sized memory ranges at least twice.",
-        address_reads.multiple_reads_count()
-    );
-    address_reads.print_histogram();
-    println!("");
-
-    // let mut multiple_reads: Vec<(&u64, &Vec<(usize, usize)>)> = read_addresses
-    //     .iter()
-    //     .filter(|&(_, v)| v.len() > 1)
-    //     .collect();
-    // multiple_reads.sort_by_key(|&(_, v)| -(v.len() as isize));
-
-    // for &(addr, reads) in multiple_reads.iter().take(25) {
-    //     println!("Read cache line at address 0x{:x} {}:",
-    //              addr,
-    //              n_times(reads.len(), "time", "times"));
-    //     for (i, &(line_index, stack_index)) in reads.iter().enumerate() {
-    //         println!(" ({}) At line {}:", i + 1, line_index);
-    //         stack_table.print_stack(stack_index);
-    //     }
-    // }
-
-    println!(
-        "Read {} ({:.0}%) bytes outside any arena.",
-        bytes_read_outside_arena,
-        100f64 * bytes_read_outside_arena as f64 / total_bytes_read as f64
-    );
-    outside_arena_reads.print_histogram();
-    println!("");
-    println!(
-        "    Here are the top 5 cache-line sized memory ranges from outside any arena, with their reads + evictions:"
+#[test]
+fn test_nested_sections() {
+    let log = [
+        "==1== Begin Paint",
+        "==1== LLCacheSwapUB: new_start=1000 old_start=0 size=64 used_bytes=0",
+        "==1== LLMiss: why=    D1 size=8 addr=1000 tid=1",
+        "==1== stack: 0",
+        "==1== Begin Reflow",
+        "==1== LLCacheSwapUB: new_start=2000 old_start=0 size=64 used_bytes=0",
+        "==1== LLMiss: why=    D1 size=8 addr=2000 tid=1",
+        "==1== stack: 0",
+        "==1== End Reflow",
+        "==1== End Paint",
+        "==1== End Paint",
+        "==1== Begin Raster tid=2",
+        "==1== LLCacheSwapUB: new_start=3000 old_start=1000 size=64 used_bytes=16",
+        "==1== LLMiss: why=    D1 size=8 addr=3000 tid=1",
+        "==1== stack: 0",
+        "==1== End Raster tid=2",
+        "==1== LLCacheSwapUB: new_start=4000 old_start=2000 size=64 used_bytes=8",
+    ];
+    let mut section_tracker = SectionTracker::new(None);
+    for (line_index, line) in log.iter().enumerate() {
+        let (_, line_contents) = parse_line_of_pid(line).unwrap();
+        section_tracker.process_line(line_index, &line_contents);
+    }
+    let summaries = section_tracker.finish();
+    let summaries: Vec<_> = summaries
+        .iter()
+        .map(|s| {
+            (
+                s.name.as_str(),
+                s.number,
+                s.tid,
+                s.depth,
+                s.end_line_index,
+                s.bytes_read,
+                s.bytes_used,
+                s.exclusive_bytes_read,
+                s.exclusive_bytes_used,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summaries,
+        vec![
+            ("Paint", 1, None, 0, Some(9), 128, 24, 64, 16),
+            ("Reflow", 1, None, 1, Some(8), 64, 8, 64, 8),
+            ("Raster", 1, Some(2), 0, Some(15), 0, 0, 0, 0),
+        ]
     );
-    outside_arena_reads.print_top_n_stacks(5, &mut stack_table);
-    println!("");
-    println!("");
+}
 
-    let arenas_read = arena_reads.into_arenas_sorted_by_most_bytes_read();
-    let mut arenas = arena_info.into_arenas();
-    for (arena, (arena_address_reads, bytes)) in arenas_read.into_iter() {
-        println!(
-            "Read {} bytes ({:.0}%) from arena {}:",
-            bytes,
-            100f64 * bytes as f64 / total_bytes_read as f64,
-            &arena
-        );
-        println!("    {}", arenas.arena_description(&arena));
-        println!("");
-        arena_address_reads.print_histogram();
-        println!("");
-        println!(
-            "    Here are the top 5 cache-line sized memory ranges in this arena, with their reads + evictions:"
-        );
-        arena_address_reads.print_top_n_stacks(5, &mut stack_table);
-        println!("");
-        println!("");
+#[test]
+fn test_parse_section_selection() {
+    match SectionSelection::parse_numbered("DisplayList building#3") {
+        Some(SectionSelection::Numbered(ref name, 3)) if name == "DisplayList building" => {}
+        _ => panic!("couldn't parse the section selection"),
     }
+    assert!(SectionSelection::parse_numbered("DisplayList building").is_none());
+    assert!(SectionSelection::parse_numbered("DisplayList building#x").is_none());
+}
+
+#[test]
+fn test_select_sections() {
+    let log = [
+        "==1== Begin Reflow",
+        "==1== End Reflow",
+        "==1== Begin Reflow tid=2",
+        "==1== Begin Reflow",
+        "==1== Begin Reflow",
+        "==1== End Reflow",
+        "==1== End Reflow tid=2",
+        "==1== End Reflow",
+        "==1== Begin Reflow",
+    ];
+    let ranges = |selection: &SectionSelection| -> Vec<(String, usize, usize)> {
+        let iter = log.iter().enumerate().map(|(i, line)| (i, line.to_string()));
+        select_sections(1, iter, None, selection)
+            .into_iter()
+            .map(|range| (range.label, range.start_line_index, range.end_line_index))
+            .collect()
+    };
+    // The third section ends at the second process-wide End after it, and
+    // the section in between doesn't count.
+    let third = SectionSelection::Numbered("Reflow".to_owned(), 3);
+    assert_eq!(ranges(&third), vec![("Reflow#3".to_owned(), 3, 7)]);
+    let last = SectionSelection::Numbered("Reflow".to_owned(), 5);
+    assert_eq!(ranges(&last), vec![("Reflow#5".to_owned(), 8, usize::MAX)]);
+    assert!(ranges(&SectionSelection::Numbered("Reflow".to_owned(), 6)).is_empty());
+
+    // The same boundaries, as the log index has them.
+    let boundaries = |tid, start_line_index, end_line_index| SectionBoundaries {
+        name: "Reflow".to_owned(),
+        tid,
+        start_line_index,
+        end_line_index,
+    };
+    let sections = vec![
+        boundaries(None, 0, Some(1)),
+        boundaries(Some(2), 2, Some(6)),
+        boundaries(None, 3, Some(7)),
+        boundaries(None, 4, Some(5)),
+        boundaries(None, 8, None),
+    ];
+    let indexed_ranges = |selection: &SectionSelection| -> Vec<(String, usize, usize)> {
+        select_indexed_sections(&sections, None, selection)
+            .unwrap()
+            .into_iter()
+            .map(|range| (range.label, range.start_line_index, range.end_line_index))
+            .collect()
+    };
+    assert_eq!(indexed_ranges(&third), ranges(&third));
+    assert_eq!(indexed_ranges(&last), ranges(&last));
+    assert_eq!(indexed_ranges(&SectionSelection::All), ranges(&SectionSelection::All));
+    assert!(select_indexed_sections(&sections, None, &SectionSelection::Largest).is_none());
 }
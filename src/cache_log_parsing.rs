@@ -23,8 +23,8 @@ pub enum LineContent<'a> {
         tid: u32,
     },
     StackForLLMiss(usize),
-    BeginDisplayList,
-    EndDisplayList,
+    BeginSection { name: &'a str, tid: Option<u32> },
+    EndSection { name: &'a str, tid: Option<u32> },
     AddFrame { index: usize, address: u64 },
     AddStack {
         index: usize,
@@ -57,6 +57,8 @@ pub enum LineContent<'a> {
 // LLMiss: why=I1_NoX size=3 addr=000000000596e8fe tid=1
 // Begin DisplayList building
 // End DisplayList building
+// Begin <section name> [tid=<tid>]
+// End <section name> [tid=<tid>]
 // add_frame 3 000000000129fe07d (<frame_index> <frame_address>)
 // add_stack 5 2 3 (<stack_index> <parent_stack> <frame_index>)
 
@@ -128,16 +130,41 @@ named!(parse_stack_for_llmiss<&str, LineContent>, do_parse!(
   ( LineContent::StackForLLMiss(stack_index) )
 ));
 
-// Begin DisplayList building
-named!(parse_begin_display_list<&str, LineContent>, do_parse!(
-  tag!("Begin DisplayList building") >>
-  ( LineContent::BeginDisplayList )
+// Splits "Reflow tid=3" into ("Reflow", Some(3)). Markers without a tid
+// apply to the whole process. Section names start with an uppercase
+// letter and consist of words of letters, digits, '_' and '-' separated by
+// single spaces, so that ordinary output like "End of stream." isn't
+// mistaken for a marker.
+fn split_section_marker(s: &str) -> Option<(&str, Option<u32>)> {
+    let (name, tid) = match s.rfind(" tid=") {
+        Some(pos) => (&s[..pos], Some(s[pos + " tid=".len()..].parse().ok()?)),
+        None => (s, None),
+    };
+    if !is_section_name(name) {
+        return None;
+    }
+    Some((name, tid))
+}
+
+fn is_section_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase())
+        && name.split(' ').all(|word| {
+            !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+}
+
+// Begin <section name> [tid=<tid>], e.g. Begin DisplayList building
+named!(parse_begin_section<&str, LineContent>, do_parse!(
+  tag!("Begin ") >>
+  marker: map_opt!(rest_s, split_section_marker) >>
+  ( LineContent::BeginSection{ name: marker.0, tid: marker.1 } )
 ));
 
-// End DisplayList building
-named!(parse_end_display_list<&str, LineContent>, do_parse!(
-  tag!("End DisplayList building") >>
-  ( LineContent::EndDisplayList )
+// End <section name> [tid=<tid>], e.g. End DisplayList building
+named!(parse_end_section<&str, LineContent>, do_parse!(
+  tag!("End ") >>
+  marker: map_opt!(rest_s, split_section_marker) >>
+  ( LineContent::EndSection{ name: marker.0, tid: marker.1 } )
 ));
 
 // [ArenaAllocator:0x976d1300] Allocating arena chunk at 0x976d7b70 with size 2048 bytes
@@ -225,7 +252,7 @@ named!(parse_line<&str, LineContent>, alt!(
     parse_llcache_info |
     parse_llcache_line_swap | parse_llmiss | parse_stack_for_llmiss |
     parse_llcache_line_swap_with_used_bytes |
-    parse_begin_display_list |  parse_end_display_list |
+    parse_begin_section | parse_end_section |
     parse_allocate_arena_chunk | parse_deallocate_arena_chunk |
     parse_association | parse_extra_field |
     parse_add_frame | parse_add_stack | parse_shared_libs_chunk |
@@ -304,3 +331,32 @@ fn test_parse_line() {
         ))
     );
 }
+
+#[test]
+fn test_parse_section_markers() {
+    assert_eq!(
+        parse_line_of_pid("==8884== Begin DisplayList building"),
+        Some((8884, LineContent::BeginSection { name: "DisplayList building", tid: None }))
+    );
+    assert_eq!(
+        parse_line_of_pid("==8884== End Style flush"),
+        Some((8884, LineContent::EndSection { name: "Style flush", tid: None }))
+    );
+    assert_eq!(
+        parse_line_of_pid("==8884== Begin Reflow tid=18"),
+        Some((8884, LineContent::BeginSection { name: "Reflow", tid: Some(18) }))
+    );
+    // Anything else that starts with "Begin " or "End " is ordinary output.
+    assert_eq!(
+        parse_line_of_pid("==8884== End Reflow tid=x"),
+        Some((8884, LineContent::Other("End Reflow tid=x")))
+    );
+    assert_eq!(
+        parse_line_of_pid("==8884== End of stream reached."),
+        Some((8884, LineContent::Other("End of stream reached.")))
+    );
+    assert_eq!(
+        parse_line_of_pid("==8884== Begin  Reflow"),
+        Some((8884, LineContent::Other("Begin  Reflow")))
+    );
+}
@@ -0,0 +1,409 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use pretty_bytes::converter::convert;
+use cache_log_info::{CacheLineRead, ReadsCollector};
+use cpucache::{CacheAccess, CacheConfig, CPUCache};
+use log_events::consume_log;
+use prefetcher::{Prefetcher, PrefetcherKind};
+use replacement_policy::ReplacementPolicyKind;
+
+/// Parses a cache or line size in bytes, optionally with a K or M suffix,
+/// e.g. "64", "512K" or "2M".
+pub fn parse_cache_size(s: &str) -> Option<u32> {
+    let s = s.trim().trim_end_matches(['B', 'b']);
+    let (number, factor) = match s.chars().last()? {
+        'K' | 'k' => (&s[..s.len() - 1], 1 << 10),
+        'M' | 'm' => (&s[..s.len() - 1], 1 << 20),
+        _ => (s, 1),
+    };
+    number.parse::<u32>().ok()?.checked_mul(factor)
+}
+
+fn format_cache_size(size: u32) -> String {
+    if size.is_multiple_of(1 << 20) {
+        format!("{}M", size >> 20)
+    } else if size.is_multiple_of(1 << 10) {
+        format!("{}K", size >> 10)
+    } else {
+        format!("{}", size)
+    }
+}
+
+/// The numbers of one cache configuration for a line range.
+#[derive(Serialize)]
+pub struct CacheSimulationResult {
+    pub config: CacheConfig,
+    pub policy: ReplacementPolicyKind,
+    pub prefetcher: PrefetcherKind,
+    /// The number of cache lines that were read into the cache because
+    /// they were accessed.
+    pub misses: u64,
+    /// Includes the prefetched bytes.
+    pub read_bytes: u64,
+    /// The bytes of the distinct cache lines that were read.
+    pub unique_bytes_read: u64,
+    pub used_bytes: u64,
+    pub wasted_bytes: u64,
+    /// The number of cache lines that the prefetcher read into the cache.
+    pub prefetches: u64,
+    pub prefetched_bytes: u64,
+    /// The used bytes of the prefetched lines, from the accesses between
+    /// their prefetch and their eviction.
+    pub prefetched_used_bytes: u64,
+    pub prefetched_wasted_bytes: u64,
+}
+
+impl CacheSimulationResult {
+    fn print(&self, recorded_read_bytes: u64, show_prefetches: bool, note: &str) {
+        let prefetches = if show_prefetches {
+            format!(
+                " {:>13} {:>11} {:>7.1}%",
+                self.prefetcher.name(),
+                convert(self.prefetched_bytes as f64),
+                percentage(self.prefetched_wasted_bytes, self.prefetched_bytes)
+            )
+        } else {
+            String::new()
+        };
+        println!(
+            "{:>8} {:>6} {:>6} {:>7} {:>10} {:>11} {:>7.1}% {:>11} {:>7.1}% {:>11.1}%{}  {}",
+            format_cache_size(self.config.size),
+            format_cache_size(self.config.line_size),
+            format!("{}-way", self.config.assoc),
+            self.policy.name(),
+            self.misses,
+            convert(self.read_bytes as f64),
+            percentage(self.read_bytes, recorded_read_bytes),
+            convert(self.wasted_bytes as f64),
+            percentage(self.wasted_bytes, self.read_bytes),
+            percentage(self.read_bytes.saturating_sub(self.unique_bytes_read), self.unique_bytes_read),
+            prefetches,
+            note
+        );
+    }
+}
+
+pub fn percentage(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64 * 100.0
+    }
+}
+
+/// A re-simulation of the LL cache with a different geometry, fed with the
+/// cache lines that the recorded simulation read. A recorded line either
+/// covers several simulated lines or is a piece of one, and its used bytes
+/// are attributed to those pieces evenly, because the log only has the
+/// number of used bytes and not which bytes they were.
+struct SimulatedCache {
+    config: CacheConfig,
+    policy: ReplacementPolicyKind,
+    prefetcher_kind: PrefetcherKind,
+    cache: CPUCache,
+    prefetcher: Option<Box<dyn Prefetcher>>,
+    recorded_line_size: u32,
+    piece_size: u32,
+    /// The cached lines, by line address.
+    lines: HashMap<u64, CachedLine>,
+    lines_read: HashSet<u64>,
+    misses: u64,
+    used_bytes: f64,
+    prefetches: u64,
+    prefetched_used_bytes: f64,
+}
+
+struct CachedLine {
+    /// The used bytes of each piece of the line.
+    used_bytes_per_piece: Vec<f64>,
+    /// Whether the line was read into the cache by the prefetcher.
+    prefetched: bool,
+}
+
+impl CachedLine {
+    fn used_bytes(&self) -> f64 {
+        self.used_bytes_per_piece.iter().sum()
+    }
+}
+
+impl SimulatedCache {
+    fn new(
+        config: CacheConfig,
+        policy: ReplacementPolicyKind,
+        prefetcher: PrefetcherKind,
+        recorded_line_size: u32,
+    ) -> SimulatedCache {
+        SimulatedCache {
+            config,
+            policy,
+            prefetcher_kind: prefetcher,
+            cache: CPUCache::with_policy(&config, policy),
+            prefetcher: prefetcher.create(config.line_size),
+            recorded_line_size,
+            piece_size: cmp::min(config.line_size, recorded_line_size),
+            lines: HashMap::new(),
+            lines_read: HashSet::new(),
+            misses: 0,
+            used_bytes: 0.0,
+            prefetches: 0,
+            prefetched_used_bytes: 0.0,
+        }
+    }
+
+    fn replay(&mut self, read: &CacheLineRead) {
+        let used_bytes = read.used_bytes.unwrap_or(read.size) as f64;
+        let used_bytes_per_piece = used_bytes * self.piece_size as f64 / self.recorded_line_size as f64;
+        let line_mask = !(self.config.line_size as u64 - 1);
+        let recorded_line_end = read.address + self.recorded_line_size as u64;
+        let mut prefetches = Vec::new();
+        for piece_start in (read.address..recorded_line_end).step_by(self.piece_size as usize) {
+            let line_start = piece_start & line_mask;
+            let access = self.cache.access(piece_start);
+            if let CacheAccess::Miss { evicted } = access {
+                self.misses += 1;
+                self.insert_line(line_start, evicted, false);
+            }
+            let piece = ((piece_start - line_start) / self.piece_size as u64) as usize;
+            if let Some(line) = self.lines.get_mut(&line_start) {
+                // A line that stays in the simulated cache may have been
+                // read several times by the recorded simulation. The bytes
+                // that were used each time probably overlap, so count the
+                // most that were used at once.
+                let pieces = &mut line.used_bytes_per_piece;
+                pieces[piece] = pieces[piece].max(used_bytes_per_piece);
+            }
+            if let Some(ref mut prefetcher) = self.prefetcher {
+                prefetcher.on_access(line_start, &access, &mut prefetches);
+            }
+            for prefetch in prefetches.drain(..) {
+                if let CacheAccess::Miss { evicted } = self.cache.prefetch(prefetch) {
+                    self.prefetches += 1;
+                    self.insert_line(prefetch & line_mask, evicted, true);
+                }
+            }
+        }
+    }
+
+    fn insert_line(&mut self, line_start: u64, evicted: Option<u64>, prefetched: bool) {
+        if let Some(evicted) = evicted {
+            self.evict(evicted);
+        }
+        let pieces_per_line = (self.config.line_size / self.piece_size) as usize;
+        let line = CachedLine {
+            used_bytes_per_piece: vec![0.0; pieces_per_line],
+            prefetched,
+        };
+        self.lines.insert(line_start, line);
+        self.lines_read.insert(line_start);
+    }
+
+    fn evict(&mut self, line_start: u64) {
+        if let Some(line) = self.lines.remove(&line_start) {
+            self.add_used_bytes(&line);
+        }
+    }
+
+    fn add_used_bytes(&mut self, line: &CachedLine) {
+        let used_bytes = line.used_bytes();
+        self.used_bytes += used_bytes;
+        if line.prefetched {
+            self.prefetched_used_bytes += used_bytes;
+        }
+    }
+
+    /// The lines that are still cached at the end count as evicted.
+    fn into_result(mut self) -> CacheSimulationResult {
+        let lines: Vec<CachedLine> = self.lines.drain().map(|(_, line)| line).collect();
+        for line in &lines {
+            self.add_used_bytes(line);
+        }
+        let line_size = self.config.line_size as u64;
+        let read_bytes = (self.misses + self.prefetches) * line_size;
+        let used_bytes = self.used_bytes.round() as u64;
+        let prefetched_bytes = self.prefetches * line_size;
+        let prefetched_used_bytes = self.prefetched_used_bytes.round() as u64;
+        CacheSimulationResult {
+            config: self.config,
+            policy: self.policy,
+            prefetcher: self.prefetcher_kind,
+            misses: self.misses,
+            read_bytes,
+            unique_bytes_read: self.lines_read.len() as u64 * line_size,
+            used_bytes,
+            wasted_bytes: read_bytes.saturating_sub(used_bytes),
+            prefetches: self.prefetches,
+            prefetched_bytes,
+            prefetched_used_bytes,
+            prefetched_wasted_bytes: prefetched_bytes.saturating_sub(prefetched_used_bytes),
+        }
+    }
+}
+
+/// The recorded LL cache next to the simulated ones.
+#[derive(Serialize)]
+pub struct CacheSimulationReport {
+    recorded: CacheSimulationResult,
+    simulated: Vec<CacheSimulationResult>,
+}
+
+impl CacheSimulationReport {
+    pub fn print(&self) {
+        let show_prefetches = self
+            .simulated
+            .iter()
+            .any(|result| result.prefetcher != PrefetcherKind::None);
+        let prefetch_columns = if show_prefetches {
+            format!(" {:>13} {:>11} {:>8}", "Prefetcher", "Prefetched", "Wasted")
+        } else {
+            String::new()
+        };
+        println!(
+            "{:>8} {:>6} {:>6} {:>7} {:>10} {:>11} {:>8} {:>11} {:>8} {:>12}{}",
+            "Size", "Line", "Assoc", "Policy", "Misses", "Read", "", "Wasted", "", "Multi-read", prefetch_columns
+        );
+        let recorded_read_bytes = self.recorded.read_bytes;
+        self.recorded.print(recorded_read_bytes, show_prefetches, "(recorded)");
+        let mut has_smaller_caches = false;
+        for result in &self.simulated {
+            let is_smaller = result.config.size < self.recorded.config.size;
+            has_smaller_caches |= is_smaller;
+            result.print(recorded_read_bytes, show_prefetches, if is_smaller { "*" } else { "" });
+        }
+        if has_smaller_caches {
+            println!();
+            println!(
+                "* Smaller than the recorded cache. The log only has the reads that missed the recorded cache, \
+                 so these underestimate the misses."
+            );
+        }
+    }
+}
+
+/// Replays the cache lines that were read between from_line and to_line
+/// in every one of the given cache configurations with each combination of
+/// the given replacement policies and prefetchers, starting with empty
+/// caches. The log only has the accesses that missed
+/// the recorded cache, so the hits of the recorded cache are never
+/// replayed. Caches that are at least as large as the recorded one would
+/// have hit on those too, they just don't see the hits move lines to the
+/// front of their sets. Smaller caches would have missed some of them, so
+/// their misses are underestimated. The smaller the recorded cache, the
+/// more of the accesses are in the log. For the same reason, prefetched
+/// lines that would only have been used by those hits count as wasted.
+#[allow(clippy::too_many_arguments)]
+pub fn simulate_caches<T>(
+    pid: i32,
+    iter: T,
+    from_line: usize,
+    to_line: usize,
+    recorded: CacheConfig,
+    configs: &[CacheConfig],
+    policies: &[ReplacementPolicyKind],
+    prefetchers: &[PrefetcherKind],
+) -> CacheSimulationReport
+where
+    T: iter::Iterator<Item = (usize, String)>,
+{
+    let mut reads_info = ReadsCollector::for_line_range(from_line, to_line);
+    consume_log(pid, iter, to_line, &mut [&mut reads_info]);
+    simulate_caches_for_reads(&reads_info.into_reads(), recorded, configs, policies, prefetchers)
+}
+
+fn simulate_caches_for_reads(
+    reads: &[CacheLineRead],
+    recorded: CacheConfig,
+    configs: &[CacheConfig],
+    policies: &[ReplacementPolicyKind],
+    prefetchers: &[PrefetcherKind],
+) -> CacheSimulationReport {
+    let mut caches: Vec<SimulatedCache> = configs
+        .iter()
+        .flat_map(|&config| {
+            policies.iter().flat_map(move |&policy| {
+                prefetchers
+                    .iter()
+                    .map(move |&prefetcher| SimulatedCache::new(config, policy, prefetcher, recorded.line_size))
+            })
+        })
+        .collect();
+    let mut read_bytes = 0;
+    let mut used_bytes = 0;
+    let mut lines_read = HashSet::new();
+    for read in reads {
+        read_bytes += read.size as u64;
+        used_bytes += read.used_bytes.unwrap_or(read.size) as u64;
+        lines_read.insert(read.address);
+        for cache in &mut caches {
+            cache.replay(read);
+        }
+    }
+    let recorded = CacheSimulationResult {
+        config: recorded,
+        // Cachegrind's simulation.
+        policy: ReplacementPolicyKind::Lru,
+        prefetcher: PrefetcherKind::None,
+        misses: reads.len() as u64,
+        read_bytes,
+        unique_bytes_read: lines_read.len() as u64 * recorded.line_size as u64,
+        used_bytes,
+        wasted_bytes: read_bytes.saturating_sub(used_bytes),
+        prefetches: 0,
+        prefetched_bytes: 0,
+        prefetched_used_bytes: 0,
+        prefetched_wasted_bytes: 0,
+    };
+    CacheSimulationReport {
+        recorded,
+        simulated: caches.into_iter().map(SimulatedCache::into_result).collect(),
+    }
+}
+
+#[test]
+fn test_simulate_caches() {
+    assert_eq!(parse_cache_size("64"), Some(64));
+    assert_eq!(parse_cache_size("512K"), Some(512 * 1024));
+    assert_eq!(parse_cache_size("2MB"), Some(2 * 1024 * 1024));
+    assert_eq!(parse_cache_size("2G"), None);
+    assert_eq!(format_cache_size(2 * 1024 * 1024), "2M");
+
+    // A recorded 2-line direct-mapped cache, where 0x1000 and 0x1080
+    // keep evicting each other.
+    let read = |address, used_bytes| ::cache_log_info::test_read(address, used_bytes, None);
+    let reads = vec![read(0x1000, 8), read(0x1080, 64), read(0x1000, 16), read(0x1040, 32)];
+    let recorded = CacheConfig { size: 128, line_size: 64, assoc: 1 };
+    let configs = [
+        CacheConfig { size: 128, line_size: 64, assoc: 2 },
+        CacheConfig { size: 256, line_size: 128, assoc: 2 },
+        CacheConfig { size: 64, line_size: 32, assoc: 1 },
+    ];
+    let report = simulate_caches_for_reads(&reads, recorded, &configs, &[ReplacementPolicyKind::Lru], &[PrefetcherKind::None]);
+    let numbers = |result: &CacheSimulationResult| (result.misses, result.read_bytes, result.wasted_bytes);
+    assert_eq!(numbers(&report.recorded), (4, 256, 136));
+    // Two ways keep both lines; 0x1000 is read once and counts the 16 bytes
+    // of its second recorded use. Reading 0x1040 evicts 0x1080.
+    assert_eq!(numbers(&report.simulated[0]), (3, 192, 80));
+    // 0x1000 and 0x1040 share a 128 byte line.
+    assert_eq!(numbers(&report.simulated[1]), (2, 256, 144));
+    // Every recorded line is two simulated lines, one in each set, so this
+    // behaves like the recorded cache.
+    assert_eq!(numbers(&report.simulated[2]), (8, 256, 136));
+
+    // With two ways and another read of 0x1000 at the end, LRU keeps
+    // 0x1000 because it was used more recently than 0x1080, but FIFO evicts
+    // it because it was read first, and has to read it again.
+    let reads = vec![read(0x1000, 8), read(0x1080, 64), read(0x1000, 16), read(0x1040, 32), read(0x1000, 8)];
+    let policies = [ReplacementPolicyKind::Lru, ReplacementPolicyKind::Fifo];
+    let report = simulate_caches_for_reads(&reads, recorded, &configs[..1], &policies, &[PrefetcherKind::None]);
+    assert_eq!(report.recorded.unique_bytes_read, 192);
+    assert_eq!((report.simulated[0].misses, report.simulated[0].unique_bytes_read), (3, 192));
+    assert_eq!((report.simulated[1].misses, report.simulated[1].unique_bytes_read), (4, 192));
+
+    // The next-line prefetcher reads 0x1040 before it's accessed, and 0x1080
+    // when 0x1040 is first accessed, which is never used.
+    let reads = vec![read(0x1000, 8), read(0x1040, 32)];
+    let report = simulate_caches_for_reads(&reads, recorded, &configs[..1], &policies[..1], &[PrefetcherKind::NextLine]);
+    let result = &report.simulated[0];
+    assert_eq!((result.misses, result.prefetches, result.read_bytes, result.wasted_bytes), (1, 2, 192, 152));
+    assert_eq!((result.prefetched_bytes, result.prefetched_used_bytes, result.prefetched_wasted_bytes), (128, 32, 96));
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::iter;
+use pretty_bytes::converter::convert;
+use arenas::Arenas;
+use cache_log_info::{ArenaInfoCollector, StackInfoCollector};
+use cache_log_parsing::LineContent;
+use cpucache::{CacheConfig, CPUCache};
+use log_events::{consume_log, LogEventConsumer};
+use shared_libraries::SharedLibraries;
+
+/// Rebuilds the contents of the recorded LL cache by replaying its swaps,
+/// starting with the empty cache from the "LL cache information" line.
+/// Swaps that don't fit the cache contents are skipped with a warning.
+#[derive(Default)]
+pub struct CacheContentsCollector {
+    cache: Option<Result<(CacheConfig, CPUCache), String>>,
+    skipped_swaps: u64,
+}
+
+impl CacheContentsCollector {
+    pub fn new() -> CacheContentsCollector {
+        CacheContentsCollector {
+            cache: None,
+            skipped_swaps: 0,
+        }
+    }
+
+    /// Fails if the log didn't have the cache information of the process,
+    /// or if it describes a cache that can't be simulated.
+    pub fn into_cache(self) -> Result<(CacheConfig, CPUCache), String> {
+        self.cache
+            .unwrap_or_else(|| Err("Couldn't find the LL cache information of the process in the log.".to_owned()))
+    }
+
+    pub fn skipped_swaps(&self) -> u64 {
+        self.skipped_swaps
+    }
+}
+
+impl LogEventConsumer for CacheContentsCollector {
+    fn process_line(&mut self, line_index: usize, line_content: &LineContent) {
+        match *line_content {
+            LineContent::LLCacheInfo { size, line_size, assoc } => {
+                if self.cache.is_none() {
+                    let config = CacheConfig { size, line_size: line_size as u32, assoc };
+                    self.cache = Some(match config.check() {
+                        Ok(()) => Ok((config, CPUCache::from_config(&config))),
+                        Err(e) => Err(format!("Line {} has an LL cache that can't be simulated: {}", line_index, e)),
+                    });
+                }
+            }
+            LineContent::LLCacheLineSwap { new_start, old_start, .. } => {
+                if let Some(Ok((_, ref mut cache))) = self.cache {
+                    if let Err(e) = cache.exchange(new_start, old_start) {
+                        eprintln!("Skipping the swap at line {}: {}", line_index, e);
+                        self.skipped_swaps += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// What the memory of a cached line belongs to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LineOwner {
+    /// An arena, with its kind (see Arenas::arena_kind).
+    Arena { arena: String, kind: String },
+    /// The code or static data of a shared library.
+    Library { name: String },
+    /// Anything else, e.g. the heap outside of arenas, or the stack.
+    Other,
+}
+
+impl LineOwner {
+    /// Arenas take precedence over libraries.
+    fn for_address(address: u64, arenas: &Arenas, libs: Option<&SharedLibraries>) -> LineOwner {
+        if let Some(arena) = arenas.arena_covering_address(address) {
+            let kind = arenas.arena_kind(&arena);
+            return LineOwner::Arena { arena, kind };
+        }
+        match libs.and_then(|libs| libs.lib_for_address(address)) {
+            Some(lib) => LineOwner::Library { name: lib.name.clone() },
+            None => LineOwner::Other,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match *self {
+            LineOwner::Arena { ref arena, .. } => arena,
+            LineOwner::Library { ref name } => name,
+            LineOwner::Other => "(other)",
+        }
+    }
+
+    fn description(&self) -> String {
+        match *self {
+            LineOwner::Arena { ref arena, ref kind } => format!("{} ({})", arena, kind),
+            _ => self.name().to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ResidentLine {
+    pub address: u64,
+    /// The name of the owner, see LineOwner::name.
+    pub owner: String,
+}
+
+#[derive(Serialize)]
+pub struct SetContents {
+    pub set: u64,
+    /// Sorted by address.
+    pub lines: Vec<ResidentLine>,
+}
+
+#[derive(Serialize)]
+pub struct OwnerBytes {
+    pub owner: LineOwner,
+    pub lines: u64,
+    pub bytes: u64,
+}
+
+/// The contents of the recorded LL cache right before a line of the log.
+#[derive(Serialize)]
+pub struct CacheSnapshot {
+    pub line_index: usize,
+    pub config: CacheConfig,
+    pub resident_lines: u64,
+    /// The swaps that didn't fit the replayed cache contents, e.g. because
+    /// the evicted line wasn't cached. The snapshot can be off by these.
+    pub skipped_swaps: u64,
+    pub sets: Vec<SetContents>,
+    /// Sorted by bytes, descending.
+    pub owners: Vec<OwnerBytes>,
+}
+
+impl CacheSnapshot {
+    fn new(
+        line_index: usize,
+        config: CacheConfig,
+        cache: &CPUCache,
+        skipped_swaps: u64,
+        arenas: &Arenas,
+        libs: Option<&SharedLibraries>,
+    ) -> CacheSnapshot {
+        let mut owners: Vec<OwnerBytes> = Vec::new();
+        let mut owner_indexes: HashMap<String, usize> = HashMap::new();
+        let mut sets = Vec::new();
+        for set in 0..cache.set_count() {
+            let mut lines = Vec::new();
+            for address in cache.lines_in_set(set) {
+                let owner = LineOwner::for_address(address, arenas, libs);
+                let name = owner.name().to_owned();
+                let index = *owner_indexes.entry(name.clone()).or_insert_with(|| {
+                    owners.push(OwnerBytes { owner, lines: 0, bytes: 0 });
+                    owners.len() - 1
+                });
+                owners[index].lines += 1;
+                owners[index].bytes += config.line_size as u64;
+                lines.push(ResidentLine { address, owner: name });
+            }
+            sets.push(SetContents { set, lines });
+        }
+        owners.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.owner.name().cmp(b.owner.name())));
+        CacheSnapshot {
+            line_index,
+            config,
+            resident_lines: owners.iter().map(|owner| owner.lines).sum(),
+            skipped_swaps,
+            sets,
+            owners,
+        }
+    }
+
+    pub fn print(&self) {
+        let line_count = (self.config.size / self.config.line_size) as u64;
+        println!(
+            "LL cache before line {}: {} B, {} B lines, {}-way, {} sets",
+            self.line_index,
+            self.config.size,
+            self.config.line_size,
+            self.config.assoc,
+            self.sets.len()
+        );
+        println!(
+            "{} of {} lines resident ({:.1}%)",
+            self.resident_lines,
+            line_count,
+            self.resident_lines as f64 / line_count as f64 * 100.0
+        );
+        if self.skipped_swaps > 0 {
+            println!(
+                "{} swaps didn't fit the cache contents and were skipped, so this can be inaccurate.",
+                self.skipped_swaps
+            );
+        }
+        println!();
+        println!("{:>11} {:>8}  Owner", "Bytes", "Lines");
+        for owner in &self.owners {
+            println!("{:>11} {:>8}  {}", convert(owner.bytes as f64), owner.lines, owner.owner.description());
+        }
+        println!();
+        println!("{:>8} {:>8}  Owners", "Set", "Lines");
+        for set in &self.sets {
+            // The owners of the set in the order of their first line, with
+            // the number of lines they have in the set.
+            let mut owners: Vec<(&str, usize)> = Vec::new();
+            for line in &set.lines {
+                match owners.iter_mut().find(|&&mut (name, _)| name == line.owner) {
+                    Some(&mut (_, ref mut count)) => *count += 1,
+                    None => owners.push((&line.owner, 1)),
+                }
+            }
+            let owners: Vec<String> = owners
+                .into_iter()
+                .map(|(name, count)| format!("{} ({})", name, count))
+                .collect();
+            println!(
+                "{:>8} {:>8}  {}",
+                set.set,
+                format!("{}/{}", set.lines.len(), self.config.assoc),
+                owners.join(", ")
+            );
+        }
+    }
+}
+
+/// Replays the swaps of the recorded LL cache up to at_line, and returns
+/// which lines the cache holds at that point, and what the memory of each
+/// line belongs to, according to the arenas and shared libraries at that
+/// point. This needs to start at the beginning of the log, because the
+/// cache contents are not part of the checkpoints in the log index.
+/// Fails if the log doesn't have usable cache information for the process.
+pub fn cache_snapshot_at<T>(pid: i32, iter: T, at_line: usize) -> Result<CacheSnapshot, String>
+where
+    T: iter::Iterator<Item = (usize, String)>,
+{
+    let mut cache_contents = CacheContentsCollector::new();
+    let mut arena_info = ArenaInfoCollector::new();
+    let mut stack_info = StackInfoCollector::new();
+    consume_log(pid, iter, at_line, &mut [&mut cache_contents, &mut arena_info, &mut stack_info]);
+    let skipped_swaps = cache_contents.skipped_swaps();
+    let (config, cache) = cache_contents.into_cache()?;
+    let libs = stack_info.get_stack_table().libs;
+    Ok(CacheSnapshot::new(at_line, config, &cache, skipped_swaps, arena_info.arenas(), libs.as_ref()))
+}
+
+#[test]
+fn test_cache_snapshot_at() {
+    let log = [
+        "==1== LL cache information: 256 B, 64 B, 2-way associative",
+        "==1== SharedLibsChunk: [{\"start\": 4096, \"end\": 8192, \"offset\": 0, \"name\": \"libxul.so\", \"path\": \"\", \
+         \"debugName\": \"\", \"debugPath\": \"\", \"breakpadId\": \"\", \"arch\": \"\"}]",
+        "==1== [ArenaAllocator:0x1] Allocating arena chunk at 0x4000 with size 4096 bytes",
+        "==1== LLCacheSwap: new_start=1000 old_start=0 size=64",
+        "==2== LLCacheSwap: new_start=9000 old_start=0 size=64",
+        "==1== LLCacheSwap: new_start=4040 old_start=0 size=64",
+        "==1== LLCacheSwap: new_start=8000 old_start=0 size=64",
+        "==1== LLCacheSwap: new_start=4080 old_start=8000 size=64",
+        "==1== LLCacheSwap: new_start=4000 old_start=1000 size=64",
+    ];
+    let iter = log.iter().enumerate().map(|(i, line)| (i, line.to_string()));
+    // The last swap is at the line of the snapshot, so it hasn't happened
+    // yet.
+    let snapshot = cache_snapshot_at(1, iter, 8).unwrap();
+    assert_eq!(snapshot.resident_lines, 3);
+    assert_eq!(snapshot.skipped_swaps, 0);
+    let set_lines: Vec<Vec<(u64, &str)>> = snapshot
+        .sets
+        .iter()
+        .map(|set| set.lines.iter().map(|line| (line.address, &line.owner[..])).collect())
+        .collect();
+    assert_eq!(
+        set_lines,
+        vec![vec![(0x1000, "libxul.so"), (0x4080, "ArenaAllocator:0x1")], vec![(0x4040, "ArenaAllocator:0x1")]]
+    );
+    let owners: Vec<(&str, u64)> = snapshot
+        .owners
+        .iter()
+        .map(|owner| (owner.owner.name(), owner.bytes))
+        .collect();
+    assert_eq!(owners, vec![("ArenaAllocator:0x1", 128), ("libxul.so", 64)]);
+    assert_eq!(
+        snapshot.owners[0].owner,
+        LineOwner::Arena { arena: "ArenaAllocator:0x1".to_owned(), kind: "ArenaAllocator".to_owned() }
+    );
+}
+
+#[test]
+fn test_cache_snapshot_errors() {
+    let snapshot_at = |log: &[&str], at_line| {
+        let iter = log.iter().enumerate().map(|(i, line)| (i, line.to_string()));
+        cache_snapshot_at(1, iter, at_line)
+    };
+    assert!(snapshot_at(&["==1== LLCacheSwap: new_start=1000 old_start=0 size=64"], 1).is_err());
+    assert!(snapshot_at(&["==1== LL cache information: 192 B, 64 B, 1-way associative"], 1).is_err());
+    // The second swap evicts a line that isn't cached, and the third one
+    // would move a line to another set.
+    let log = [
+        "==1== LL cache information: 256 B, 64 B, 2-way associative",
+        "==1== LLCacheSwap: new_start=1000 old_start=0 size=64",
+        "==1== LLCacheSwap: new_start=2000 old_start=3000 size=64",
+        "==1== LLCacheSwap: new_start=2040 old_start=1000 size=64",
+        "==1== LLCacheSwap: new_start=4000 old_start=0 size=64",
+    ];
+    let snapshot = snapshot_at(&log, 5).unwrap();
+    assert_eq!(snapshot.skipped_swaps, 2);
+    assert_eq!(snapshot.resident_lines, 2);
+}
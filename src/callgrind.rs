@@ -0,0 +1,248 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use profile::{ProfileBuilder, ResolvedStacks};
+
+/// A function in the callgrind format. Like valgrind, we call unknown
+/// objects and files "???".
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Function {
+    object: String,
+    file: String,
+    name: String,
+}
+
+/// The function of the given frame, and the line in it.
+fn frame_location(resolved_stacks: &ResolvedStacks, frame: usize) -> (Function, usize) {
+    let address = resolved_stacks.stack_table.frames[frame].0;
+    let object = resolved_stacks
+        .stack_table
+        .libs
+        .as_ref()
+        .and_then(|libs| libs.lib_for_address(address))
+        .map_or_else(|| "???".to_owned(), |lib| lib.name.clone());
+    let frame_info = resolved_stacks.frame_info(frame);
+    let file = frame_info
+        .map(|info| info.file_path_str.as_str())
+        .filter(|file| *file != "??")
+        .unwrap_or("???")
+        .to_owned();
+    let line = frame_info.map_or(0, |info| info.line_number);
+    let function = Function {
+        object,
+        file,
+        name: resolved_stacks.frame_name(frame),
+    };
+    (function, line)
+}
+
+fn add_costs(costs: &mut Vec<u64>, other_costs: &[u64]) {
+    if costs.is_empty() {
+        costs.resize(other_costs.len(), 0);
+    }
+    for (cost, other_cost) in costs.iter_mut().zip(other_costs) {
+        *cost += *other_cost;
+    }
+}
+
+/// A call from one line of a function to a line of another function.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Call {
+    caller_line: usize,
+    callee: Function,
+    callee_line: usize,
+}
+
+#[derive(Default)]
+struct FunctionCosts {
+    /// The exclusive costs per line.
+    self_costs: BTreeMap<usize, Vec<u64>>,
+    /// The number of samples and the inclusive costs per call.
+    calls: BTreeMap<Call, (u64, Vec<u64>)>,
+}
+
+/// Callgrind event names can't have spaces, so "Read bytes" becomes
+/// "ReadBytes".
+fn event_name_for_track(track_name: &str) -> String {
+    track_name
+        .split_whitespace()
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// Names in the callgrind format can be compressed: the first use of a name
+/// assigns it a number, "(1) name", and later uses only need the number.
+struct NameCompression {
+    ids: HashMap<String, usize>,
+}
+
+impl NameCompression {
+    pub fn new() -> NameCompression {
+        NameCompression { ids: HashMap::new() }
+    }
+
+    pub fn compress(&mut self, name: &str) -> String {
+        if let Some(id) = self.ids.get(name) {
+            return format!("({})", id);
+        }
+        let id = self.ids.len() + 1;
+        self.ids.insert(name.to_owned(), id);
+        format!("({}) {}", id, name)
+    }
+}
+
+fn format_costs(costs: &[u64]) -> String {
+    costs
+        .iter()
+        .map(|cost| cost.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Writes the profile in the callgrind format. Every track becomes an event,
+/// and the Misses event counts the samples, i.e. the reads. A sample's costs
+/// are the self costs of the line of its innermost frame, and every frame
+/// of its stack calls the next one, so the callers get the inclusive costs
+/// at the line of the call. Like in callgrind's own output, the inclusive
+/// costs of recursive calls are counted once per call.
+pub fn write_callgrind_profile<W: Write>(writer: &mut W, profile_builder: &ProfileBuilder) -> Result<(), io::Error> {
+    let resolved_stacks = profile_builder.resolve_stacks();
+    let mut costs_per_function: BTreeMap<Function, FunctionCosts> = BTreeMap::new();
+    let mut total_costs = Vec::new();
+    for (sample, &stack) in profile_builder.samples().iter().zip(&resolved_stacks.sample_stacks) {
+        let mut costs = sample.weights.clone();
+        costs.push(sample.count);
+        add_costs(&mut total_costs, &costs);
+        let locations: Vec<(Function, usize)> = resolved_stacks
+            .frames_for_stack(stack)
+            .into_iter()
+            .map(|frame| frame_location(&resolved_stacks, frame))
+            .collect();
+        for (i, &(ref function, line)) in locations.iter().enumerate() {
+            let function_costs = costs_per_function.entry(function.clone()).or_default();
+            match locations.get(i + 1) {
+                Some(&(ref callee, callee_line)) => {
+                    let call = Call {
+                        caller_line: line,
+                        callee: callee.clone(),
+                        callee_line,
+                    };
+                    let &mut (ref mut call_count, ref mut call_costs) =
+                        function_costs.calls.entry(call).or_insert_with(|| (0, Vec::new()));
+                    *call_count += sample.count;
+                    add_costs(call_costs, &costs);
+                }
+                None => add_costs(function_costs.self_costs.entry(line).or_default(), &costs),
+            }
+        }
+    }
+
+    writeln!(writer, "# callgrind format")?;
+    writeln!(writer, "version: 1")?;
+    writeln!(writer, "creator: cache-log-parser")?;
+    writeln!(writer, "positions: line")?;
+    let mut event_names = Vec::new();
+    for track_name in profile_builder.track_names() {
+        let event_name = event_name_for_track(track_name);
+        writeln!(writer, "event: {} : {}", event_name, track_name)?;
+        event_names.push(event_name);
+    }
+    writeln!(writer, "event: Misses : LL cache misses")?;
+    event_names.push("Misses".to_owned());
+    writeln!(writer, "events: {}", event_names.join(" "))?;
+    if total_costs.is_empty() {
+        total_costs.resize(event_names.len(), 0);
+    }
+    writeln!(writer, "summary: {}", format_costs(&total_costs))?;
+
+    let mut objects = NameCompression::new();
+    let mut files = NameCompression::new();
+    let mut functions = NameCompression::new();
+    for (function, function_costs) in &costs_per_function {
+        writeln!(writer)?;
+        writeln!(writer, "ob={}", objects.compress(&function.object))?;
+        writeln!(writer, "fl={}", files.compress(&function.file))?;
+        writeln!(writer, "fn={}", functions.compress(&function.name))?;
+        for (line, costs) in &function_costs.self_costs {
+            writeln!(writer, "{} {}", line, format_costs(costs))?;
+        }
+        for (call, &(call_count, ref costs)) in &function_costs.calls {
+            if call.callee.object != function.object {
+                writeln!(writer, "cob={}", objects.compress(&call.callee.object))?;
+            }
+            writeln!(writer, "cfi={}", files.compress(&call.callee.file))?;
+            writeln!(writer, "cfn={}", functions.compress(&call.callee.name))?;
+            writeln!(writer, "calls={} {}", call_count, call.callee_line)?;
+            writeln!(writer, "{} {}", call.caller_line, format_costs(costs))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn save_callgrind_profile(profile_builder: &ProfileBuilder, path: &Path) -> Result<(), io::Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_callgrind_profile(&mut writer, profile_builder)?;
+    writer.flush()
+}
+
+#[test]
+fn test_event_name_for_track() {
+    assert_eq!(event_name_for_track("Read bytes"), "ReadBytes");
+    assert_eq!(event_name_for_track("wasted  bytes"), "WastedBytes");
+}
+
+#[test]
+fn test_callgrind_profile() {
+    // Stack 0 is the root, stack 1 is a child of it, and stack 2 a child of
+    // stack 1.
+    let stack_table = ::profile::test_stack_table(&[0, 0, 1]);
+    let mut profile_builder = ProfileBuilder::new(stack_table, 1.0, &["Read bytes", "Wasted bytes"]);
+    for (i, &(stack, wasted_bytes)) in [(2, 10), (2, 20), (1, 0), (0, 64)].iter().enumerate() {
+        profile_builder.add_sample(stack, i as f64, 0, &[64, wasted_bytes]);
+    }
+    let mut output = Vec::new();
+    write_callgrind_profile(&mut output, &profile_builder).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "# callgrind format\n\
+         version: 1\n\
+         creator: cache-log-parser\n\
+         positions: line\n\
+         event: ReadBytes : Read bytes\n\
+         event: WastedBytes : Wasted bytes\n\
+         event: Misses : LL cache misses\n\
+         events: ReadBytes WastedBytes Misses\n\
+         summary: 256 94 4\n\
+         \n\
+         ob=(1) ???\n\
+         fl=(1) ???\n\
+         fn=(1) 0x1020\n\
+         0 128 30 2\n\
+         \n\
+         ob=(2) libtest.so\n\
+         fl=(1)\n\
+         fn=(2) libtest.so+0x0\n\
+         0 64 64 1\n\
+         cfi=(1)\n\
+         cfn=(3) libtest.so+0x10\n\
+         calls=3 0\n\
+         0 192 30 3\n\
+         \n\
+         ob=(2)\n\
+         fl=(1)\n\
+         fn=(3)\n\
+         0 64 0 1\n\
+         cob=(1)\n\
+         cfi=(1)\n\
+         cfn=(1)\n\
+         calls=2 0\n\
+         0 128 30 2\n"
+    );
+}
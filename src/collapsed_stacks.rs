@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use profile::ProfileBuilder;
+
+/// Frames in the folded format are separated by semicolons, so they can't
+/// contain any.
+fn sanitize_frame_name(name: &str) -> String {
+    name.replace(';', ":")
+}
+
+/// Returns the folded stacks of each track of the profile, i.e. the total
+/// weight per stack, where a stack is its function names from the outermost
+/// to the innermost, joined by semicolons. Stacks whose weight is zero are
+/// left out.
+pub fn collapsed_stacks(profile_builder: &ProfileBuilder) -> Vec<BTreeMap<String, u64>> {
+    let resolved_stacks = profile_builder.resolve_stacks();
+    let mut weights_per_stack: BTreeMap<usize, Vec<u64>> = BTreeMap::new();
+    for (sample, &stack) in profile_builder.samples().iter().zip(&resolved_stacks.sample_stacks) {
+        let weights = weights_per_stack
+            .entry(stack)
+            .or_insert_with(|| vec![0; sample.weights.len()]);
+        for (weight, sample_weight) in weights.iter_mut().zip(&sample.weights) {
+            *weight += *sample_weight;
+        }
+    }
+    let mut stacks_per_track = vec![BTreeMap::new(); profile_builder.track_names().len()];
+    for (stack, weights) in weights_per_stack {
+        let folded_stack = resolved_stacks
+            .frames_for_stack(stack)
+            .into_iter()
+            .map(|frame| sanitize_frame_name(&resolved_stacks.frame_name(frame)))
+            .collect::<Vec<String>>()
+            .join(";");
+        for (stacks, weight) in stacks_per_track.iter_mut().zip(weights) {
+            if weight != 0 {
+                // Different stacks can have the same function names.
+                *stacks.entry(folded_stack.clone()).or_insert(0) += weight;
+            }
+        }
+    }
+    stacks_per_track
+}
+
+/// Writes one "frame;frame;frame <bytes>" line per stack.
+pub fn write_collapsed_stacks<W: Write>(writer: &mut W, stacks: &BTreeMap<String, u64>) -> Result<(), io::Error> {
+    for (stack, weight) in stacks {
+        writeln!(writer, "{} {}", stack, weight)?;
+    }
+    Ok(())
+}
+
+/// Saves the folded stacks of each track of the profile to the path with
+/// the same index.
+pub fn save_collapsed_stacks(profile_builder: &ProfileBuilder, paths: &[PathBuf]) -> Result<(), io::Error> {
+    assert_eq!(paths.len(), profile_builder.track_names().len(), "need one path per track");
+    for (stacks, path) in collapsed_stacks(profile_builder).iter().zip(paths) {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write_collapsed_stacks(&mut writer, stacks)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_collapsed_stacks() {
+    // Stack 0 is the root, stack 1 is a child of it, and stack 2 a child of
+    // stack 1.
+    let stack_table = ::profile::test_stack_table(&[0, 0, 1]);
+    let mut profile_builder = ProfileBuilder::new(stack_table, 1.0, &["Read bytes", "Wasted bytes"]);
+    for (i, &(stack, wasted_bytes)) in [(2, 10), (1, 0), (2, 20), (0, 64)].iter().enumerate() {
+        profile_builder.add_sample(stack, i as f64, 0, &[64, wasted_bytes]);
+    }
+    let stacks_per_track = collapsed_stacks(&profile_builder);
+    let mut output = Vec::new();
+    write_collapsed_stacks(&mut output, &stacks_per_track[0]).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "libtest.so+0x0 64\n\
+         libtest.so+0x0;libtest.so+0x10 64\n\
+         libtest.so+0x0;libtest.so+0x10;0x1020 128\n"
+    );
+    // Stack 1 didn't waste anything.
+    assert_eq!(stacks_per_track[1].len(), 2);
+    assert_eq!(stacks_per_track[1]["libtest.so+0x0;libtest.so+0x10;0x1020"], 30);
+    assert_eq!(stacks_per_track[1]["libtest.so+0x0"], 64);
+}
@@ -1,11 +1,67 @@
 use ranges::Ranges;
+use replacement_policy::{ReplacementPolicy, ReplacementPolicyKind};
+
+/// The geometry of a set-associative cache, e.g. 2 MB with 64 byte lines
+/// and 8 ways, like the `--LL` parameter of cachegrind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CacheConfig {
+    pub size: u32,
+    pub line_size: u32,
+    pub assoc: u32,
+}
+
+impl CacheConfig {
+    pub fn set_count(&self) -> u32 {
+        self.size / self.line_size / self.assoc
+    }
+
+    /// Checks the same things as cachegrind does for `--LL`: the line size
+    /// and the number of sets need to be powers of two.
+    pub fn check(&self) -> Result<(), String> {
+        if log2(self.line_size).is_none() {
+            return Err(format!("The line size of {} B isn't a power of two.", self.line_size));
+        }
+        if self.assoc == 0 || !self.size.is_multiple_of(self.line_size * self.assoc) {
+            return Err(format!(
+                "A {} B cache can't have {} ways of {} B lines.",
+                self.size, self.assoc, self.line_size
+            ));
+        }
+        if log2(self.set_count()).is_none() {
+            return Err(format!(
+                "A {} B cache with {} ways of {} B lines has {} sets, which isn't a power of two.",
+                self.size,
+                self.assoc,
+                self.line_size,
+                self.set_count()
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub enum CacheAccess {
+    Hit,
+    /// The cache line of the accessed address was read into the cache,
+    /// evicting the line that the replacement policy picked, if the set was
+    /// full.
+    Miss { evicted: Option<u64> },
+    /// The first access to a line that was prefetched.
+    PrefetchHit,
+}
 
 pub struct CPUCache {
-    line_size: u8,
+    line_size: u32,
     line_size_bits: u8,
     sets_min_1: u64,
     assoc: u64,
+    /// The tags of each way of each set. A tag of zero means that the way
+    /// is empty, as in cachegrind.
     tags: Vec<u64>,
+    /// Whether each way holds a prefetched line that hasn't been accessed
+    /// yet.
+    prefetched: Vec<bool>,
+    policy: Box<dyn ReplacementPolicy>,
 }
 
 /* Returns the base-2 logarithm of x.  Returns None if x is not a power
@@ -21,44 +77,170 @@ fn log2(x: u32) -> Option<u8> {
 }
 
 impl CPUCache {
-    pub fn new(size: u32, line_size: u8, assoc: u32) -> CPUCache {
-        let sets = ((size / line_size as u32) / assoc) as u64;
+    pub fn new(size: u32, line_size: u32, assoc: u32) -> CPUCache {
+        let config = CacheConfig { size, line_size, assoc };
+        CPUCache::with_policy(&config, ReplacementPolicyKind::Lru)
+    }
+
+    pub fn from_config(config: &CacheConfig) -> CPUCache {
+        CPUCache::with_policy(config, ReplacementPolicyKind::Lru)
+    }
+
+    pub fn with_policy(config: &CacheConfig, policy: ReplacementPolicyKind) -> CPUCache {
+        let CacheConfig { size, line_size, assoc } = *config;
+        let sets = ((size / line_size) / assoc) as u64;
         CPUCache {
             // size: size as u64,
             line_size: line_size,
-            line_size_bits: log2(line_size as u32).unwrap(),
+            line_size_bits: log2(line_size).unwrap(),
             // sets,
             sets_min_1: sets - 1,
             assoc: assoc as u64,
-            tags: vec![0; (size / line_size as u32) as usize],
+            tags: vec![0; (size / line_size) as usize],
+            prefetched: vec![false; (size / line_size) as usize],
+            policy: policy.create(sets as usize, assoc as usize),
+        }
+    }
+
+    fn set_mut(&mut self, set_no: u64) -> &mut [u64] {
+        let tag_index_start = (set_no * self.assoc) as usize;
+        let tag_index_end = ((set_no + 1) * self.assoc) as usize;
+        &mut self.tags[tag_index_start..tag_index_end]
+    }
+
+    /// The set number and the tag of the cache line that contains addr.
+    fn set_and_tag(&self, addr: u64) -> (u64, u64) {
+        let tag = addr >> self.line_size_bits;
+        (tag & self.sets_min_1, tag)
+    }
+
+    /// Simulates an access to the cache line that contains addr. Empty ways
+    /// are filled first, like in cachegrind's cg_sim.c, and then the
+    /// replacement policy picks the lines to evict.
+    pub fn access(&mut self, addr: u64) -> CacheAccess {
+        let (set_no, tag) = self.set_and_tag(addr);
+        let tag_index_start = (set_no * self.assoc) as usize;
+        match self.set_mut(set_no).iter().position(|&t| t == tag) {
+            Some(way) => {
+                self.policy.on_hit(set_no as usize, way);
+                if self.prefetched[tag_index_start + way] {
+                    self.prefetched[tag_index_start + way] = false;
+                    CacheAccess::PrefetchHit
+                } else {
+                    CacheAccess::Hit
+                }
+            }
+            None => CacheAccess::Miss { evicted: self.fill(addr) },
+        }
+    }
+
+    /// Reads the cache line that contains addr into the cache without
+    /// accessing it, and tags it as prefetched until it's accessed. Lines
+    /// that are already cached are left alone, and count as hits.
+    pub fn prefetch(&mut self, addr: u64) -> CacheAccess {
+        let (set_no, tag) = self.set_and_tag(addr);
+        if tag == 0 || self.set_mut(set_no).contains(&tag) {
+            return CacheAccess::Hit;
+        }
+        let (way, evicted) = self.fill_way(addr);
+        self.prefetched[(set_no * self.assoc) as usize + way] = true;
+        CacheAccess::Miss { evicted }
+    }
+
+    /// Whether the cache line that contains addr is cached, and if it is,
+    /// tells the replacement policy about the hit.
+    pub fn lookup(&mut self, addr: u64) -> bool {
+        let (set_no, tag) = self.set_and_tag(addr);
+        match self.set_mut(set_no).iter().position(|&t| t == tag) {
+            Some(way) => {
+                self.policy.on_hit(set_no as usize, way);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Puts the cache line that contains addr, which mustn't be cached yet,
+    /// into the cache, and returns the address of the line it evicted.
+    pub fn fill(&mut self, addr: u64) -> Option<u64> {
+        self.fill_way(addr).1
+    }
+
+    /// Fills the line like fill, and returns the way it went into.
+    fn fill_way(&mut self, addr: u64) -> (usize, Option<u64>) {
+        let (set_no, tag) = self.set_and_tag(addr);
+        let line_size_bits = self.line_size_bits;
+        let tag_index_start = (set_no * self.assoc) as usize;
+        let set = &mut self.tags[tag_index_start..tag_index_start + self.assoc as usize];
+        let (way, evicted) = match set.iter().position(|&t| t == 0) {
+            Some(way) => (way, None),
+            None => {
+                let way = self.policy.victim(set_no as usize);
+                (way, Some(set[way] << line_size_bits))
+            }
+        };
+        set[way] = tag;
+        self.prefetched[tag_index_start + way] = false;
+        self.policy.on_fill(set_no as usize, way);
+        (way, evicted)
+    }
+
+    /// Removes the cache line that contains addr from the cache, e.g.
+    /// because an outer cache level of an inclusive hierarchy evicted it.
+    /// Returns whether it was cached.
+    pub fn invalidate(&mut self, addr: u64) -> bool {
+        let (set_no, tag) = self.set_and_tag(addr);
+        match self.set_mut(set_no).iter().position(|&t| t == tag) {
+            Some(way) => {
+                let index = (set_no * self.assoc) as usize + way;
+                self.tags[index] = 0;
+                self.prefetched[index] = false;
+                true
+            }
+            None => false,
         }
     }
 
-    pub fn exchange(&mut self, new_addr: u64, old_addr: u64) {
+    /// Replays a swap that was decided by the recorded simulation. Leaves
+    /// the cache unchanged if the swap doesn't fit its contents.
+    pub fn exchange(&mut self, new_addr: u64, old_addr: u64) -> Result<(), String> {
         let old_tag = old_addr >> self.line_size_bits;
         let new_tag = new_addr >> self.line_size_bits;
         let old_set_no = old_tag & self.sets_min_1;
         let new_set_no = new_tag & self.sets_min_1;
         if old_tag != 0 && old_set_no != new_set_no {
-            panic!(
+            return Err(format!(
                 "Expected to only exchange cache lines inside the same set! old_addr={:x} new_addr={:x} old_set_no={} new_set_no={}",
                 old_addr,
                 new_addr,
                 old_set_no,
                 new_set_no
-            );
+            ));
         }
         let set_no = new_set_no;
-        let tag_index_start = (set_no * self.assoc) as usize;
-        let tag_index_end = ((set_no + 1) * self.assoc) as usize;
-        let set = &mut self.tags[tag_index_start..tag_index_end];
-        for tag in set.iter_mut() {
-            if *tag == old_tag {
-                *tag = new_tag;
-                return;
-            }
+        if let Some(way) = self.set_mut(set_no).iter().position(|&t| t == old_tag) {
+            let index = (set_no * self.assoc) as usize + way;
+            self.tags[index] = new_tag;
+            self.prefetched[index] = false;
+            return Ok(());
         }
-        panic!("Couldn't find tag {:x} in set {}", old_tag, set_no);
+        Err(format!("Couldn't find tag {:x} in set {}", old_tag, set_no))
+    }
+
+    pub fn set_count(&self) -> u64 {
+        self.sets_min_1 + 1
+    }
+
+    /// The addresses of the cache lines in the given set, sorted.
+    pub fn lines_in_set(&self, set_no: u64) -> Vec<u64> {
+        let tag_index_start = (set_no * self.assoc) as usize;
+        let mut lines: Vec<u64> = self.tags[tag_index_start..tag_index_start + self.assoc as usize]
+            .iter()
+            .filter(|&&tag| tag != 0)
+            .map(|tag| tag << self.line_size_bits)
+            .collect();
+        lines.sort();
+        lines
     }
 
     pub fn get_cached_ranges(&self) -> Vec<(u64, u64)> {
@@ -72,3 +254,38 @@ impl CPUCache {
         ranges.get()
     }
 }
+
+#[test]
+fn test_cpucache_lru() {
+    let config = CacheConfig { size: 256, line_size: 64, assoc: 2 };
+    assert_eq!(config.check(), Ok(()));
+    assert!(CacheConfig { size: 192, line_size: 64, assoc: 1 }.check().is_err());
+    assert!(CacheConfig { size: 256, line_size: 48, assoc: 1 }.check().is_err());
+
+    // Two sets of two ways; 0x1000, 0x1080 and 0x1100 all map to set 0.
+    let mut cache = CPUCache::from_config(&config);
+    let is_miss = |access| match access {
+        CacheAccess::Hit | CacheAccess::PrefetchHit => None,
+        CacheAccess::Miss { evicted } => Some(evicted),
+    };
+    assert_eq!(is_miss(cache.access(0x1000)), Some(None));
+    assert_eq!(is_miss(cache.access(0x1080)), Some(None));
+    assert_eq!(is_miss(cache.access(0x1040)), Some(None));
+    assert_eq!(is_miss(cache.access(0x1010)), None);
+    // 0x1080 is now the least recently used line of set 0.
+    assert_eq!(is_miss(cache.access(0x1100)), Some(Some(0x1080)));
+    assert_eq!(is_miss(cache.access(0x1000)), None);
+    assert_eq!(cache.get_cached_ranges(), vec![(0x1000, 0x1080), (0x1100, 0x1140)]);
+    assert!(cache.invalidate(0x1100));
+    assert!(!cache.invalidate(0x1100));
+    assert!(!cache.lookup(0x1100));
+    // The invalidated way is filled before anything is evicted.
+    assert_eq!(cache.fill(0x1180), None);
+
+    // Prefetched lines are only tagged until they're accessed.
+    assert!(cache.invalidate(0x1040));
+    assert!(matches!(cache.prefetch(0x1040), CacheAccess::Miss { evicted: None }));
+    assert!(matches!(cache.prefetch(0x1040), CacheAccess::Hit));
+    assert!(matches!(cache.access(0x1050), CacheAccess::PrefetchHit));
+    assert!(matches!(cache.access(0x1050), CacheAccess::Hit));
+}
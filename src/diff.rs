@@ -0,0 +1,476 @@
+use std::collections::BTreeMap;
+use std::iter;
+use pretty_bytes::converter::convert;
+use cache_log_info::{wastage_source_report, DoubleReadsCollector, DoubleReadsReport, LineRangeSummarizer, ProcessState,
+                     ReadsCollector, SectionSelection, SectionSummary, WastageSourceReport};
+use log_events::consume_log;
+
+/// Which part of a log to compare.
+#[derive(Debug, PartialEq)]
+pub enum DiffRange {
+    /// A line range, start inclusive, end exclusive.
+    Lines(usize, usize),
+    Section(SectionSelection),
+}
+
+/// One side of a diff, given on the command line as <LOG>:<PID>:<SECTION>.
+#[derive(Debug, PartialEq)]
+pub struct DiffSpec {
+    pub spec: String,
+    pub log: String,
+    pub pid: i32,
+    pub range: DiffRange,
+}
+
+impl DiffSpec {
+    /// Parses <LOG>:<PID>:<SECTION>, where SECTION is <name>#<n>, "largest"
+    /// or <start>-<end>. The PID is the first all-digit component after the
+    /// log, so the log path may contain colons as long as none of its
+    /// components are plain numbers.
+    pub fn parse(s: &str) -> Option<DiffSpec> {
+        let components: Vec<&str> = s.split(':').collect();
+        let pid_pos = (1..components.len().saturating_sub(1))
+            .find(|&i| !components[i].is_empty() && components[i].bytes().all(|b| b.is_ascii_digit()))?;
+        let log = components[..pid_pos].join(":");
+        let pid = components[pid_pos].parse().ok()?;
+        let section = components[pid_pos + 1..].join(":");
+        let range = if section == "largest" {
+            DiffRange::Section(SectionSelection::Largest)
+        } else if let Some(range) = parse_line_range(&section) {
+            range
+        } else {
+            DiffRange::Section(SectionSelection::parse_numbered(&section)?)
+        };
+        Some(DiffSpec {
+            spec: s.to_owned(),
+            log,
+            pid,
+            range,
+        })
+    }
+}
+
+fn parse_line_range(s: &str) -> Option<DiffRange> {
+    let pos = s.find('-')?;
+    let start = s[..pos].parse().ok()?;
+    let end = s[pos + 1..].parse().ok()?;
+    Some(DiffRange::Lines(start, end))
+}
+
+#[derive(Serialize)]
+pub struct DiffInput {
+    spec: String,
+    /// The section label, e.g. "DisplayList building#3", if the range was
+    /// picked by section.
+    label: Option<String>,
+    start_line_index: usize,
+    /// None if the section never ended.
+    end_line_index: Option<usize>,
+}
+
+/// The numbers of one side of a diff.
+pub struct Measurement {
+    input: DiffInput,
+    bytes_read: u64,
+    unique_bytes_read: u64,
+    bytes_used: u64,
+    multi_read_overhead_percent: f64,
+    cache_line_overhead_percent: f64,
+    bytes_read_per_arena_kind: BTreeMap<String, u64>,
+    wasted_bytes_per_function: BTreeMap<String, u64>,
+}
+
+impl Measurement {
+    pub fn new(
+        spec: &DiffSpec,
+        label: Option<String>,
+        start_line_index: usize,
+        end_line_index: usize,
+        summary: &SectionSummary,
+        double_reads: &DoubleReadsReport,
+        wastage: &WastageSourceReport,
+    ) -> Measurement {
+        Measurement {
+            input: DiffInput {
+                spec: spec.spec.clone(),
+                label,
+                start_line_index,
+                end_line_index: if end_line_index == usize::MAX { None } else { Some(end_line_index) },
+            },
+            bytes_read: summary.bytes_read,
+            unique_bytes_read: summary.unique_bytes_read,
+            bytes_used: summary.bytes_used,
+            multi_read_overhead_percent: summary.multi_read_overhead_percent,
+            cache_line_overhead_percent: summary.cache_line_overhead_percent,
+            bytes_read_per_arena_kind: double_reads.bytes_read_per_arena_kind(),
+            wasted_bytes_per_function: wastage.wasted_bytes_per_function(),
+        }
+    }
+
+    /// Measures the line range in a single pass over the log. iter and state
+    /// need to start at or before start_line_index.
+    pub fn measure<T>(
+        spec: &DiffSpec,
+        label: Option<String>,
+        iter: T,
+        state: ProcessState,
+        start_line_index: usize,
+        end_line_index: usize,
+    ) -> Measurement
+    where
+        T: iter::Iterator<Item = (usize, String)>,
+    {
+        let ProcessState {
+            mut stack_info,
+            arena_info,
+        } = state;
+        let mut summarizer = LineRangeSummarizer::new(start_line_index, end_line_index);
+        let mut double_reads = DoubleReadsCollector::new(arena_info, start_line_index);
+        let mut reads_info = ReadsCollector::for_line_range(start_line_index, end_line_index);
+        consume_log(
+            spec.pid,
+            iter,
+            end_line_index,
+            &mut [&mut stack_info, &mut summarizer, &mut double_reads, &mut reads_info],
+        );
+        let mut stack_table = stack_info.get_stack_table();
+        let double_reads = double_reads.into_report(&mut stack_table);
+        let wastage = wastage_source_report(reads_info.into_reads(), &mut stack_table);
+        Measurement::new(
+            spec,
+            label,
+            start_line_index,
+            end_line_index,
+            &summarizer.into_summary(),
+            &double_reads,
+            &wastage,
+        )
+    }
+
+    fn wasted_bytes(&self) -> u64 {
+        self.bytes_read.saturating_sub(self.bytes_used)
+    }
+}
+
+#[derive(Serialize)]
+pub struct ByteDelta {
+    before: u64,
+    after: u64,
+    change: i64,
+    /// None if there were no bytes before.
+    change_percent: Option<f64>,
+}
+
+impl ByteDelta {
+    fn new(before: u64, after: u64) -> ByteDelta {
+        ByteDelta {
+            before,
+            after,
+            change: after as i64 - before as i64,
+            change_percent: if before == 0 {
+                None
+            } else {
+                Some((after as f64 / before as f64 - 1.0) * 100.0)
+            },
+        }
+    }
+
+    /// Whether the value grew by more than threshold_percent percent. Growing
+    /// from zero counts as exceeding any threshold.
+    fn exceeds(&self, threshold_percent: f64) -> bool {
+        match self.change_percent {
+            Some(change_percent) => change_percent > threshold_percent,
+            None => self.after > 0,
+        }
+    }
+
+    fn print(&self, name: &str) {
+        let change_percent = match self.change_percent {
+            Some(change_percent) => format!(" ({:+.1}%)", change_percent),
+            None => "".to_owned(),
+        };
+        println!(
+            "  {:<22}{:>12} -> {:>12}  {:+}{}",
+            name,
+            self.before,
+            self.after,
+            self.change,
+            change_percent
+        );
+    }
+}
+
+/// The overheads are percentages already, so their change is given in
+/// percentage points.
+#[derive(Serialize)]
+pub struct PercentDelta {
+    before: f64,
+    after: f64,
+    change: f64,
+}
+
+impl PercentDelta {
+    fn new(before: f64, after: f64) -> PercentDelta {
+        PercentDelta {
+            before,
+            after,
+            change: after - before,
+        }
+    }
+
+    /// Whether the overhead grew by more than threshold_points percentage
+    /// points. An overhead that isn't a finite number counts as exceeding
+    /// any threshold.
+    fn exceeds(&self, threshold_points: f64) -> bool {
+        !self.after.is_finite() || self.change > threshold_points
+    }
+
+    fn print(&self, name: &str) {
+        println!(
+            "  {:<22}{:>11.1}% -> {:>11.1}%  {:+.1} points",
+            name,
+            self.before,
+            self.after,
+            self.change
+        );
+    }
+}
+
+#[derive(Serialize)]
+pub struct NamedDelta {
+    name: String,
+    before: u64,
+    after: u64,
+    change: i64,
+}
+
+/// Pairs up the entries of both maps and sorts them by the size of the
+/// change, biggest first. Entries that didn't change are dropped.
+fn named_deltas(before: &BTreeMap<String, u64>, after: &BTreeMap<String, u64>) -> Vec<NamedDelta> {
+    let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+    names.sort();
+    names.dedup();
+    let mut deltas: Vec<NamedDelta> = names
+        .into_iter()
+        .map(|name| {
+            let before = before.get(name).cloned().unwrap_or(0);
+            let after = after.get(name).cloned().unwrap_or(0);
+            NamedDelta {
+                name: name.clone(),
+                before,
+                after,
+                change: after as i64 - before as i64,
+            }
+        })
+        .filter(|delta| delta.change != 0)
+        .collect();
+    // The sort is stable, so equal changes stay sorted by name.
+    deltas.sort_by_key(|delta| -delta.change.abs());
+    deltas
+}
+
+const DIFF_TOP_ENTRY_COUNT: usize = 25;
+
+/// The result of the diff subcommand.
+#[derive(Serialize)]
+pub struct DiffReport {
+    before: DiffInput,
+    after: DiffInput,
+    bytes_read: ByteDelta,
+    unique_bytes_read: ByteDelta,
+    bytes_used: ByteDelta,
+    wasted_bytes: ByteDelta,
+    multi_read_overhead_percent: PercentDelta,
+    cache_line_overhead_percent: PercentDelta,
+    /// Bytes read per arena kind, sorted by the size of the change.
+    arenas: Vec<NamedDelta>,
+    /// Wasted bytes per function, sorted by the size of the change.
+    functions: Vec<NamedDelta>,
+    threshold_percent: Option<f64>,
+    /// Why the diff exceeded the threshold, if it did.
+    regressions: Vec<String>,
+}
+
+impl DiffReport {
+    /// The threshold applies to the bytes read and the wasted bytes, in
+    /// percent, and to the multi-read and cache-line overheads, in
+    /// percentage points.
+    pub fn new(before: Measurement, after: Measurement, threshold_percent: Option<f64>) -> DiffReport {
+        let bytes_read = ByteDelta::new(before.bytes_read, after.bytes_read);
+        let wasted_bytes = ByteDelta::new(before.wasted_bytes(), after.wasted_bytes());
+        let multi_read_overhead_percent =
+            PercentDelta::new(before.multi_read_overhead_percent, after.multi_read_overhead_percent);
+        let cache_line_overhead_percent =
+            PercentDelta::new(before.cache_line_overhead_percent, after.cache_line_overhead_percent);
+        let mut regressions = Vec::new();
+        if let Some(threshold_percent) = threshold_percent {
+            for &(name, delta) in &[("bytes read", &bytes_read), ("wasted bytes", &wasted_bytes)] {
+                if delta.exceeds(threshold_percent) {
+                    regressions.push(format!(
+                        "{} grew from {} to {}, more than the threshold of {}%",
+                        name,
+                        delta.before,
+                        delta.after,
+                        threshold_percent
+                    ));
+                }
+            }
+            for &(name, delta) in &[
+                ("multi-read overhead", &multi_read_overhead_percent),
+                ("cache-line overhead", &cache_line_overhead_percent),
+            ] {
+                if delta.exceeds(threshold_percent) {
+                    regressions.push(format!(
+                        "{} grew from {:.1}% to {:.1}%, more than the threshold of {} points",
+                        name,
+                        delta.before,
+                        delta.after,
+                        threshold_percent
+                    ));
+                }
+            }
+        }
+        DiffReport {
+            bytes_read,
+            unique_bytes_read: ByteDelta::new(before.unique_bytes_read, after.unique_bytes_read),
+            bytes_used: ByteDelta::new(before.bytes_used, after.bytes_used),
+            wasted_bytes,
+            multi_read_overhead_percent,
+            cache_line_overhead_percent,
+            arenas: named_deltas(&before.bytes_read_per_arena_kind, &after.bytes_read_per_arena_kind),
+            functions: named_deltas(&before.wasted_bytes_per_function, &after.wasted_bytes_per_function),
+            threshold_percent,
+            regressions,
+            before: before.input,
+            after: after.input,
+        }
+    }
+
+    pub fn has_regressions(&self) -> bool {
+        !self.regressions.is_empty()
+    }
+
+    pub fn print(&self) {
+        for &(name, input) in &[("Before", &self.before), ("After", &self.after)] {
+            let range = match input.end_line_index {
+                Some(end_line_index) => format!("lines {} to {}", input.start_line_index, end_line_index),
+                None => format!("line {} to the end of the log", input.start_line_index),
+            };
+            match input.label {
+                Some(ref label) => println!("{}: {} ({}, {})", name, input.spec, label, range),
+                None => println!("{}: {} ({})", name, input.spec, range),
+            }
+        }
+        println!();
+        self.bytes_read.print("bytes read");
+        self.unique_bytes_read.print("unique bytes read");
+        self.bytes_used.print("bytes used");
+        self.wasted_bytes.print("wasted bytes");
+        self.multi_read_overhead_percent.print("multi-read overhead");
+        self.cache_line_overhead_percent.print("cache-line overhead");
+        println!();
+
+        for &(title, deltas) in &[
+            ("Change in bytes read per arena", &self.arenas),
+            ("Change in wasted bytes per function", &self.functions),
+        ] {
+            if deltas.is_empty() {
+                println!("{}: none.", title);
+            } else {
+                println!("{}, top {} changes:", title, DIFF_TOP_ENTRY_COUNT);
+                for delta in deltas.iter().take(DIFF_TOP_ENTRY_COUNT) {
+                    println!(
+                        "  {:>+12} ({} -> {}) {}",
+                        delta.change,
+                        convert(delta.before as f64),
+                        convert(delta.after as f64),
+                        delta.name
+                    );
+                }
+            }
+            println!();
+        }
+
+        for regression in &self.regressions {
+            println!("Regression: {}.", regression);
+        }
+    }
+}
+
+#[test]
+fn test_parse_diff_spec() {
+    let spec = DiffSpec::parse("before.log:8884:DisplayList building#3").unwrap();
+    assert_eq!(spec.log, "before.log");
+    assert_eq!(spec.pid, 8884);
+    assert_eq!(
+        spec.range,
+        DiffRange::Section(SectionSelection::Numbered("DisplayList building".to_owned(), 3))
+    );
+
+    let spec = DiffSpec::parse("C:/logs/after.log.zst:8884:largest").unwrap();
+    assert_eq!(spec.log, "C:/logs/after.log.zst");
+    assert_eq!(spec.range, DiffRange::Section(SectionSelection::Largest));
+
+    let spec = DiffSpec::parse("after.log:12:100-200").unwrap();
+    assert_eq!(spec.pid, 12);
+    assert_eq!(spec.range, DiffRange::Lines(100, 200));
+
+    assert_eq!(DiffSpec::parse("after.log:12"), None);
+    assert_eq!(DiffSpec::parse("after.log:12:Paint"), None);
+    assert_eq!(DiffSpec::parse("after.log:DisplayList building#3"), None);
+}
+
+#[test]
+fn test_named_deltas() {
+    let before: BTreeMap<String, u64> = vec![("a".to_owned(), 100), ("b".to_owned(), 50), ("c".to_owned(), 10)]
+        .into_iter()
+        .collect();
+    let after: BTreeMap<String, u64> = vec![("a".to_owned(), 100), ("b".to_owned(), 20), ("d".to_owned(), 40)]
+        .into_iter()
+        .collect();
+    let deltas: Vec<(String, i64)> = named_deltas(&before, &after)
+        .into_iter()
+        .map(|delta| (delta.name, delta.change))
+        .collect();
+    assert_eq!(
+        deltas,
+        vec![("d".to_owned(), 40), ("b".to_owned(), -30), ("c".to_owned(), -10)]
+    );
+}
+
+#[test]
+fn test_diff_report_threshold() {
+    let measurement = |bytes_read, bytes_used, multi_read_overhead_percent, cache_line_overhead_percent| Measurement {
+        input: DiffInput {
+            spec: "".to_owned(),
+            label: None,
+            start_line_index: 0,
+            end_line_index: None,
+        },
+        bytes_read,
+        unique_bytes_read: bytes_read,
+        bytes_used,
+        multi_read_overhead_percent,
+        cache_line_overhead_percent,
+        bytes_read_per_arena_kind: BTreeMap::new(),
+        wasted_bytes_per_function: BTreeMap::new(),
+    };
+    let report = DiffReport::new(measurement(1000, 500, 1.0, 50.0), measurement(1010, 505, 1.5, 51.0), Some(2.0));
+    assert!(!report.has_regressions());
+    // The overheads are compared in percentage points, even when the bytes
+    // stay the same.
+    let report = DiffReport::new(measurement(1000, 500, 1.0, 50.0), measurement(1000, 500, 3.5, 51.0), Some(2.0));
+    assert_eq!(report.regressions.len(), 1);
+    assert!(report.regressions[0].starts_with("multi-read overhead"));
+    let report = DiffReport::new(measurement(1000, 500, 1.0, 50.0), measurement(1030, 500, 1.0, 53.0), Some(2.0));
+    assert_eq!(report.regressions.len(), 3);
+    // An empty side has no overheads, and reading something after nothing
+    // exceeds any threshold.
+    let empty = LineRangeSummarizer::new(0, 0).into_summary();
+    assert_eq!(empty.multi_read_overhead_percent, 0.0);
+    assert_eq!(empty.cache_line_overhead_percent, 0.0);
+    let report = DiffReport::new(measurement(0, 0, 0.0, 0.0), measurement(1000, 0, 0.0, 0.0), Some(2.0));
+    assert_eq!(report.regressions.len(), 2);
+    let report = DiffReport::new(measurement(1000, 500, 1.0, 50.0), measurement(1000, 500, 1.0, f64::NAN), Some(2.0));
+    assert_eq!(report.regressions.len(), 1);
+}
@@ -0,0 +1,58 @@
+//! The analyses behind the cache-log-parser command line tool, for logs of
+//! the instrumented cachegrind and Gecko. Custom analyses can implement
+//! LogEventConsumer and run in the same pass over the log as the collectors
+//! that build up the stack table, the arenas and the reads; see consume_log.
+
+#![feature(test)]
+extern crate test;
+extern crate regex;
+extern crate hyper;
+extern crate flate2;
+extern crate rayon;
+#[macro_use]
+extern crate nom;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+extern crate itertools;
+extern crate pretty_bytes;
+extern crate fixed_circular_buffer;
+extern crate xz2;
+extern crate zstd;
+extern crate addr2line;
+
+pub mod cache_log_parsing;
+pub mod shared_libraries;
+pub mod addr2line_cmd;
+pub mod symbolication;
+pub mod symbol_cache;
+pub mod ranges;
+pub mod replacement_policy;
+pub mod prefetcher;
+pub mod cpucache;
+pub mod cache_simulation;
+pub mod cache_hierarchy;
+pub mod cache_snapshot;
+pub mod stack_table;
+pub mod log_events;
+pub mod cache_log_info;
+pub mod arenas;
+pub mod profile;
+mod collapsed_stacks;
+mod callgrind;
+mod protobuf;
+mod pprof;
+pub mod server;
+pub mod log_reader;
+pub mod log_index;
+pub mod diff;
+
+pub use cache_log_parsing::{parse_line_of_pid, LineContent};
+pub use stack_table::StackTable;
+pub use arenas::Arenas;
+pub use ranges::Ranges;
+pub use cpucache::{CacheConfig, CPUCache};
+pub use log_events::{consume_log, LogEventConsumer};
+pub use cache_log_info::{AccessKind, ArenaInfoCollector, CacheLineRead, ProcessState, ReadsCollector, StackInfoCollector};
@@ -0,0 +1,78 @@
+use std::iter;
+use cache_log_parsing::{parse_line_of_pid, LineContent};
+
+/// Something that builds up information from the log lines of one process,
+/// like the stack table or the reads in a line range. Consumers see every
+/// line of the process in order, so several of them can share one pass over
+/// the log; see consume_log.
+pub trait LogEventConsumer {
+    fn process_line(&mut self, line_index: usize, line_contents: &LineContent);
+
+    /// Whether the consumer needs lines after the end of the range that's
+    /// being analyzed, e.g. to find out how many bytes of the cache lines
+    /// that were read in the range were used before they were evicted.
+    fn needs_more_lines(&self) -> bool {
+        false
+    }
+}
+
+/// Passes the lines of the process with the given pid to all consumers, in
+/// one pass over the log. Lines before to_line go to every consumer, later
+/// lines only to the consumers that still need more lines, until none of
+/// them does.
+pub fn consume_log<T>(pid: i32, iter: T, to_line: usize, consumers: &mut [&mut dyn LogEventConsumer])
+where
+    T: iter::Iterator<Item = (usize, String)>,
+{
+    for (line_index, line) in iter {
+        let is_after_range = line_index >= to_line;
+        if is_after_range && !consumers.iter().any(|consumer| consumer.needs_more_lines()) {
+            break;
+        }
+        if let Some((p, line_contents)) = parse_line_of_pid(&line) {
+            if p != pid {
+                continue;
+            }
+            for consumer in consumers.iter_mut() {
+                if !is_after_range || consumer.needs_more_lines() {
+                    consumer.process_line(line_index, &line_contents);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_consume_log() {
+    /// Counts the lines it gets, and wants the lines up to until_line.
+    struct LineCounter {
+        line_count: usize,
+        until_line: usize,
+        last_line_index: usize,
+    }
+
+    impl LogEventConsumer for LineCounter {
+        fn process_line(&mut self, line_index: usize, _line_contents: &LineContent) {
+            self.line_count += 1;
+            self.last_line_index = line_index;
+        }
+
+        fn needs_more_lines(&self) -> bool {
+            self.last_line_index + 1 < self.until_line
+        }
+    }
+
+    let lines: Vec<(usize, String)> = (0..10)
+        .map(|line_index| {
+            let pid = if line_index % 2 == 0 { 100 } else { 200 };
+            (line_index, format!("=={}== add_frame: {} 1000", pid, line_index))
+        })
+        .collect();
+    let mut short = LineCounter { line_count: 0, until_line: 0, last_line_index: 0 };
+    let mut long = LineCounter { line_count: 0, until_line: 7, last_line_index: 0 };
+    consume_log(100, lines.into_iter(), 4, &mut [&mut short, &mut long]);
+    // Lines 0 and 2 are before the end of the range, and only the long one
+    // wanted to see lines 4 and 6 too.
+    assert_eq!((short.line_count, short.last_line_index), (2, 2));
+    assert_eq!((long.line_count, long.last_line_index), (4, 6));
+}
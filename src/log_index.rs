@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter};
+use std::str;
+use serde_json;
+use cache_log_parsing::{parse_line_of_pid, LineContent};
+use cache_log_info::{ArenaInfoCollector, ProcessState, StackInfoCollector};
+use log_events::LogEventConsumer;
+use log_reader::open_log;
+use stack_table::StackTable;
+use arenas::Arenas;
+
+const INDEX_FORMAT_VERSION: u32 = 3;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SectionBoundaries {
+    pub name: String,
+    pub tid: Option<u32>,
+    pub start_line_index: usize,
+    pub end_line_index: Option<usize>,
+}
+
+/// What a process looked like at a checkpoint. The stack table and the shared
+/// library information only ever grow, so their snapshots are stored as the
+/// lengths they had at the checkpoint, relative to the final versions in
+/// ProcessIndex. Arenas can shrink, so they're stored in full.
+#[derive(Serialize, Deserialize)]
+pub struct ProcessSnapshot {
+    pub pid: i32,
+    pub frame_count: usize,
+    pub stack_count: usize,
+    pub shared_libs_json_len: usize,
+    pub arenas: Arenas,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The first line that was not processed yet when this snapshot was taken.
+    pub line_index: usize,
+    /// The offset of that line in the (decompressed) log.
+    pub byte_offset: u64,
+    pub processes: Vec<ProcessSnapshot>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProcessIndex {
+    pub pid: i32,
+    pub line_count: usize,
+    pub sections: Vec<SectionBoundaries>,
+    pub stack_table: StackTable,
+    pub shared_libs_json: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LogIndex {
+    pub format_version: u32,
+    pub input_size: u64,
+    pub line_count: usize,
+    pub checkpoint_interval: usize,
+    /// In the order in which the processes first appear in the log.
+    pub processes: Vec<ProcessIndex>,
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+struct ProcessIndexBuilder {
+    pid: i32,
+    line_count: usize,
+    sections: Vec<SectionBoundaries>,
+    state: ProcessState,
+}
+
+impl ProcessIndexBuilder {
+    pub fn new(pid: i32) -> ProcessIndexBuilder {
+        ProcessIndexBuilder {
+            pid,
+            line_count: 0,
+            sections: Vec::new(),
+            state: ProcessState::new(),
+        }
+    }
+
+    pub fn process_line(&mut self, line_index: usize, line_contents: &LineContent) {
+        self.line_count += 1;
+        self.state.stack_info.process_line(line_index, line_contents);
+        self.state.arena_info.process_line(line_index, line_contents);
+        match *line_contents {
+            LineContent::BeginSection { name, tid } => {
+                self.sections.push(SectionBoundaries {
+                    name: name.to_owned(),
+                    tid,
+                    start_line_index: line_index,
+                    end_line_index: None,
+                });
+            }
+            LineContent::EndSection { name, tid } => {
+                if let Some(section) = self.sections.iter_mut().rev().find(|section| {
+                    section.name == name && section.tid == tid && section.end_line_index.is_none()
+                })
+                {
+                    section.end_line_index = Some(line_index);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn snapshot(&self) -> ProcessSnapshot {
+        let stack_info = &self.state.stack_info;
+        ProcessSnapshot {
+            pid: self.pid,
+            frame_count: stack_info.stack_table.frames.len(),
+            stack_count: stack_info.stack_table.stacks.len(),
+            shared_libs_json_len: stack_info.shared_libs_json_string.len(),
+            arenas: self.state.arena_info.arenas().clone(),
+        }
+    }
+
+    pub fn into_process_index(self) -> ProcessIndex {
+        let StackInfoCollector {
+            stack_table,
+            shared_libs_json_string,
+        } = self.state.stack_info;
+        ProcessIndex {
+            pid: self.pid,
+            line_count: self.line_count,
+            sections: self.sections,
+            stack_table,
+            shared_libs_json: shared_libs_json_string,
+        }
+    }
+}
+
+pub fn index_path_for_input(filename: &str) -> String {
+    format!("{}.index", filename)
+}
+
+/// Parses the whole log once and records a checkpoint every checkpoint_interval
+/// lines, from which later analyses can resume.
+pub fn build_index(filename: &str, checkpoint_interval: usize) -> Result<LogIndex, io::Error> {
+    assert!(checkpoint_interval > 0, "checkpoint interval must not be zero");
+    let input_size = fs::metadata(filename)?.len();
+    let mut reader = open_log(filename)?;
+    let mut processes: Vec<ProcessIndexBuilder> = Vec::new();
+    let mut process_index_for_pid: HashMap<i32, usize> = HashMap::new();
+    let mut checkpoints = Vec::new();
+    let mut line = Vec::new();
+    let mut byte_offset = 0u64;
+    let mut line_index = 0;
+    loop {
+        if line_index % checkpoint_interval == 0 {
+            checkpoints.push(Checkpoint {
+                line_index,
+                byte_offset,
+                processes: processes.iter().map(|p| p.snapshot()).collect(),
+            });
+        }
+        line.clear();
+        let line_len = reader.read_until(b'\n', &mut line)?;
+        if line_len == 0 {
+            break;
+        }
+        byte_offset += line_len as u64;
+        if let Ok(line) = str::from_utf8(&line) {
+            let line = line.trim_end_matches(&['\n', '\r'][..]);
+            if let Some((pid, line_contents)) = parse_line_of_pid(line) {
+                let process_index = *process_index_for_pid.entry(pid).or_insert_with(|| {
+                    processes.push(ProcessIndexBuilder::new(pid));
+                    processes.len() - 1
+                });
+                processes[process_index].process_line(line_index, &line_contents);
+            }
+        }
+        line_index += 1;
+    }
+    Ok(LogIndex {
+        format_version: INDEX_FORMAT_VERSION,
+        input_size,
+        line_count: line_index,
+        checkpoint_interval,
+        processes: processes
+            .into_iter()
+            .map(|p| p.into_process_index())
+            .collect(),
+        checkpoints,
+    })
+}
+
+impl LogIndex {
+    pub fn load(path: &str) -> Result<LogIndex, io::Error> {
+        let reader = BufReader::new(File::open(path)?);
+        let index: LogIndex = serde_json::from_reader(reader)?;
+        if index.format_version != INDEX_FORMAT_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} has index format version {}, expected {}",
+                    path,
+                    index.format_version,
+                    INDEX_FORMAT_VERSION
+                ),
+            ));
+        }
+        Ok(index)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), io::Error> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    /// Whether this index was built from the given file. Only the file size
+    /// is compared.
+    pub fn matches_input(&self, filename: &str) -> bool {
+        match fs::metadata(filename) {
+            Ok(metadata) => metadata.len() == self.input_size,
+            Err(_) => false,
+        }
+    }
+
+    pub fn process(&self, pid: i32) -> Option<&ProcessIndex> {
+        self.processes.iter().find(|p| p.pid == pid)
+    }
+
+    /// Finds the last checkpoint at or before line_index and restores the
+    /// state of the process with the given pid at that checkpoint. Returns
+    /// the line index and byte offset at which parsing needs to resume.
+    pub fn state_before_line(&self, pid: i32, line_index: usize) -> Option<(usize, u64, ProcessState)> {
+        let checkpoint = self.checkpoints
+            .iter()
+            .take_while(|c| c.line_index <= line_index)
+            .last()?;
+        let state = match (
+            self.process(pid),
+            checkpoint.processes.iter().find(|p| p.pid == pid),
+        ) {
+            (Some(process), Some(snapshot)) => {
+                let mut stack_table = process.stack_table.clone();
+                stack_table.truncate(snapshot.frame_count, snapshot.stack_count);
+                ProcessState {
+                    stack_info: StackInfoCollector {
+                        stack_table,
+                        shared_libs_json_string: process.shared_libs_json
+                            [..snapshot.shared_libs_json_len]
+                            .to_owned(),
+                    },
+                    arena_info: ArenaInfoCollector::from_arenas(snapshot.arenas.clone()),
+                }
+            }
+            // The process hasn't printed anything before this checkpoint.
+            _ => ProcessState::new(),
+        };
+        Some((checkpoint.line_index, checkpoint.byte_offset, state))
+    }
+}
+
+#[test]
+fn test_build_index() {
+    use std::env;
+    use std::io::Write;
+    use log_reader::get_line_iter_at;
+
+    let log_path = env::temp_dir().join(format!("cache-log-parser-test-{}.log", ::std::process::id()));
+    let log_path = log_path.to_str().unwrap().to_owned();
+    {
+        let mut log = File::create(&log_path).unwrap();
+        log.write_all(
+            b"==5== add_frame: 0 1000\n\
+             ==6== add_frame: 0 2000\n\
+             ==5== add_stack: 0 0 0\n\
+             ==5== [ArenaAllocator:0x1] Allocating arena chunk at 0x100 with size 64 bytes\n\
+             ==5== Begin DisplayList building\n\
+             ==5== add_frame: 1 1010\n\
+             ==5== add_stack: 1 0 1\n\
+             ==5== [ArenaAllocator:0x1] Deallocating arena chunk at 0x100 with size 64 bytes\n\
+             ==5== End DisplayList building\n"
+        ).unwrap();
+    }
+    let index = build_index(&log_path, 4).unwrap();
+    assert!(index.matches_input(&log_path));
+    assert_eq!(index.line_count, 9);
+    assert_eq!(
+        index.checkpoints.iter().map(|c| c.line_index).collect::<Vec<_>>(),
+        vec![0, 4, 8]
+    );
+    assert_eq!(index.processes.iter().map(|p| (p.pid, p.line_count)).collect::<Vec<_>>(), vec![(5, 8), (6, 1)]);
+    assert_eq!(
+        index.process(5).unwrap().sections,
+        vec![SectionBoundaries {
+            name: "DisplayList building".to_owned(),
+            tid: None,
+            start_line_index: 4,
+            end_line_index: Some(8),
+        }]
+    );
+
+    let (line_index, byte_offset, state) = index.state_before_line(5, 6).unwrap();
+    assert_eq!(line_index, 4);
+    assert_eq!(state.stack_info.stack_table.frames.len(), 1);
+    assert_eq!(state.stack_info.stack_table.stacks.len(), 1);
+    assert_eq!(
+        state.arena_info.arenas().arena_covering_address(0x120),
+        Some("ArenaAllocator:0x1".to_owned())
+    );
+    let first_line = get_line_iter_at(&log_path, line_index, byte_offset).unwrap().next();
+    assert_eq!(first_line, Some((4, "==5== Begin DisplayList building".to_owned())));
+
+    let (_, _, state) = index.state_before_line(5, 8).unwrap();
+    assert_eq!(state.stack_info.stack_table.stacks.len(), 2);
+    assert_eq!(state.arena_info.arenas().arena_covering_address(0x120), None);
+
+    fs::remove_file(&log_path).unwrap();
+}
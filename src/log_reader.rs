@@ -0,0 +1,165 @@
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::fs::File;
+use flate2::bufread::MultiGzDecoder;
+use xz2::bufread::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Plain,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Looks at the first few bytes of a file and decides how it's compressed.
+pub fn detect_input_format(header: &[u8]) -> InputFormat {
+    if header.starts_with(GZIP_MAGIC) {
+        InputFormat::Gzip
+    } else if header.starts_with(ZSTD_MAGIC) {
+        InputFormat::Zstd
+    } else if header.starts_with(XZ_MAGIC) {
+        InputFormat::Xz
+    } else {
+        InputFormat::Plain
+    }
+}
+
+/// Opens the log file and returns a reader that produces the decompressed
+/// contents, so that gzip / zstd / xz compressed logs can be streamed without
+/// a temporary file.
+pub fn open_log(filename: &str) -> Result<Box<dyn BufRead>, io::Error> {
+    let mut reader = BufReader::new(File::open(filename)?);
+    let input_format = detect_input_format(reader.fill_buf()?);
+    Ok(match input_format {
+        InputFormat::Plain => Box::new(reader),
+        InputFormat::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        InputFormat::Zstd => Box::new(BufReader::new(ZstdDecoder::with_buffer(reader)?)),
+        InputFormat::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+    })
+}
+
+/// Like open_log, but the returned reader starts at byte_offset in the
+/// decompressed contents. Plain files are seeked, compressed files have to be
+/// decompressed up to that point.
+pub fn open_log_at(filename: &str, byte_offset: u64) -> Result<Box<dyn BufRead>, io::Error> {
+    let mut file = File::open(filename)?;
+    let input_format = {
+        let mut header = [0u8; 6];
+        let header_len = file.read(&mut header)?;
+        detect_input_format(&header[..header_len])
+    };
+    if input_format == InputFormat::Plain {
+        file.seek(SeekFrom::Start(byte_offset))?;
+        return Ok(Box::new(BufReader::new(file)));
+    }
+    let mut reader = open_log(filename)?;
+    let skipped = io::copy(&mut (&mut reader).take(byte_offset), &mut io::sink())?;
+    if skipped != byte_offset {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{} is shorter than {} bytes", filename, byte_offset),
+        ));
+    }
+    Ok(reader)
+}
+
+/// Splits the reader into lines and numbers them, starting at zero. Lines
+/// that aren't valid UTF-8 are skipped but still counted. Reading stops at the
+/// first I/O error, e.g. when a compressed log is truncated.
+pub fn enumerate_lines<R>(reader: R) -> Box<dyn Iterator<Item = (usize, String)>>
+where
+    R: BufRead + 'static,
+{
+    enumerate_lines_from(reader, 0)
+}
+
+/// Like enumerate_lines, but numbers the lines starting at first_line_index,
+/// for readers that don't start at the beginning of the log.
+pub fn enumerate_lines_from<R>(
+    reader: R,
+    first_line_index: usize,
+) -> Box<dyn Iterator<Item = (usize, String)>>
+where
+    R: BufRead + 'static,
+{
+    Box::new(
+        reader
+            .split(b'\n')
+            .enumerate()
+            .map(move |(i, line_result)| (first_line_index + i, line_result))
+            .scan((), |_, (line_index, line_result)| match line_result {
+                Ok(line) => Some((line_index, line)),
+                Err(e) => {
+                    eprintln!("Stopped reading the log at line {}: {}", line_index, e);
+                    None
+                }
+            })
+            .flat_map(|(line_index, mut line)| {
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                String::from_utf8(line).ok().map(|line| (line_index, line))
+            }),
+    )
+}
+
+pub fn get_line_iter(filename: &str) -> Result<Box<dyn Iterator<Item = (usize, String)>>, io::Error> {
+    Ok(enumerate_lines(open_log(filename)?))
+}
+
+/// Returns the lines of the log starting at line_index, which needs to start
+/// at byte_offset in the decompressed log.
+pub fn get_line_iter_at(
+    filename: &str,
+    line_index: usize,
+    byte_offset: u64,
+) -> Result<Box<dyn Iterator<Item = (usize, String)>>, io::Error> {
+    Ok(enumerate_lines_from(open_log_at(filename, byte_offset)?, line_index))
+}
+
+#[test]
+fn test_detect_input_format() {
+    assert_eq!(detect_input_format(b"==8884== LL cache information"), InputFormat::Plain);
+    assert_eq!(detect_input_format(&[0x1f, 0x8b, 0x08, 0x00]), InputFormat::Gzip);
+    assert_eq!(detect_input_format(&[0x28, 0xb5, 0x2f, 0xfd, 0x04]), InputFormat::Zstd);
+    assert_eq!(detect_input_format(b"\xfd7zXZ\x00\x00"), InputFormat::Xz);
+    assert_eq!(detect_input_format(&[0x1f]), InputFormat::Plain);
+}
+
+#[test]
+fn test_enumerate_compressed_lines() {
+    use std::io::{Cursor, Write};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    let log = "==1== Begin DisplayList building\r\n==1== \u{fffd}\n==1== End DisplayList building\n";
+    let mut gzip_encoder = GzEncoder::new(Vec::new(), Compression::default());
+    gzip_encoder.write_all(log.as_bytes()).unwrap();
+    let compressed = gzip_encoder.finish().unwrap();
+    let mut reader = BufReader::new(Cursor::new(compressed));
+    assert_eq!(detect_input_format(reader.fill_buf().unwrap()), InputFormat::Gzip);
+    let lines: Vec<(usize, String)> =
+        enumerate_lines(BufReader::new(MultiGzDecoder::new(reader))).collect();
+    assert_eq!(
+        lines,
+        vec![
+            (0, "==1== Begin DisplayList building".to_owned()),
+            (1, "==1== \u{fffd}".to_owned()),
+            (2, "==1== End DisplayList building".to_owned()),
+        ]
+    );
+
+    let compressed = ::zstd::encode_all(Cursor::new(log.as_bytes()), 0).unwrap();
+    let lines: Vec<(usize, String)> =
+        enumerate_lines(BufReader::new(ZstdDecoder::new(Cursor::new(compressed)).unwrap())).collect();
+    assert_eq!(lines.len(), 3);
+
+    let invalid_utf8: &[u8] = b"a\n\xff\xfe\nb";
+    let lines: Vec<(usize, String)> = enumerate_lines(Cursor::new(invalid_utf8)).collect();
+    assert_eq!(lines, vec![(0, "a".to_owned()), (2, "b".to_owned())]);
+}
@@ -1,49 +1,443 @@
-#![feature(test)]
-extern crate test;
-extern crate regex;
-extern crate hyper;
-extern crate flate2;
-extern crate rayon;
-#[macro_use]
-extern crate nom;
+extern crate cache_log_parser;
 extern crate serde;
 #[macro_use]
-extern crate serde_json;
-#[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate clap;
-extern crate rand;
-extern crate itertools;
-extern crate pretty_bytes;
-extern crate fixed_circular_buffer;
-
-mod cache_log_parsing;
-mod shared_libraries;
-mod addr2line_cmd;
-mod ranges;
-mod cpucache;
-mod stack_table;
-mod cache_log_info;
-mod arenas;
-mod profile;
-
-use std::io::{BufRead, BufReader};
-use std::fs::File;
-use cache_log_info::{print_display_list_info, print_other_lines, print_process_info,
-                     print_multiple_read_ranges, print_cache_line_wastage,
-                     print_surrounding_lines, print_wastage_source_code};
-
-fn get_line_iter(filename: &str) -> Box<Iterator<Item = (usize, String)>> {
-    let reader = BufReader::new(File::open(filename).unwrap());
-
-    Box::new(reader.lines().enumerate().flat_map(
-        |(line_index, line_result)| {
-            match line_result {
-                Ok(line) => Some((line_index, line)),
-                Err(_) => None,
-            }
-        },
-    ))
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process;
+use cache_log_parser::{log_reader, server};
+use cache_log_parser::cache_log_info::{print_section_info, print_other_lines, print_process_info,
+                     analyze_multiple_read_ranges, print_cache_line_wastage, build_cache_line_usage_profiles,
+                     print_surrounding_lines, analyze_wastage_source_code, select_sections, select_indexed_sections, find_cache_config,
+                     OutputFormat, ProcessState, ProfileOutput, SectionRange, SectionSelection};
+use cache_log_parser::cache_simulation::{parse_cache_size, simulate_caches};
+use cache_log_parser::cache_snapshot::cache_snapshot_at;
+use cache_log_parser::cache_hierarchy::{parse_cache_level, simulate_hierarchy, HierarchyConfig, Inclusion};
+use cache_log_parser::cpucache::CacheConfig;
+use cache_log_parser::replacement_policy::ReplacementPolicyKind;
+use cache_log_parser::prefetcher::PrefetcherKind;
+use cache_log_parser::profile::{prefixed_file_name, ProfileFormat};
+use cache_log_parser::diff::{DiffRange, DiffReport, DiffSpec, Measurement};
+use cache_log_parser::log_index::{build_index, index_path_for_input, LogIndex};
+use cache_log_parser::symbolication::{Addr2lineCommand, DwarfSymbolicator, Symbolicator};
+use cache_log_parser::symbol_cache::SymbolCache;
+
+fn get_line_iter(filename: &str) -> Box<dyn Iterator<Item = (usize, String)>> {
+    match log_reader::get_line_iter(filename) {
+        Ok(iter) => iter,
+        Err(e) => {
+            eprintln!("Couldn't open {}: {}", filename, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn get_symbolicator(matches: &clap::ArgMatches) -> Box<dyn Symbolicator> {
+    if matches.is_present("use-addr2line-binary") {
+        Box::new(Addr2lineCommand)
+    } else {
+        Box::new(DwarfSymbolicator::new())
+    }
+}
+
+fn get_symbol_cache(matches: &clap::ArgMatches) -> Option<SymbolCache> {
+    if matches.is_present("no-symbol-cache") {
+        return None;
+    }
+    match matches.value_of("symbol-cache-dir") {
+        Some(dir) => Some(SymbolCache::new(Path::new(dir))),
+        None => SymbolCache::default_dir().map(|dir| SymbolCache::new(&dir)),
+    }
+}
+
+/// Returns the lines of the log starting at the last checkpoint before
+/// start_line_index, together with the state of the process at that
+/// checkpoint, set up to symbolicate with the backend that was asked for.
+fn get_line_iter_and_state(
+    matches: &clap::ArgMatches,
+    pid: i32,
+    start_line_index: usize,
+) -> (Box<dyn Iterator<Item = (usize, String)>>, ProcessState) {
+    let filename = matches.value_of("INPUT").unwrap();
+    get_line_iter_and_state_for_log(matches, filename, matches.value_of("index"), pid, start_line_index)
+}
+
+fn get_line_iter_and_state_for_log(
+    matches: &clap::ArgMatches,
+    filename: &str,
+    index_path: Option<&str>,
+    pid: i32,
+    start_line_index: usize,
+) -> (Box<dyn Iterator<Item = (usize, String)>>, ProcessState) {
+    let (iter, mut state) = get_line_iter_and_state_from_index(filename, index_path, pid, start_line_index);
+    state.stack_info.stack_table.set_symbolicator(get_symbolicator(matches));
+    if let Some(symbol_cache) = get_symbol_cache(matches) {
+        state.stack_info.stack_table.set_symbol_cache(symbol_cache);
+    }
+    (iter, state)
+}
+
+/// Loads the given index, or <INPUT>.index if it exists, unless it doesn't
+/// match the input. Returns the index together with its path.
+fn load_index(filename: &str, index_path: Option<&str>) -> Option<(LogIndex, String)> {
+    let index_path = match index_path {
+        Some(index_path) => index_path.to_owned(),
+        None => {
+            let index_path = index_path_for_input(filename);
+            if !Path::new(&index_path).exists() {
+                return None;
+            }
+            index_path
+        }
+    };
+    match LogIndex::load(&index_path) {
+        Ok(ref index) if !index.matches_input(filename) => {
+            eprintln!("Ignoring the index {} because it doesn't match {}.", index_path, filename);
+            None
+        }
+        Ok(index) => Some((index, index_path)),
+        Err(e) => {
+            eprintln!("Couldn't read the index {}: {}", index_path, e);
+            None
+        }
+    }
+}
+
+/// Falls back to the start of the log if there's no usable index.
+fn get_line_iter_and_state_from_index(
+    filename: &str,
+    index_path: Option<&str>,
+    pid: i32,
+    start_line_index: usize,
+) -> (Box<dyn Iterator<Item = (usize, String)>>, ProcessState) {
+    if let Some((index, index_path)) = load_index(filename, index_path) {
+        if let Some((line_index, byte_offset, state)) = index.state_before_line(pid, start_line_index) {
+            eprintln!("Resuming from the checkpoint at line {} in {}.", line_index, index_path);
+            match log_reader::get_line_iter_at(filename, line_index, byte_offset) {
+                Ok(iter) => return (iter, state),
+                Err(e) => {
+                    eprintln!("Couldn't open {} at byte offset {}: {}", filename, byte_offset, e);
+                    process::exit(1);
+                }
+            }
+        }
+    }
+    (get_line_iter(filename), ProcessState::new())
+}
+
+/// The selected sections of the log, with the boundaries from the index if
+/// there is one. Otherwise, and for the largest section, whose size the
+/// index doesn't know, this needs a pass over the log.
+fn find_sections(
+    filename: &str,
+    index_path: Option<&str>,
+    pid: i32,
+    section_name: Option<&str>,
+    selection: &SectionSelection,
+) -> Vec<SectionRange> {
+    if let Some((index, _)) = load_index(filename, index_path) {
+        if let Some(process) = index.process(pid) {
+            if let Some(sections) = select_indexed_sections(&process.sections, section_name, selection) {
+                return sections;
+            }
+        }
+    }
+    select_sections(pid, get_line_iter(filename), section_name, selection)
+}
+
+/// The line ranges to analyze: either the one given with --start and --end,
+/// or the sections picked with --section, --largest-section or
+/// --all-sections, labeled with <name>#<n>.
+fn get_line_ranges(matches: &clap::ArgMatches, pid: i32) -> Vec<(Option<String>, usize, usize)> {
+    let selection = if let Some(section) = matches.value_of("section") {
+        match SectionSelection::parse_numbered(section) {
+            Some(selection) => Some(selection),
+            None => {
+                eprintln!("--section needs to have the form <name>#<n>, e.g. \"DisplayList building#3\".");
+                process::exit(1);
+            }
+        }
+    } else if matches.is_present("largest-section") {
+        Some(SectionSelection::Largest)
+    } else if matches.is_present("all-sections") {
+        Some(SectionSelection::All)
+    } else {
+        None
+    };
+    let selection = match selection {
+        Some(selection) => selection,
+        None => {
+            let (start_line_index, end_line_index) = match (matches.value_of("start"), matches.value_of("end")) {
+                (Some(start), Some(end)) => (start, end),
+                _ => {
+                    eprintln!("Either --start and --end, --section, --largest-section or --all-sections need to be given.");
+                    process::exit(1);
+                }
+            };
+            let start_line_index: usize = start_line_index.parse().expect("start line number needs to be an unsigned integer");
+            let end_line_index: usize = end_line_index.parse().expect("end line number needs to be an unsigned integer");
+            return vec![(None, start_line_index, end_line_index)];
+        }
+    };
+    let sections = find_sections(
+        matches.value_of("INPUT").unwrap(),
+        matches.value_of("index"),
+        pid,
+        matches.value_of("section-name"),
+        &selection,
+    );
+    if sections.is_empty() {
+        eprintln!("Couldn't find a matching section for pid {}.", pid);
+        process::exit(1);
+    }
+    sections
+        .into_iter()
+        .map(|section| (Some(section.label), section.start_line_index, section.end_line_index))
+        .collect()
+}
+
+/// Measures one side of a diff. This needs at most two passes over the log:
+/// one to find the section, if the range was given by section and the index
+/// doesn't have it, and one that measures everything, which can skip ahead
+/// using the index.
+fn measure_diff_input(matches: &clap::ArgMatches, spec: &DiffSpec, index_path: Option<&str>) -> Measurement {
+    let pid = spec.pid;
+    let (label, start_line_index, end_line_index) = match spec.range {
+        DiffRange::Lines(start_line_index, end_line_index) => (None, start_line_index, end_line_index),
+        DiffRange::Section(ref selection) => {
+            match find_sections(&spec.log, index_path, pid, None, selection).into_iter().next() {
+                Some(section) => (Some(section.label), section.start_line_index, section.end_line_index),
+                None => {
+                    eprintln!("Couldn't find a matching section for {}.", spec.spec);
+                    process::exit(1);
+                }
+            }
+        }
+    };
+    let (iter, state) = get_line_iter_and_state_for_log(matches, &spec.log, index_path, pid, start_line_index);
+    Measurement::measure(spec, label, iter, state, start_line_index, end_line_index)
+}
+
+fn get_diff_spec(matches: &clap::ArgMatches, name: &str) -> DiffSpec {
+    let spec = matches.value_of(name).unwrap();
+    match DiffSpec::parse(spec) {
+        Some(spec) => spec,
+        None => {
+            eprintln!(
+                "{} needs to have the form <LOG>:<PID>:<SECTION>, where SECTION is <name>#<n>, largest or <start>-<end>, e.g. \"cache.log:8884:DisplayList building#3\".",
+                spec
+            );
+            process::exit(1);
+        }
+    }
+}
+
+fn get_output_format(matches: &clap::ArgMatches) -> OutputFormat {
+    match matches.value_of("format") {
+        None | Some("text") => OutputFormat::Text,
+        Some("json") => OutputFormat::Json,
+        Some(format) => {
+            eprintln!("Unknown output format {}, needs to be text or json.", format);
+            process::exit(1);
+        }
+    }
+}
+
+fn get_profile_formats(matches: &clap::ArgMatches) -> Vec<ProfileFormat> {
+    let formats = match matches.value_of("profile-format") {
+        Some(formats) => formats,
+        None => return vec![ProfileFormat::Firefox],
+    };
+    formats
+        .split(',')
+        .map(|format| match ProfileFormat::parse(format) {
+            Some(format) => format,
+            None => {
+                eprintln!("Unknown profile format {}, needs to be firefox, collapsed, callgrind or pprof.", format);
+                process::exit(1);
+            }
+        })
+        .collect()
+}
+
+/// The geometry of the cache that the log was recorded with.
+fn get_recorded_cache_config(matches: &clap::ArgMatches, pid: i32) -> CacheConfig {
+    match find_cache_config(pid, &mut get_line_iter(matches.value_of("INPUT").unwrap())) {
+        Some(recorded) => recorded,
+        None => {
+            eprintln!("Couldn't find the LL cache information of process {} in the log.", pid);
+            process::exit(1);
+        }
+    }
+}
+
+/// The cache configurations to simulate: every combination of the sizes,
+/// line sizes and associativities that were given, each of which defaults
+/// to the one of the recorded cache.
+fn get_cache_configs(matches: &clap::ArgMatches, recorded: CacheConfig) -> Vec<CacheConfig> {
+    let values = |name: &str, default: u32| -> Vec<u32> {
+        match matches.value_of(name) {
+            None => vec![default],
+            Some(values) => values
+                .split(',')
+                .map(|value| match parse_cache_size(value) {
+                    Some(value) => value,
+                    None => {
+                        eprintln!("Couldn't parse {} in --{}, needs to be a number, optionally with K or M.", value, name);
+                        process::exit(1);
+                    }
+                })
+                .collect(),
+        }
+    };
+    let mut configs = Vec::new();
+    for &size in &values("size", recorded.size) {
+        for &line_size in &values("line-size", recorded.line_size) {
+            for &assoc in &values("assoc", recorded.assoc) {
+                let config = CacheConfig { size, line_size, assoc };
+                if let Err(e) = config.check() {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+                configs.push(config);
+            }
+        }
+    }
+    configs.sort();
+    configs.dedup();
+    configs
+}
+
+/// The levels of the hierarchy to simulate. The LLC defaults to the
+/// recorded cache, and all levels have its line size.
+fn get_hierarchy_config(matches: &clap::ArgMatches, recorded: CacheConfig) -> HierarchyConfig {
+    let level = |name: &str, default: &str| -> CacheConfig {
+        let value = matches.value_of(name).unwrap_or(default);
+        let config = match parse_cache_level(value, recorded.line_size) {
+            Some(config) => config,
+            None => {
+                eprintln!("--{} needs to have the form <SIZE>/<WAYS>, e.g. 32K/8, not {}.", name, value);
+                process::exit(1);
+            }
+        };
+        if let Err(e) = config.check() {
+            eprintln!("--{}: {}", name, e);
+            process::exit(1);
+        }
+        config
+    };
+    let recorded_llc = format!("{}/{}", recorded.size, recorded.assoc);
+    HierarchyConfig {
+        l1d: level("l1d", "32K/8"),
+        l1i: level("l1i", "32K/8"),
+        l2: level("l2", "256K/8"),
+        llc: level("llc", &recorded_llc),
+    }
+}
+
+fn get_inclusions(matches: &clap::ArgMatches) -> Vec<Inclusion> {
+    matches
+        .value_of("inclusion")
+        .unwrap_or("inclusive,exclusive,non-inclusive")
+        .split(',')
+        .map(|inclusion| match Inclusion::parse(inclusion) {
+            Some(inclusion) => inclusion,
+            None => {
+                eprintln!("Unknown inclusion mode {}, needs to be inclusive, exclusive or non-inclusive.", inclusion);
+                process::exit(1);
+            }
+        })
+        .collect()
+}
+
+fn get_prefetchers(matches: &clap::ArgMatches) -> Vec<PrefetcherKind> {
+    matches
+        .value_of("prefetcher")
+        .unwrap_or("none")
+        .split(',')
+        .map(|prefetcher| match PrefetcherKind::parse(prefetcher) {
+            Some(prefetcher) => prefetcher,
+            None => {
+                eprintln!("Unknown prefetcher {}, needs to be none, next-line, adjacent-pair or stride.", prefetcher);
+                process::exit(1);
+            }
+        })
+        .collect()
+}
+
+fn get_replacement_policies(matches: &clap::ArgMatches, configs: &[CacheConfig]) -> Vec<ReplacementPolicyKind> {
+    let policies = match matches.value_of("policy") {
+        Some(policies) => policies,
+        None => return vec![ReplacementPolicyKind::Lru],
+    };
+    policies
+        .split(',')
+        .map(|policy| {
+            let policy = match ReplacementPolicyKind::parse(policy) {
+                Some(policy) => policy,
+                None => {
+                    eprintln!("Unknown replacement policy {}, needs to be lru, plru, random, srrip or fifo.", policy);
+                    process::exit(1);
+                }
+            };
+            for config in configs {
+                if let Err(e) = policy.check(config.assoc) {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            }
+            policy
+        })
+        .collect()
+}
+
+/// The JSON output of the subcommands that analyze line ranges.
+#[derive(Serialize)]
+struct LineRangeReport<T> {
+    /// The section label, e.g. "DisplayList building#3", if the range was
+    /// picked by section.
+    label: Option<String>,
+    start_line_index: usize,
+    /// None if the section never ended.
+    end_line_index: Option<usize>,
+    report: T,
+}
+
+#[derive(Serialize)]
+struct LineRangeReports<T> {
+    pid: i32,
+    ranges: Vec<LineRangeReport<T>>,
+}
+
+fn line_range_report<T>(label: Option<String>, start_line_index: usize, end_line_index: usize, report: T) -> LineRangeReport<T> {
+    LineRangeReport {
+        label,
+        start_line_index,
+        end_line_index: if end_line_index == usize::MAX { None } else { Some(end_line_index) },
+        report,
+    }
+}
+
+fn print_line_range(label: &Option<String>, start_line_index: usize, end_line_index: usize) {
+    if let Some(ref label) = *label {
+        if end_line_index == usize::MAX {
+            println!("{}: from line {} to the end of the log", label, start_line_index);
+        } else {
+            println!("{}: from line {} to line {}", label, start_line_index, end_line_index);
+        }
+    }
+}
+
+/// Turns e.g. "DisplayList building#3" into "DisplayList_building_3", so that
+/// it can be used in file names.
+fn file_name_for_label(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
 }
 
 fn main() {
@@ -51,60 +445,169 @@ fn main() {
         .version("0.1")
         .author("Markus Stange <mstange@themasta.com>")
         .about("Parses a log with information about memory")
+        .arg(clap::Arg::with_name("use-addr2line-binary")
+             .long("use-addr2line-binary")
+             .global(true)
+             .help("Symbolicate by running the addr2line command from binutils instead of reading the debug information in-process"))
+        .arg(clap::Arg::with_name("symbol-cache-dir")
+             .long("symbol-cache-dir")
+             .takes_value(true)
+             .value_name("DIR")
+             .global(true)
+             .help("Where to cache symbolication results across runs (defaults to ~/.cache/cache-log-parser/symbols)"))
+        .arg(clap::Arg::with_name("no-symbol-cache")
+             .long("no-symbol-cache")
+             .global(true)
+             .help("Don't read or write the symbol cache"))
         .subcommand(clap::SubCommand::with_name("list-processes")
                     .about("Lists the processes (PIDs) whose output is present in the log file.")
                     .args_from_usage(
-                        "<INPUT>             'The input file to use'"))
+                        "--format=[FORMAT]   'Output format: text (default) or json'
+                        <INPUT>              'The input file to use (may be compressed with gzip, zstd or xz)'"))
         .subcommand(clap::SubCommand::with_name("list-sections")
                     .about("Looks for sections in the log that are marked with \"Begin ...\" and \"End ...\" and prints some information about them.")
                     .args_from_usage(
                         "-p, --pid=<PID>     'The pid of the process that should be analyzed'
-                        <INPUT>              'The input file to use'"))
+                        -n, --section-name=[NAME] 'Only list sections with this name, e.g. \"DisplayList building\"'
+                        --format=[FORMAT]    'Output format: text (default) or json'
+                        <INPUT>              'The input file to use (may be compressed with gzip, zstd or xz)'"))
         .subcommand(clap::SubCommand::with_name("generate-profiles")
                     .about("Generates read_bytes, used_bytes, and wasted_bytes profiles for the given range for the given process.")
                     .args_from_usage(
                         "-p, --pid=<PID>     'The pid of the process that should be analyzed'
-                        -s, --start=<START>  'The line number at which to start analyzing'
-                        -e, --end=<END>      'The line number at which to stop analyzing'
-                        <INPUT>              'The input file to use'"))
+                        -s, --start=[START]  'The line number at which to start analyzing'
+                        -e, --end=[END]      'The line number at which to stop analyzing'
+                        --section=[SECTION]  'Analyze the n-th section with the given name instead of a line range, e.g. \"DisplayList building#3\" (see list-sections)'
+                        --largest-section    'Analyze the section that read the most memory instead of a line range'
+                        --all-sections       'Analyze every section, one after the other, instead of a line range'
+                        --section-name=[NAME] 'Only consider sections with this name for --largest-section and --all-sections'
+                        -o, --output-dir=[DIR] 'The directory in which the profiles should be saved (created if missing, defaults to the current directory)'
+                        --prefix=[PREFIX]    'A prefix for the file names of the generated profiles'
+                        --per-arena          'Also write one profile per arena, and one for the reads outside of any arena'
+                        --profile-format=[FORMATS] 'Comma-separated formats of the profiles: firefox (default), collapsed (for flamegraph.pl, inferno and speedscope) , callgrind (for KCachegrind) or pprof'
+                        --index=[INDEX]      'The log index to use for skipping ahead (defaults to <INPUT>.index if it exists)'
+                        <INPUT>              'The input file to use (may be compressed with gzip, zstd or xz)'"))
+        .subcommand(clap::SubCommand::with_name("serve")
+                    .about("Generates the cache line usage profile for the given range for the given process and serves it to the Firefox profiler, together with symbols for it.")
+                    .args_from_usage(
+                        "-p, --pid=<PID>     'The pid of the process that should be analyzed'
+                        -s, --start=[START]  'The line number at which to start analyzing'
+                        -e, --end=[END]      'The line number at which to stop analyzing'
+                        --section=[SECTION]  'Analyze the n-th section with the given name instead of a line range, e.g. \"DisplayList building#3\" (see list-sections)'
+                        --largest-section    'Analyze the section that read the most memory instead of a line range'
+                        --all-sections       'Analyze every section, one after the other, instead of a line range'
+                        --section-name=[NAME] 'Only consider sections with this name for --largest-section and --all-sections'
+                        --port=[PORT]        'The port to listen on, on localhost (defaults to a free port)'
+                        --index=[INDEX]      'The log index to use for skipping ahead (defaults to <INPUT>.index if it exists)'
+                        <INPUT>              'The input file to use (may be compressed with gzip, zstd or xz)'"))
         .subcommand(clap::SubCommand::with_name("print-wastage-source-code")
                     .about("Prints the source code that's responsible for the most wasted bytes for the given range for the given process.")
                     .args_from_usage(
                         "-p, --pid=<PID>     'The pid of the process that should be analyzed'
-                        -s, --start=<START>  'The line number at which to start analyzing'
-                        -e, --end=<END>      'The line number at which to stop analyzing'
-                        <INPUT>              'The input file to use'"))
+                        -s, --start=[START]  'The line number at which to start analyzing'
+                        -e, --end=[END]      'The line number at which to stop analyzing'
+                        --section=[SECTION]  'Analyze the n-th section with the given name instead of a line range, e.g. \"DisplayList building#3\" (see list-sections)'
+                        --largest-section    'Analyze the section that read the most memory instead of a line range'
+                        --all-sections       'Analyze every section, one after the other, instead of a line range'
+                        --section-name=[NAME] 'Only consider sections with this name for --largest-section and --all-sections'
+                        --format=[FORMAT]    'Output format: text (default) or json'
+                        --index=[INDEX]      'The log index to use for skipping ahead (defaults to <INPUT>.index if it exists)'
+                        <INPUT>              'The input file to use (may be compressed with gzip, zstd or xz)'"))
         .subcommand(clap::SubCommand::with_name("analyze-double-reads")
                     .about("Checks which memory ranges are read into the cache multiple times, and prints callstacks for reads + evictions for some of them.")
                     .args_from_usage(
                         "-p, --pid=<PID>     'The pid of the process that should be analyzed'
-                        -s, --start=<START>  'The line number at which to start analyzing'
-                        -e, --end=<END>      'The line number at which to stop analyzing'
-                        <INPUT>              'The input file to use'"))
+                        -s, --start=[START]  'The line number at which to start analyzing'
+                        -e, --end=[END]      'The line number at which to stop analyzing'
+                        --section=[SECTION]  'Analyze the n-th section with the given name instead of a line range, e.g. \"DisplayList building#3\" (see list-sections)'
+                        --largest-section    'Analyze the section that read the most memory instead of a line range'
+                        --all-sections       'Analyze every section, one after the other, instead of a line range'
+                        --section-name=[NAME] 'Only consider sections with this name for --largest-section and --all-sections'
+                        --format=[FORMAT]    'Output format: text (default) or json'
+                        --index=[INDEX]      'The log index to use for skipping ahead (defaults to <INPUT>.index if it exists)'
+                        <INPUT>              'The input file to use (may be compressed with gzip, zstd or xz)'"))
+        .subcommand(clap::SubCommand::with_name("simulate-cache")
+                    .about("Replays the reads of the given range for the given process in caches of other sizes, line sizes, associativities, replacement policies and prefetchers, and compares their misses, wasted bytes and multi-read overhead with the recorded cache.")
+                    .args_from_usage(
+                        "-p, --pid=<PID>     'The pid of the process that should be analyzed'
+                        -s, --start=[START]  'The line number at which to start analyzing'
+                        -e, --end=[END]      'The line number at which to stop analyzing'
+                        --section=[SECTION]  'Analyze the n-th section with the given name instead of a line range, e.g. \"DisplayList building#3\" (see list-sections)'
+                        --largest-section    'Analyze the section that read the most memory instead of a line range'
+                        --all-sections       'Analyze every section, one after the other, instead of a line range'
+                        --section-name=[NAME] 'Only consider sections with this name for --largest-section and --all-sections'
+                        --size=[SIZES]       'Comma-separated cache sizes to simulate, e.g. 512K,1M,2M (defaults to the size of the recorded cache)'
+                        --line-size=[SIZES]  'Comma-separated line sizes to simulate (defaults to the line size of the recorded cache)'
+                        --assoc=[WAYS]       'Comma-separated associativities to simulate (defaults to the associativity of the recorded cache)'
+                        --policy=[POLICIES]  'Comma-separated replacement policies to simulate each cache with: lru (default), plru, random, srrip or fifo'
+                        --prefetcher=[PREFETCHERS] 'Comma-separated prefetchers to simulate each cache with: none (default), next-line, adjacent-pair or stride'
+                        --format=[FORMAT]    'Output format: text (default) or json'
+                        --index=[INDEX]      'The log index to use for skipping ahead (defaults to <INPUT>.index if it exists)'
+                        <INPUT>              'The input file to use (may be compressed with gzip, zstd or xz)'"))
+        .subcommand(clap::SubCommand::with_name("simulate-hierarchy")
+                    .about("Replays the reads of the given range for the given process in a hierarchy of L1D, L1I, L2 and LLC caches, and prints the hits, misses, transferred bytes and used bytes of each level, and which level served the data accesses and the instruction fetches.")
+                    .args_from_usage(
+                        "-p, --pid=<PID>     'The pid of the process that should be analyzed'
+                        -s, --start=[START]  'The line number at which to start analyzing'
+                        -e, --end=[END]      'The line number at which to stop analyzing'
+                        --section=[SECTION]  'Analyze the n-th section with the given name instead of a line range, e.g. \"DisplayList building#3\" (see list-sections)'
+                        --largest-section    'Analyze the section that read the most memory instead of a line range'
+                        --all-sections       'Analyze every section, one after the other, instead of a line range'
+                        --section-name=[NAME] 'Only consider sections with this name for --largest-section and --all-sections'
+                        --l1d=[LEVEL]        'The size and associativity of the L1 data cache, as <SIZE>/<WAYS> (defaults to 32K/8)'
+                        --l1i=[LEVEL]        'The size and associativity of the L1 instruction cache (defaults to 32K/8)'
+                        --l2=[LEVEL]         'The size and associativity of the L2 cache (defaults to 256K/8)'
+                        --llc=[LEVEL]        'The size and associativity of the last level cache (defaults to the recorded cache)'
+                        --inclusion=[MODES]  'Comma-separated inclusion modes to simulate: inclusive, exclusive and non-inclusive (defaults to all three)'
+                        --format=[FORMAT]    'Output format: text (default) or json'
+                        --index=[INDEX]      'The log index to use for skipping ahead (defaults to <INPUT>.index if it exists)'
+                        <INPUT>              'The input file to use (may be compressed with gzip, zstd or xz)'"))
+        .subcommand(clap::SubCommand::with_name("cache-snapshot")
+                    .about("Replays the swaps of the recorded cache up to the given line for the given process, and prints the occupancy of every set, the arena or shared library that each cached line belongs to, and the cached bytes per owner.")
+                    .args_from_usage(
+                        "-p, --pid=<PID>     'The pid of the process that should be analyzed'
+                        -l, --line=<LINE>    'The line number before which to take the snapshot'
+                        --format=[FORMAT]    'Output format: text (default) or json'
+                        <INPUT>              'The input file to use (may be compressed with gzip, zstd or xz)'"))
+        .subcommand(clap::SubCommand::with_name("diff")
+                    .about("Compares the memory reads of two sections or line ranges, e.g. before and after a change, and exits with status 2 if they grew by more than a threshold.")
+                    .args_from_usage(
+                        "--threshold=[PERCENT] 'Exit with status 2 if the bytes read or the wasted bytes grew by more than this many percent, or the multi-read or cache-line overhead by more than this many percentage points'
+                        --format=[FORMAT]    'Output format: text (default) or json'
+                        --before-index=[INDEX] 'The index of the log of BEFORE (defaults to <LOG>.index if it exists)'
+                        --after-index=[INDEX] 'The index of the log of AFTER (defaults to <LOG>.index if it exists)'
+                        <BEFORE>             'The baseline, as <LOG>:<PID>:<SECTION>, where SECTION is <name>#<n>, largest or <start>-<end>'
+                        <AFTER>              'What to compare with the baseline, in the same form as BEFORE'"))
+        .subcommand(clap::SubCommand::with_name("index")
+                    .about("Parses the whole log once and saves checkpoints, so that other subcommands can skip to the part of the log they need.")
+                    .args_from_usage(
+                        "-n, --interval=[LINES] 'How many lines apart the checkpoints should be (defaults to 1000000)'
+                        -o, --output=[FILE]  'Where to save the index (defaults to <INPUT>.index)'
+                        <INPUT>              'The input file to use (may be compressed with gzip, zstd or xz)'"))
         .subcommand(clap::SubCommand::with_name("print-context")
                     .about("Prints a small excerpt from the log, filtering out output from other processes.")
                     .args_from_usage(
                         "-p, --pid=<PID>     'The pid of the process whose log output should be printed'
                         -l, --line=<LINE>    'The line number around which to print the context'
                         -c, --context=[CONTEXT] 'How many lines of context should be printed both before and after the line in question'
-                        <INPUT>              'The input file to use'"
+                        <INPUT>              'The input file to use (may be compressed with gzip, zstd or xz)'"
                     ))
         .subcommand(clap::SubCommand::with_name("print-unrecognized-lines")
                     .about("Prints all lines from the log which don't match any of the known patterns.")
                     .args_from_usage(
                         "
-                        <INPUT>              'The input file to use'"
+                        <INPUT>              'The input file to use (may be compressed with gzip, zstd or xz)'"
                     ))
         .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("list-processes") {
         let iter = get_line_iter(matches.value_of("INPUT").unwrap());
-        print_process_info(iter); 
+        print_process_info(iter, get_output_format(matches));
     } else if let Some(matches) = matches.subcommand_matches("list-sections") {
         let pid = matches.value_of("pid").unwrap();
         let pid: i32 = pid.parse().expect("pid needs to be an integer");
         let iter = get_line_iter(matches.value_of("INPUT").unwrap());
-        print_display_list_info(pid, iter);
+        print_section_info(pid, iter, matches.value_of("section-name"), get_output_format(matches));
     } else if let Some(matches) = matches.subcommand_matches("print-context") {
         let pid = matches.value_of("pid").unwrap();
         let pid: i32 = pid.parse().expect("pid needs to be an integer");
@@ -116,30 +619,194 @@ fn main() {
     } else if let Some(matches) = matches.subcommand_matches("generate-profiles") {
         let pid = matches.value_of("pid").unwrap();
         let pid: i32 = pid.parse().expect("pid needs to be an integer");
-        let start_line_index = matches.value_of("start").unwrap();
-        let start_line_index: usize = start_line_index.parse().expect("start line number needs to be an unsigned integer");
-        let end_line_index = matches.value_of("end").unwrap();
-        let end_line_index: usize = end_line_index.parse().expect("end line number needs to be an unsigned integer");
-        let iter = get_line_iter(matches.value_of("INPUT").unwrap());
-        print_cache_line_wastage(pid, iter, start_line_index, end_line_index);
+        let output_dir = Path::new(matches.value_of("output-dir").unwrap_or("."));
+        let file_prefix = matches.value_of("prefix").unwrap_or("");
+        let formats = get_profile_formats(matches);
+        for (label, start_line_index, end_line_index) in get_line_ranges(matches, pid) {
+            print_line_range(&label, start_line_index, end_line_index);
+            let file_prefix = match (label, file_prefix) {
+                (Some(label), "") => file_name_for_label(&label),
+                (Some(label), file_prefix) => format!("{}_{}", file_prefix, file_name_for_label(&label)),
+                (None, file_prefix) => file_prefix.to_owned(),
+            };
+            let output = ProfileOutput {
+                output_dir,
+                file_prefix: &file_prefix,
+                per_arena: matches.is_present("per-arena"),
+                formats: &formats,
+            };
+            let (iter, state) = get_line_iter_and_state(matches, pid, start_line_index);
+            if let Err(e) = print_cache_line_wastage(pid, iter, state, start_line_index, end_line_index, &output) {
+                eprintln!("Couldn't save the profiles to {}: {}", output_dir.display(), e);
+                process::exit(1);
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("serve") {
+        let pid = matches.value_of("pid").unwrap();
+        let pid: i32 = pid.parse().expect("pid needs to be an integer");
+        let port: u16 = matches.value_of("port").map_or(0, |port| port.parse().expect("port needs to be an integer"));
+        let mut profiles = BTreeMap::new();
+        let mut stack_table = None;
+        for (label, start_line_index, end_line_index) in get_line_ranges(matches, pid) {
+            print_line_range(&label, start_line_index, end_line_index);
+            let file_prefix = label.map_or_else(String::new, |label| file_name_for_label(&label));
+            let (iter, state) = get_line_iter_and_state(matches, pid, start_line_index);
+            let mut profile_builder =
+                build_cache_line_usage_profiles(pid, iter, state, start_line_index, end_line_index, false).profile_builder;
+            // The profiler asks the server for symbols when it needs them.
+            profile_builder.set_symbolicate(false);
+            let profile = serde_json::to_vec(&profile_builder.to_json()).expect("serializing the profile failed");
+            profiles.insert(prefixed_file_name(&file_prefix, "cache_line_usage_profile.json"), profile);
+            stack_table = Some(profile_builder.stack_table().clone());
+        }
+        let stack_table = stack_table.expect("need at least one line range");
+        if let Err(e) = server::serve_profiles(profiles, &stack_table, port) {
+            eprintln!("Couldn't serve the profiles: {}", e);
+            process::exit(1);
+        }
     } else if let Some(matches) = matches.subcommand_matches("print-wastage-source-code") {
         let pid = matches.value_of("pid").unwrap();
         let pid: i32 = pid.parse().expect("pid needs to be an integer");
-        let start_line_index = matches.value_of("start").unwrap();
-        let start_line_index: usize = start_line_index.parse().expect("start line number needs to be an unsigned integer");
-        let end_line_index = matches.value_of("end").unwrap();
-        let end_line_index: usize = end_line_index.parse().expect("end line number needs to be an unsigned integer");
-        let iter = get_line_iter(matches.value_of("INPUT").unwrap());
-        print_wastage_source_code(pid, iter, start_line_index, end_line_index);
+        let format = get_output_format(matches);
+        let mut reports = Vec::new();
+        for (label, start_line_index, end_line_index) in get_line_ranges(matches, pid) {
+            let (iter, state) = get_line_iter_and_state(matches, pid, start_line_index);
+            let report = analyze_wastage_source_code(pid, iter, state, start_line_index, end_line_index);
+            match format {
+                OutputFormat::Text => {
+                    print_line_range(&label, start_line_index, end_line_index);
+                    report.print();
+                }
+                OutputFormat::Json => reports.push(line_range_report(label, start_line_index, end_line_index, report)),
+            }
+        }
+        if format == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&LineRangeReports { pid, ranges: reports }).unwrap());
+        }
     } else if let Some(matches) = matches.subcommand_matches("analyze-double-reads") {
         let pid = matches.value_of("pid").unwrap();
         let pid: i32 = pid.parse().expect("pid needs to be an integer");
-        let start_line_index = matches.value_of("start").unwrap();
-        let start_line_index: usize = start_line_index.parse().expect("start line number needs to be an unsigned integer");
-        let end_line_index = matches.value_of("end").unwrap();
-        let end_line_index: usize = end_line_index.parse().expect("end line number needs to be an unsigned integer");
+        let format = get_output_format(matches);
+        let mut reports = Vec::new();
+        for (label, start_line_index, end_line_index) in get_line_ranges(matches, pid) {
+            let (iter, state) = get_line_iter_and_state(matches, pid, start_line_index);
+            let report = analyze_multiple_read_ranges(pid, iter, state, start_line_index, end_line_index);
+            match format {
+                OutputFormat::Text => {
+                    print_line_range(&label, start_line_index, end_line_index);
+                    report.print();
+                }
+                OutputFormat::Json => reports.push(line_range_report(label, start_line_index, end_line_index, report)),
+            }
+        }
+        if format == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&LineRangeReports { pid, ranges: reports }).unwrap());
+        }
+    } else if let Some(matches) = matches.subcommand_matches("simulate-cache") {
+        let pid = matches.value_of("pid").unwrap();
+        let pid: i32 = pid.parse().expect("pid needs to be an integer");
+        let format = get_output_format(matches);
+        let recorded = get_recorded_cache_config(matches, pid);
+        let configs = get_cache_configs(matches, recorded);
+        let policies = get_replacement_policies(matches, &configs);
+        let prefetchers = get_prefetchers(matches);
+        let mut reports = Vec::new();
+        for (label, start_line_index, end_line_index) in get_line_ranges(matches, pid) {
+            let (iter, _) = get_line_iter_and_state(matches, pid, start_line_index);
+            let report = simulate_caches(pid, iter, start_line_index, end_line_index, recorded, &configs, &policies, &prefetchers);
+            match format {
+                OutputFormat::Text => {
+                    print_line_range(&label, start_line_index, end_line_index);
+                    report.print();
+                }
+                OutputFormat::Json => reports.push(line_range_report(label, start_line_index, end_line_index, report)),
+            }
+        }
+        if format == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&LineRangeReports { pid, ranges: reports }).unwrap());
+        }
+    } else if let Some(matches) = matches.subcommand_matches("simulate-hierarchy") {
+        let pid = matches.value_of("pid").unwrap();
+        let pid: i32 = pid.parse().expect("pid needs to be an integer");
+        let format = get_output_format(matches);
+        let recorded = get_recorded_cache_config(matches, pid);
+        let config = get_hierarchy_config(matches, recorded);
+        let inclusions = get_inclusions(matches);
+        let mut reports = Vec::new();
+        for (label, start_line_index, end_line_index) in get_line_ranges(matches, pid) {
+            let (iter, _) = get_line_iter_and_state(matches, pid, start_line_index);
+            let report = simulate_hierarchy(pid, iter, start_line_index, end_line_index, &config, &inclusions);
+            match format {
+                OutputFormat::Text => {
+                    print_line_range(&label, start_line_index, end_line_index);
+                    report.print();
+                }
+                OutputFormat::Json => reports.push(line_range_report(label, start_line_index, end_line_index, report)),
+            }
+        }
+        if format == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&LineRangeReports { pid, ranges: reports }).unwrap());
+        }
+    } else if let Some(matches) = matches.subcommand_matches("cache-snapshot") {
+        let pid = matches.value_of("pid").unwrap();
+        let pid: i32 = pid.parse().expect("pid needs to be an integer");
+        let line_index = matches.value_of("line").unwrap();
+        let line_index: usize = line_index.parse().expect("line number needs to be an unsigned integer");
+        let format = get_output_format(matches);
         let iter = get_line_iter(matches.value_of("INPUT").unwrap());
-        print_multiple_read_ranges(pid, iter, start_line_index, end_line_index);
+        let snapshot = match cache_snapshot_at(pid, iter, line_index) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("Couldn't take a snapshot of the cache of process {}: {}", pid, e);
+                process::exit(1);
+            }
+        };
+        match format {
+            OutputFormat::Text => snapshot.print(),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&snapshot).unwrap()),
+        }
+    } else if let Some(matches) = matches.subcommand_matches("diff") {
+        let threshold_percent = matches.value_of("threshold").map(|threshold| {
+            threshold.trim_end_matches('%').parse::<f64>().expect("threshold needs to be a number")
+        });
+        let format = get_output_format(matches);
+        let before = measure_diff_input(matches, &get_diff_spec(matches, "BEFORE"), matches.value_of("before-index"));
+        let after = measure_diff_input(matches, &get_diff_spec(matches, "AFTER"), matches.value_of("after-index"));
+        let report = DiffReport::new(before, after, threshold_percent);
+        match format {
+            OutputFormat::Text => report.print(),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        }
+        if report.has_regressions() {
+            process::exit(2);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("index") {
+        let filename = matches.value_of("INPUT").unwrap();
+        let checkpoint_interval: usize = matches.value_of("interval").unwrap_or("1000000").parse().expect("interval needs to be an unsigned integer");
+        if checkpoint_interval == 0 {
+            eprintln!("The checkpoint interval needs to be at least one line.");
+            process::exit(1);
+        }
+        let index_path = matches.value_of("output").map(|s| s.to_owned()).unwrap_or_else(|| index_path_for_input(filename));
+        let result = build_index(filename, checkpoint_interval).and_then(|index| {
+            index.save(&index_path)?;
+            Ok(index)
+        });
+        match result {
+            Ok(index) => {
+                println!(
+                    "Wrote {} with {} checkpoints for {} lines from {} processes.",
+                    index_path,
+                    index.checkpoints.len(),
+                    index.line_count,
+                    index.processes.len()
+                );
+            }
+            Err(e) => {
+                eprintln!("Couldn't index {}: {}", filename, e);
+                process::exit(1);
+            }
+        }
     } else if let Some(matches) = matches.subcommand_matches("print-unrecognized-lines") {
         let iter = get_line_iter(matches.value_of("INPUT").unwrap());
         print_other_lines(iter);
@@ -148,8 +815,6 @@ fn main() {
     // let result = 
     // // print_process_info(iter);
     // print_display_list_info(31480, iter);
-    // // print_cache_contents_at(iter, 67317106)?;
-    // // print_cache_contents_at(iter, cache, 58256278)?;
     // // print_other_lines(iter);
     // // print_multiple_read_ranges(iter, 112906438, 113242142);
     // // print_surrounding_lines(31480, iter, 174117724);
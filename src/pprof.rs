@@ -0,0 +1,392 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use profile::{track_identifier, ProfileBuilder, ResolvedStacks, StringTable};
+use protobuf::ProtobufWriter;
+
+// The messages of pprof's profile.proto that we write. Strings are indexes
+// into the profile's string table, and ids start at 1.
+
+struct ValueType {
+    type_: usize,
+    unit: usize,
+}
+
+impl ValueType {
+    fn encode(&self) -> ProtobufWriter {
+        let mut writer = ProtobufWriter::new();
+        writer.int64(1, self.type_ as i64);
+        writer.int64(2, self.unit as i64);
+        writer
+    }
+}
+
+struct Sample {
+    /// The innermost location comes first.
+    location_ids: Vec<u64>,
+    /// One value per sample type.
+    values: Vec<i64>,
+    /// (key, string value) pairs.
+    labels: Vec<(usize, usize)>,
+}
+
+impl Sample {
+    fn encode(&self) -> ProtobufWriter {
+        let mut writer = ProtobufWriter::new();
+        writer.packed_uint64(1, &self.location_ids);
+        writer.packed_int64(2, &self.values);
+        for &(key, value) in &self.labels {
+            let mut label = ProtobufWriter::new();
+            label.int64(1, key as i64);
+            label.int64(2, value as i64);
+            writer.message(3, &label);
+        }
+        writer
+    }
+}
+
+struct Mapping {
+    id: u64,
+    memory_start: u64,
+    memory_limit: u64,
+    file_offset: u64,
+    filename: usize,
+    build_id: usize,
+    /// Whether all locations in this mapping were symbolicated. If they
+    /// weren't, pprof tries to symbolicate them itself.
+    is_symbolicated: bool,
+}
+
+impl Mapping {
+    fn encode(&self) -> ProtobufWriter {
+        let mut writer = ProtobufWriter::new();
+        writer.uint64(1, self.id);
+        writer.uint64(2, self.memory_start);
+        writer.uint64(3, self.memory_limit);
+        writer.uint64(4, self.file_offset);
+        writer.int64(5, self.filename as i64);
+        writer.int64(6, self.build_id as i64);
+        writer.bool(7, self.is_symbolicated);
+        writer.bool(8, self.is_symbolicated);
+        writer.bool(9, self.is_symbolicated);
+        writer.bool(10, self.is_symbolicated);
+        writer
+    }
+}
+
+struct Location {
+    id: u64,
+    mapping_id: u64,
+    address: u64,
+    /// (function id, line) pairs. Functions that were inlined into the
+    /// last one come first.
+    lines: Vec<(u64, usize)>,
+}
+
+impl Location {
+    fn encode(&self) -> ProtobufWriter {
+        let mut writer = ProtobufWriter::new();
+        writer.uint64(1, self.id);
+        writer.uint64(2, self.mapping_id);
+        writer.uint64(3, self.address);
+        for &(function_id, line_number) in &self.lines {
+            let mut line = ProtobufWriter::new();
+            line.uint64(1, function_id);
+            line.int64(2, line_number as i64);
+            writer.message(4, &line);
+        }
+        writer
+    }
+}
+
+struct Function {
+    id: u64,
+    name: usize,
+    filename: usize,
+}
+
+impl Function {
+    fn encode(&self) -> ProtobufWriter {
+        let mut writer = ProtobufWriter::new();
+        writer.uint64(1, self.id);
+        writer.int64(2, self.name as i64);
+        writer.int64(3, self.name as i64);
+        writer.int64(4, self.filename as i64);
+        writer
+    }
+}
+
+/// A profile in pprof's profile.proto format. Every track of the
+/// ProfileBuilder becomes a sample type, e.g. read_bytes in bytes, and the
+/// miss_count sample type counts the samples, i.e. the reads. There's one
+/// location per address, which lists the functions that were inlined at the
+/// address, and one mapping per library. Samples with a category have a
+/// "category" label, e.g. for the arena of the read.
+pub struct PprofProfile {
+    sample_types: Vec<ValueType>,
+    samples: Vec<Sample>,
+    mappings: Vec<Mapping>,
+    locations: Vec<Location>,
+    functions: Vec<Function>,
+    string_table: Vec<String>,
+    default_sample_type: usize,
+}
+
+/// Builds the locations, functions and mappings of a PprofProfile.
+struct LocationTables<'a> {
+    resolved_stacks: &'a ResolvedStacks,
+    string_table: StringTable,
+    location_for_address: HashMap<u64, u64>,
+    function_for_key: HashMap<(usize, usize), u64>,
+    mapping_for_lib_index: BTreeMap<usize, Mapping>,
+    locations: Vec<Location>,
+    functions: Vec<Function>,
+}
+
+impl<'a> LocationTables<'a> {
+    pub fn new(resolved_stacks: &'a ResolvedStacks, string_table: StringTable) -> LocationTables<'a> {
+        LocationTables {
+            resolved_stacks,
+            string_table,
+            location_for_address: HashMap::new(),
+            function_for_key: HashMap::new(),
+            mapping_for_lib_index: BTreeMap::new(),
+            locations: Vec::new(),
+            functions: Vec::new(),
+        }
+    }
+
+    fn function(&mut self, name: &str, file_name: &str) -> u64 {
+        let name = self.string_table.index_for_string(name);
+        let file_name = if file_name == "??" {
+            0
+        } else {
+            self.string_table.index_for_string(file_name)
+        };
+        if let Some(&function) = self.function_for_key.get(&(name, file_name)) {
+            return function;
+        }
+        let function = self.functions.len() as u64 + 1;
+        self.functions.push(Function {
+            id: function,
+            name,
+            filename: file_name,
+        });
+        self.function_for_key.insert((name, file_name), function);
+        function
+    }
+
+    fn mapping(&mut self, address: u64, is_symbolicated: bool) -> u64 {
+        let libs = match self.resolved_stacks.stack_table.libs {
+            Some(ref libs) => libs,
+            None => return 0,
+        };
+        let lib_index = match libs.lib_index_for_address(address) {
+            Some(lib_index) => lib_index,
+            None => return 0,
+        };
+        let string_table = &mut self.string_table;
+        let mapping = self.mapping_for_lib_index.entry(lib_index).or_insert_with(|| {
+            let lib = libs.lib_at_index(lib_index);
+            Mapping {
+                id: lib_index as u64 + 1,
+                memory_start: lib.start,
+                memory_limit: lib.end,
+                file_offset: lib.offset,
+                filename: string_table.index_for_string(&lib.path),
+                build_id: string_table.index_for_string(&lib.breakpad_id),
+                is_symbolicated: true,
+            }
+        });
+        mapping.is_symbolicated &= is_symbolicated;
+        mapping.id
+    }
+
+    /// Returns the location for the given frames, which are the frames of
+    /// one address, outermost first.
+    fn location(&mut self, frames: &[usize]) -> u64 {
+        let address = self.resolved_stacks.stack_table.frames[frames[0]].0;
+        if let Some(&location) = self.location_for_address.get(&address) {
+            return location;
+        }
+        let mut lines = Vec::new();
+        for &frame in frames.iter().rev() {
+            if let Some(frame_info) = self.resolved_stacks.frame_info(frame) {
+                let function = self.function(&frame_info.function_name, &frame_info.file_path_str);
+                lines.push((function, frame_info.line_number));
+            }
+        }
+        let mapping_id = self.mapping(address, !lines.is_empty());
+        let location = self.locations.len() as u64 + 1;
+        self.locations.push(Location {
+            id: location,
+            mapping_id,
+            address,
+            lines,
+        });
+        self.location_for_address.insert(address, location);
+        location
+    }
+
+    /// Returns the locations of the given stack, innermost first.
+    pub fn locations_for_stack(&mut self, stack: usize) -> Vec<u64> {
+        let frames = self.resolved_stacks.frames_for_stack(stack);
+        // Inlined functions come right after the function that they were
+        // inlined into, and have the same address.
+        let mut frames_per_address: Vec<Vec<usize>> = Vec::new();
+        for frame in frames {
+            match frames_per_address.last_mut() {
+                Some(ref mut address_frames) if self.resolved_stacks.inline_depths[frame] > 0 => {
+                    address_frames.push(frame)
+                }
+                _ => frames_per_address.push(vec![frame]),
+            }
+        }
+        frames_per_address
+            .iter()
+            .rev()
+            .map(|address_frames| self.location(address_frames))
+            .collect()
+    }
+}
+
+impl PprofProfile {
+    pub fn from_profile_builder(profile_builder: &ProfileBuilder) -> PprofProfile {
+        let resolved_stacks = profile_builder.resolve_stacks();
+        let mut string_table = StringTable::new();
+        // The string at index 0 must be empty.
+        string_table.index_for_string("");
+        let mut sample_types = Vec::new();
+        for track_name in profile_builder.track_names() {
+            sample_types.push(ValueType {
+                type_: string_table.index_for_string(&track_identifier(track_name)),
+                unit: string_table.index_for_string("bytes"),
+            });
+        }
+        sample_types.push(ValueType {
+            type_: string_table.index_for_string("miss_count"),
+            unit: string_table.index_for_string("count"),
+        });
+        let category_key = string_table.index_for_string("category");
+
+        // pprof adds up samples with the same locations and labels anyway,
+        // so only write one sample per stack and category.
+        let mut values_per_stack_and_category: BTreeMap<(usize, usize), Vec<i64>> = BTreeMap::new();
+        for (sample, &stack) in profile_builder.samples().iter().zip(&resolved_stacks.sample_stacks) {
+            let values = values_per_stack_and_category
+                .entry((stack, sample.category))
+                .or_insert_with(|| vec![0; sample_types.len()]);
+            for (value, &weight) in values.iter_mut().zip(sample.weights.iter().chain(Some(&sample.count))) {
+                *value += weight as i64;
+            }
+        }
+
+        let mut tables = LocationTables::new(&resolved_stacks, string_table);
+        let mut samples = Vec::new();
+        for ((stack, category), values) in values_per_stack_and_category {
+            let labels = if category == 0 {
+                Vec::new()
+            } else {
+                vec![(category_key, tables.string_table.index_for_string(profile_builder.category_name(category)))]
+            };
+            samples.push(Sample {
+                location_ids: tables.locations_for_stack(stack),
+                values,
+                labels,
+            });
+        }
+        PprofProfile {
+            default_sample_type: sample_types[0].type_,
+            sample_types,
+            samples,
+            mappings: tables.mapping_for_lib_index.into_values().collect(),
+            locations: tables.locations,
+            functions: tables.functions,
+            string_table: tables.string_table.into_strings(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = ProtobufWriter::new();
+        for sample_type in &self.sample_types {
+            writer.message(1, &sample_type.encode());
+        }
+        for sample in &self.samples {
+            writer.message(2, &sample.encode());
+        }
+        for mapping in &self.mappings {
+            writer.message(3, &mapping.encode());
+        }
+        for location in &self.locations {
+            writer.message(4, &location.encode());
+        }
+        for function in &self.functions {
+            writer.message(5, &function.encode());
+        }
+        for string in &self.string_table {
+            writer.bytes(6, string.as_bytes());
+        }
+        writer.int64(14, self.default_sample_type as i64);
+        writer.into_bytes()
+    }
+}
+
+pub fn save_pprof_profile(profile_builder: &ProfileBuilder, path: &Path) -> Result<(), io::Error> {
+    let profile = PprofProfile::from_profile_builder(profile_builder);
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default());
+    encoder.write_all(&profile.encode())?;
+    encoder.finish()?.flush()
+}
+
+#[test]
+fn test_pprof_profile() {
+    // Stack 0 is the root, stack 1 is a child of it, and stack 2 a child of
+    // stack 1.
+    let stack_table = ::profile::test_stack_table(&[0, 0, 1]);
+    let mut profile_builder = ProfileBuilder::new(stack_table, 1.0, &["Read bytes", "Wasted bytes"]);
+    let arena = profile_builder.category("nsPresArena");
+    for (i, &(stack, wasted_bytes)) in [(2, 10), (1, 0), (2, 20), (2, 64)].iter().enumerate() {
+        let category = if i == 3 { arena } else { 0 };
+        profile_builder.add_sample(stack, i as f64, category, &[64, wasted_bytes]);
+    }
+    let profile = PprofProfile::from_profile_builder(&profile_builder);
+    let string = |index: usize| profile.string_table[index].as_str();
+    assert_eq!(string(0), "");
+    let sample_types: Vec<(&str, &str)> = profile
+        .sample_types
+        .iter()
+        .map(|sample_type| (string(sample_type.type_), string(sample_type.unit)))
+        .collect();
+    assert_eq!(
+        sample_types,
+        vec![("read_bytes", "bytes"), ("wasted_bytes", "bytes"), ("miss_count", "count")]
+    );
+    assert_eq!(string(profile.default_sample_type), "read_bytes");
+
+    // The two samples at stack 2 without a category were added up.
+    assert_eq!(profile.samples.len(), 3);
+    // Locations get their ids innermost first, so the root's is 2.
+    assert_eq!(profile.samples[0].location_ids, vec![1, 2]);
+    assert_eq!(profile.samples[0].values, vec![64, 0, 1]);
+    assert_eq!(profile.samples[1].location_ids, vec![3, 1, 2]);
+    assert_eq!(profile.samples[1].values, vec![128, 30, 2]);
+    assert!(profile.samples[1].labels.is_empty());
+    assert_eq!(profile.samples[2].location_ids, vec![3, 1, 2]);
+    let (key, value) = profile.samples[2].labels[0];
+    assert_eq!((string(key), string(value)), ("category", "nsPresArena"));
+
+    let addresses: Vec<(u64, u64)> = profile
+        .locations
+        .iter()
+        .map(|location| (location.address, location.mapping_id))
+        .collect();
+    assert_eq!(addresses, vec![(0x1010, 1), (0x1000, 1), (0x1020, 0)]);
+    assert_eq!(profile.mappings.len(), 1);
+    assert_eq!(string(profile.mappings[0].build_id), "ABCD");
+    // The library has no debug information, so pprof should try.
+    assert!(!profile.mappings[0].is_symbolicated);
+    assert!(profile.functions.is_empty());
+}
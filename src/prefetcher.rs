@@ -0,0 +1,177 @@
+use cpucache::CacheAccess;
+
+/// Decides which cache lines to read into the cache before they're
+/// accessed. The cache tells the prefetcher about every demand access, with
+/// the address of the accessed line, and prefetches the lines it asks for.
+pub trait Prefetcher {
+    fn on_access(&mut self, line: u64, access: &CacheAccess, prefetches: &mut Vec<u64>);
+}
+
+/// The prefetchers that the cache simulation can use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PrefetcherKind {
+    None,
+    /// Prefetches the next line on a miss and on the first hit on a
+    /// prefetched line, so a sequential stream stays one line ahead.
+    NextLine,
+    /// Prefetches the other line of the aligned pair of lines on a miss,
+    /// like the spatial prefetcher of Intel's L2.
+    AdjacentPair,
+    /// Prefetches the next line of a stream that advances by the same
+    /// stride twice in a row within a page.
+    Stride,
+}
+
+impl PrefetcherKind {
+    pub fn parse(s: &str) -> Option<PrefetcherKind> {
+        match s {
+            "none" => Some(PrefetcherKind::None),
+            "next-line" => Some(PrefetcherKind::NextLine),
+            "adjacent-pair" => Some(PrefetcherKind::AdjacentPair),
+            "stride" => Some(PrefetcherKind::Stride),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PrefetcherKind::None => "none",
+            PrefetcherKind::NextLine => "next-line",
+            PrefetcherKind::AdjacentPair => "adjacent-pair",
+            PrefetcherKind::Stride => "stride",
+        }
+    }
+
+    pub fn create(&self, line_size: u32) -> Option<Box<dyn Prefetcher>> {
+        let line_size = line_size as u64;
+        match *self {
+            PrefetcherKind::None => None,
+            PrefetcherKind::NextLine => Some(Box::new(NextLine { line_size })),
+            PrefetcherKind::AdjacentPair => Some(Box::new(AdjacentPair { line_size })),
+            PrefetcherKind::Stride => Some(Box::new(Stride::new())),
+        }
+    }
+}
+
+pub struct NextLine {
+    line_size: u64,
+}
+
+impl Prefetcher for NextLine {
+    fn on_access(&mut self, line: u64, access: &CacheAccess, prefetches: &mut Vec<u64>) {
+        match *access {
+            CacheAccess::Miss { .. } | CacheAccess::PrefetchHit => prefetches.push(line + self.line_size),
+            CacheAccess::Hit => {}
+        }
+    }
+}
+
+pub struct AdjacentPair {
+    line_size: u64,
+}
+
+impl Prefetcher for AdjacentPair {
+    fn on_access(&mut self, line: u64, access: &CacheAccess, prefetches: &mut Vec<u64>) {
+        if let CacheAccess::Miss { .. } = *access {
+            prefetches.push(line ^ self.line_size);
+        }
+    }
+}
+
+const STRIDE_TABLE_SIZE: usize = 16;
+const PAGE_SIZE_BITS: u32 = 12;
+
+#[derive(Clone, Copy)]
+struct StrideEntry {
+    page: u64,
+    last_line: u64,
+    stride: i64,
+}
+
+/// Tracks one stream per 4 KB page, in a small direct-mapped table like
+/// the hardware ones, and doesn't prefetch across page boundaries.
+pub struct Stride {
+    entries: Vec<Option<StrideEntry>>,
+}
+
+impl Stride {
+    pub fn new() -> Stride {
+        Stride {
+            entries: vec![None; STRIDE_TABLE_SIZE],
+        }
+    }
+}
+
+impl Default for Stride {
+    fn default() -> Stride {
+        Stride::new()
+    }
+}
+
+impl Prefetcher for Stride {
+    fn on_access(&mut self, line: u64, _access: &CacheAccess, prefetches: &mut Vec<u64>) {
+        let page = line >> PAGE_SIZE_BITS;
+        let slot = &mut self.entries[(page % STRIDE_TABLE_SIZE as u64) as usize];
+        match *slot {
+            Some(ref mut entry) if entry.page == page => {
+                let stride = line as i64 - entry.last_line as i64;
+                if stride == 0 {
+                    return;
+                }
+                if stride == entry.stride {
+                    let next = (line as i64 + stride) as u64;
+                    if next >> PAGE_SIZE_BITS == page {
+                        prefetches.push(next);
+                    }
+                }
+                entry.stride = stride;
+                entry.last_line = line;
+            }
+            _ => {
+                *slot = Some(StrideEntry {
+                    page,
+                    last_line: line,
+                    stride: 0,
+                })
+            }
+        }
+    }
+}
+
+#[test]
+fn test_prefetchers() {
+    let miss = CacheAccess::Miss { evicted: None };
+    let prefetches_for = |kind: PrefetcherKind, accesses: &[(u64, &CacheAccess)]| {
+        let mut prefetcher = kind.create(64).unwrap();
+        let mut prefetches = Vec::new();
+        for &(line, access) in accesses {
+            prefetcher.on_access(line, access, &mut prefetches);
+        }
+        prefetches
+    };
+    assert_eq!(
+        prefetches_for(PrefetcherKind::NextLine, &[(0x1000, &miss), (0x1040, &CacheAccess::PrefetchHit), (0x1040, &CacheAccess::Hit)]),
+        vec![0x1040, 0x1080]
+    );
+    assert_eq!(
+        prefetches_for(PrefetcherKind::AdjacentPair, &[(0x1000, &miss), (0x10c0, &miss), (0x1100, &CacheAccess::Hit)]),
+        vec![0x1040, 0x1080]
+    );
+    // The stride of 0x100 needs to be seen twice, and the stream stops at
+    // the end of the page. Other pages don't interfere.
+    let accesses = [
+        (0x1d00, &miss),
+        (0x5000, &miss),
+        (0x1e00, &miss),
+        (0x1f00, &CacheAccess::Hit),
+        (0x2000, &miss),
+        (0x1fc0, &miss),
+    ];
+    assert_eq!(prefetches_for(PrefetcherKind::Stride, &accesses), Vec::<u64>::new());
+    let accesses = [(0x1c00, &miss), (0x1d00, &miss), (0x5000, &miss), (0x1e00, &miss), (0x1f00, &miss)];
+    assert_eq!(prefetches_for(PrefetcherKind::Stride, &accesses), vec![0x1f00]);
+
+    assert_eq!(PrefetcherKind::parse("adjacent-pair"), Some(PrefetcherKind::AdjacentPair));
+    assert!(PrefetcherKind::None.create(64).is_none());
+}
@@ -1,155 +1,798 @@
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap};
 use stack_table::{StackTable, StackEntry};
+use shared_libraries::SharedLibrary;
 use addr2line_cmd::{StackFrameInfo};
-use serde_json::{Value, to_writer};
+use serde_json::{self, Value, to_writer};
 use std::io;
 use std::fs::File;
+use std::path::{Path, PathBuf};
+use collapsed_stacks::save_collapsed_stacks;
+use callgrind::save_callgrind_profile;
+use pprof::save_pprof_profile;
 
+/// The processed profile format version that save_to_file writes. The
+/// profiler upgrades older versions when it loads them.
+const PROCESSED_PROFILE_VERSION: u32 = 47;
+const GECKO_PROFILE_VERSION: u32 = 27;
+
+#[derive(Default)]
+pub struct StringTable {
+    strings: Vec<String>,
+    index_for_string: HashMap<String, usize>,
+}
+
+impl StringTable {
+    pub fn new() -> StringTable {
+        StringTable {
+            strings: Vec::new(),
+            index_for_string: HashMap::new(),
+        }
+    }
+
+    pub fn index_for_string(&mut self, s: &str) -> usize {
+        if let Some(index) = self.index_for_string.get(s) {
+            return *index;
+        }
+        let index = self.strings.len();
+        self.strings.push(s.to_owned());
+        self.index_for_string.insert(s.to_owned(), index);
+        index
+    }
+
+    pub fn into_strings(self) -> Vec<String> {
+        self.strings
+    }
+}
+
+/// The frame, func, resource and native symbol tables of the processed
+/// format. Functions are shared between frames with the same name, file and
+/// library, and only the libraries that frames point into end up in libs.
+struct FrameTables {
+    libs: Vec<Value>,
+    resource_for_lib_index: HashMap<usize, usize>,
+    func_for_key: HashMap<(Option<usize>, usize, Option<usize>), usize>,
+    native_symbol_for_key: HashMap<(usize, usize), usize>,
+
+    frame_address: Vec<i64>,
+    frame_inline_depth: Vec<usize>,
+    frame_func: Vec<usize>,
+    frame_native_symbol: Vec<Option<usize>>,
+    frame_line: Vec<Option<usize>>,
+
+    func_name: Vec<usize>,
+    func_resource: Vec<i64>,
+    func_file_name: Vec<Option<usize>>,
+
+    resource_lib: Vec<usize>,
+    resource_name: Vec<usize>,
+
+    native_symbol_lib: Vec<usize>,
+    native_symbol_address: Vec<u64>,
+    native_symbol_name: Vec<usize>,
+}
+
+impl FrameTables {
+    pub fn new() -> FrameTables {
+        FrameTables {
+            libs: Vec::new(),
+            resource_for_lib_index: HashMap::new(),
+            func_for_key: HashMap::new(),
+            native_symbol_for_key: HashMap::new(),
+            frame_address: Vec::new(),
+            frame_inline_depth: Vec::new(),
+            frame_func: Vec::new(),
+            frame_native_symbol: Vec::new(),
+            frame_line: Vec::new(),
+            func_name: Vec::new(),
+            func_resource: Vec::new(),
+            func_file_name: Vec::new(),
+            resource_lib: Vec::new(),
+            resource_name: Vec::new(),
+            native_symbol_lib: Vec::new(),
+            native_symbol_address: Vec::new(),
+            native_symbol_name: Vec::new(),
+        }
+    }
+
+    /// Returns the resource for the library with the given index in
+    /// SharedLibraries, adding the library to libs if necessary.
+    pub fn resource_for_lib(&mut self, lib_index: usize, lib: &SharedLibrary, string_table: &mut StringTable) -> usize {
+        if let Some(resource) = self.resource_for_lib_index.get(&lib_index) {
+            return *resource;
+        }
+        let resource = self.resource_lib.len();
+        self.resource_lib.push(self.libs.len());
+        self.resource_name.push(string_table.index_for_string(&lib.name));
+        let mut lib = serde_json::to_value(lib).expect("serializing a library failed");
+        lib["codeId"] = Value::Null;
+        self.libs.push(lib);
+        self.resource_for_lib_index.insert(lib_index, resource);
+        resource
+    }
+
+    pub fn func(&mut self, resource: Option<usize>, name: &str, file_name: Option<&str>, string_table: &mut StringTable) -> usize {
+        let name = string_table.index_for_string(name);
+        let file_name = file_name
+            .filter(|file_name| *file_name != "??")
+            .map(|file_name| string_table.index_for_string(file_name));
+        let key = (resource, name, file_name);
+        if let Some(func) = self.func_for_key.get(&key) {
+            return *func;
+        }
+        let func = self.func_name.len();
+        self.func_name.push(name);
+        self.func_resource.push(resource.map_or(-1, |resource| resource as i64));
+        self.func_file_name.push(file_name);
+        self.func_for_key.insert(key, func);
+        func
+    }
+
+    /// Returns the native symbol with the given name in the library of the
+    /// given resource. The log doesn't say where functions start, so the
+    /// symbol's address is the lowest address at which we've seen it.
+    pub fn native_symbol(&mut self, resource: usize, name: &str, relative_address: u64, string_table: &mut StringTable) -> usize {
+        let name = string_table.index_for_string(name);
+        let lib = self.resource_lib[resource];
+        if let Some(&native_symbol) = self.native_symbol_for_key.get(&(lib, name)) {
+            let address = &mut self.native_symbol_address[native_symbol];
+            *address = (*address).min(relative_address);
+            return native_symbol;
+        }
+        let native_symbol = self.native_symbol_lib.len();
+        self.native_symbol_lib.push(lib);
+        self.native_symbol_address.push(relative_address);
+        self.native_symbol_name.push(name);
+        self.native_symbol_for_key.insert((lib, name), native_symbol);
+        native_symbol
+    }
+
+    pub fn add_frame(
+        &mut self,
+        relative_address: Option<u64>,
+        inline_depth: usize,
+        func: usize,
+        native_symbol: Option<usize>,
+        frame_info: Option<&StackFrameInfo>,
+    ) {
+        self.frame_address.push(relative_address.map_or(-1, |address| address as i64));
+        self.frame_inline_depth.push(inline_depth);
+        self.frame_func.push(func);
+        self.frame_native_symbol.push(native_symbol);
+        self.frame_line.push(frame_info.map(|info| info.line_number).filter(|&line| line != 0));
+    }
+
+    /// Returns libs and the frame, func, resource and native symbol tables.
+    pub fn into_json(self) -> (Value, Value, Value, Value, Value) {
+        let frame_count = self.frame_func.len();
+        let func_count = self.func_name.len();
+        let resource_count = self.resource_lib.len();
+        let native_symbol_count = self.native_symbol_lib.len();
+        (
+            Value::Array(self.libs),
+            json!({
+                "address": self.frame_address,
+                "inlineDepth": self.frame_inline_depth,
+                "category": vec![0; frame_count],
+                "subcategory": vec![0; frame_count],
+                "func": self.frame_func,
+                "nativeSymbol": self.frame_native_symbol,
+                "innerWindowID": vec![0; frame_count],
+                "implementation": vec![Value::Null; frame_count],
+                "line": self.frame_line,
+                "column": vec![Value::Null; frame_count],
+                "length": frame_count
+            }),
+            json!({
+                "name": self.func_name,
+                "isJS": vec![false; func_count],
+                "relevantForJS": vec![false; func_count],
+                "resource": self.func_resource,
+                "fileName": self.func_file_name,
+                "lineNumber": vec![Value::Null; func_count],
+                "columnNumber": vec![Value::Null; func_count],
+                "length": func_count
+            }),
+            json!({
+                "lib": self.resource_lib,
+                "name": self.resource_name,
+                "host": vec![Value::Null; resource_count],
+                // 1 means "library".
+                "type": vec![1; resource_count],
+                "length": resource_count
+            }),
+            json!({
+                "libIndex": self.native_symbol_lib,
+                "address": self.native_symbol_address,
+                "name": self.native_symbol_name,
+                "functionSize": vec![Value::Null; native_symbol_count],
+                "length": native_symbol_count
+            }),
+        )
+    }
+}
+
+/// The colors that categories cycle through, in the order in which they're
+/// added. The "Other" category is always grey.
+const CATEGORY_COLORS: &[&str] = &[
+    "blue", "green", "orange", "purple", "yellow", "red", "lightblue", "brown", "magenta",
+];
+
+pub struct Sample {
+    pub stack: usize,
+    pub time: f64,
+    pub category: usize,
+    /// One weight per track.
+    pub weights: Vec<u64>,
+    /// How many add_sample calls were merged into this sample.
+    pub count: u64,
+}
+
+/// An interval marker, e.g. for a section.
+struct Marker {
+    name: String,
+    start_time: f64,
+    end_time: f64,
+}
+
+/// The file formats that a ProfileBuilder can save.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProfileFormat {
+    /// The Firefox profiler's processed format, with one thread per track.
+    Firefox,
+    /// The folded stack format of flamegraph.pl, with one file per track.
+    Collapsed,
+    /// The format of callgrind, for KCachegrind, with one event per track
+    /// and a Misses event for the number of samples.
+    Callgrind,
+    /// A gzipped profile.proto file for pprof, with one sample type per
+    /// track and a miss_count sample type for the number of samples.
+    Pprof,
+}
+
+impl ProfileFormat {
+    pub fn parse(s: &str) -> Option<ProfileFormat> {
+        match s {
+            "firefox" => Some(ProfileFormat::Firefox),
+            "collapsed" => Some(ProfileFormat::Collapsed),
+            "callgrind" => Some(ProfileFormat::Callgrind),
+            "pprof" => Some(ProfileFormat::Pprof),
+            _ => None,
+        }
+    }
+}
+
+/// Returns <file_prefix>_<file_name>, or <file_name> if there's no prefix.
+pub fn prefixed_file_name(file_prefix: &str, file_name: &str) -> String {
+    if file_prefix.is_empty() {
+        file_name.to_owned()
+    } else {
+        format!("{}_{}", file_prefix, file_name)
+    }
+}
+
+pub fn output_path(output_dir: &Path, file_prefix: &str, file_name: &str) -> PathBuf {
+    output_dir.join(prefixed_file_name(file_prefix, file_name))
+}
+
+/// Turns a track name like "Read bytes" into "read_bytes", for file names
+/// and the like.
+pub fn track_identifier(track_name: &str) -> String {
+    track_name.to_lowercase().replace(' ', "_")
+}
+
+/// The stack table of a profile after symbolication, with one frame per
+/// inlined function, and the stack of every sample in it.
+pub struct ResolvedStacks {
+    pub stack_table: StackTable,
+    /// The inline depth of each frame, 0 for the outermost function at an
+    /// address.
+    pub inline_depths: Vec<usize>,
+    /// The stack of each sample, in the same order as the samples.
+    pub sample_stacks: Vec<usize>,
+    /// The name of the outermost function at each symbolicated address.
+    symbol_name_for_address: HashMap<u64, String>,
+}
+
+impl ResolvedStacks {
+    /// The function of the given frame, or None if it couldn't be
+    /// symbolicated.
+    pub fn frame_info(&self, frame: usize) -> Option<&StackFrameInfo> {
+        self.stack_table.frames[frame]
+            .1
+            .as_ref()
+            .and_then(|infos| infos.first())
+            .filter(|info| info.function_name != "??")
+    }
+
+    /// The name of the outermost function at the given address, which all
+    /// frames that were inlined there share.
+    pub fn symbol_name(&self, address: u64) -> Option<&str> {
+        self.symbol_name_for_address.get(&address).map(|name| name.as_str())
+    }
+
+    /// The function name of the given frame, or its address if it couldn't
+    /// be symbolicated, e.g. "libxul.so+0x1a2b" for an address in a library.
+    pub fn frame_name(&self, frame: usize) -> String {
+        if let Some(info) = self.frame_info(frame) {
+            return info.function_name.clone();
+        }
+        let address = self.stack_table.frames[frame].0;
+        match self.stack_table.libs.as_ref().and_then(|libs| libs.lib_for_address(address)) {
+            Some(lib) => format!("{}+0x{:x}", lib.name, address - lib.start),
+            None => format!("0x{:x}", address),
+        }
+    }
+
+    /// The frames of the given stack, outermost first. As in the processed
+    /// profile, this includes the frame of the root stack 0.
+    pub fn frames_for_stack(&self, stack: usize) -> Vec<usize> {
+        let mut frames = Vec::new();
+        let mut stack = stack;
+        loop {
+            let StackEntry { frame, parent_stack } = self.stack_table.stacks[stack];
+            frames.push(frame);
+            if stack == 0 {
+                break;
+            }
+            stack = parent_stack;
+        }
+        frames.reverse();
+        frames
+    }
+}
+
+/// Builds a profile in the Firefox profiler's processed format. Every sample
+/// has one weight per track, e.g. the bytes read, used and wasted at its
+/// stack, and every track becomes a thread whose samples are weighted by
+/// bytes, so that the call tree shows exact byte counts. Samples can have a
+/// category, which the activity graph uses for their color, and all tracks
+/// get the same markers. The same samples always result in the same file.
 pub struct ProfileBuilder {
     stack_table: StackTable,
-    samples: Vec<(usize, f64)>,
-    used_stacks: HashSet<usize>,
+    track_names: Vec<String>,
+    category_names: Vec<String>,
+    samples: Vec<Sample>,
+    markers: Vec<Marker>,
+    used_stacks: BTreeSet<usize>,
     interval: f64,
+    symbolicate: bool,
 }
 
 impl ProfileBuilder {
-    pub fn new(stack_table: StackTable, interval: f64) -> ProfileBuilder {
+    pub fn new(stack_table: StackTable, interval: f64, track_names: &[&str]) -> ProfileBuilder {
         ProfileBuilder {
             stack_table,
+            track_names: track_names.iter().map(|name| name.to_string()).collect(),
+            category_names: vec!["Other".to_owned()],
             samples: Vec::new(),
-            used_stacks: HashSet::new(),
+            markers: Vec::new(),
+            used_stacks: BTreeSet::new(),
             interval,
+            symbolicate: true,
+        }
+    }
+
+    /// Whether to look up function names before saving, true by default.
+    /// Without symbolication, the Firefox profiler looks them up itself, e.g.
+    /// from the symbol server that the serve subcommand runs. The other
+    /// formats just get addresses then.
+    pub fn set_symbolicate(&mut self, symbolicate: bool) {
+        self.symbolicate = symbolicate;
+    }
+
+    pub fn stack_table(&self) -> &StackTable {
+        &self.stack_table
+    }
+
+    /// Returns the category with the given name, adding it if necessary.
+    /// Category 0 is "Other", the default for samples.
+    pub fn category(&mut self, name: &str) -> usize {
+        if let Some(category) = self.category_names.iter().position(|n| n == name) {
+            return category;
         }
+        self.category_names.push(name.to_owned());
+        self.category_names.len() - 1
     }
 
-    pub fn add_sample(&mut self, stack: usize, time: f64) {
-        self.samples.push((stack, time));
+    /// Adds a sample with one weight per track. Tracks for which the weight
+    /// is zero don't get the sample. A sample with the same stack and category
+    /// as the previous one is merged into it by adding up the weights, and
+    /// keeps the time of the previous one.
+    pub fn add_sample(&mut self, stack: usize, time: f64, category: usize, weights: &[u64]) {
+        assert_eq!(weights.len(), self.track_names.len(), "need one weight per track");
+        assert!(category < self.category_names.len(), "unknown category");
+        if let Some(last_sample) = self.samples.last_mut() {
+            if last_sample.stack == stack && last_sample.category == category {
+                for (last_weight, weight) in last_sample.weights.iter_mut().zip(weights) {
+                    *last_weight += *weight;
+                }
+                last_sample.count += 1;
+                return;
+            }
+        }
+        self.samples.push(Sample {
+            stack,
+            time,
+            category,
+            weights: weights.to_vec(),
+            count: 1,
+        });
         self.used_stacks.insert(stack);
     }
 
-    pub fn save_to_file(&mut self, filename: &str) -> Result<(), io::Error> {
+    pub fn add_marker(&mut self, name: &str, start_time: f64, end_time: f64) {
+        self.markers.push(Marker {
+            name: name.to_owned(),
+            start_time,
+            end_time,
+        });
+    }
+
+    pub fn category_name(&self, category: usize) -> &str {
+        &self.category_names[category]
+    }
+
+    pub fn track_names(&self) -> &[String] {
+        &self.track_names
+    }
+
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    /// Saves the profile in the given format, as
+    /// <output_dir>/<file_prefix>_<profile_name>_profile.json for the Firefox
+    /// profiler, as one <file_prefix>_<profile_name>_<track>.folded file per
+    /// track, e.g. "cache_line_usage_read_bytes.folded", or as
+    /// callgrind.out.<file_prefix>_<profile_name>, a name that KCachegrind
+    /// recognizes, or as <file_prefix>_<profile_name>.pb.gz for pprof.
+    /// Returns the paths of the written files.
+    pub fn save(
+        &self,
+        format: ProfileFormat,
+        output_dir: &Path,
+        file_prefix: &str,
+        profile_name: &str,
+    ) -> Result<Vec<PathBuf>, io::Error> {
+        match format {
+            ProfileFormat::Firefox => {
+                let path = output_path(output_dir, file_prefix, &format!("{}_profile.json", profile_name));
+                self.save_to_file(&path)?;
+                Ok(vec![path])
+            }
+            ProfileFormat::Collapsed => {
+                let paths: Vec<PathBuf> = self.track_names
+                    .iter()
+                    .map(|track_name| {
+                        let file_name = format!("{}_{}.folded", profile_name, track_identifier(track_name));
+                        output_path(output_dir, file_prefix, &file_name)
+                    })
+                    .collect();
+                save_collapsed_stacks(self, &paths)?;
+                Ok(paths)
+            }
+            ProfileFormat::Callgrind => {
+                let path = output_dir.join(format!("callgrind.out.{}", prefixed_file_name(file_prefix, profile_name)));
+                save_callgrind_profile(self, &path)?;
+                Ok(vec![path])
+            }
+            ProfileFormat::Pprof => {
+                let path = output_path(output_dir, file_prefix, &format!("{}.pb.gz", profile_name));
+                save_pprof_profile(self, &path)?;
+                Ok(vec![path])
+            }
+        }
+    }
+
+    pub fn save_to_file(&self, path: &Path) -> Result<(), io::Error> {
+        let profile = self.to_json();
+        let file = File::create(path)?;
+        to_writer(file, &profile)?;
+        Ok(())
+    }
+
+    /// Symbolicates the stacks of the samples and gives inlined functions
+    /// their own frames.
+    pub fn resolve_stacks(&self) -> ResolvedStacks {
         println!("Have {} samples.", self.samples.len());
         let (mut stack_table, old_stack_to_new_stack) =
             self.stack_table.create_reduced_table_containing_stacks(
                 &self.used_stacks,
             );
-        println!("Symbolicating...");
-        stack_table.symbolicate_all();
-        println!("Done symbolicating.");
-        let non_inline_to_inline_stack = stack_table.resolve_inline_symbols();
-        let frame_table_data: Vec<Value> = stack_table
-            .frames
+        if self.symbolicate {
+            println!("Symbolicating...");
+            stack_table.symbolicate_all();
+            println!("Done symbolicating.");
+        }
+
+        // Inlined functions share the native symbol of the outermost function
+        // at their address, so look those up before the frames are split.
+        let mut symbol_name_for_address: HashMap<u64, String> = HashMap::new();
+        for &(address, ref frame_infos) in &stack_table.frames {
+            if let Some(frame_info) = frame_infos.as_ref().and_then(|infos| infos.first()) {
+                if frame_info.function_name != "??" {
+                    symbol_name_for_address.insert(address, frame_info.function_name.clone());
+                }
+            }
+        }
+        let (non_inline_to_inline_stack, inline_depths) = stack_table.resolve_inline_symbols();
+        let sample_stacks = self.samples
             .iter()
-            .enumerate()
-            .map(|(frame, _)| json!([frame]))
+            .map(|sample| {
+                non_inline_to_inline_stack[*old_stack_to_new_stack.get(&sample.stack).expect(
+                    "Found untranslated stack",
+                )]
+            })
             .collect();
-        let stack_table_data: Vec<Value> = stack_table
+        ResolvedStacks {
+            stack_table,
+            inline_depths,
+            sample_stacks,
+            symbol_name_for_address,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let resolved_stacks = self.resolve_stacks();
+        let stack_table = &resolved_stacks.stack_table;
+
+        let mut string_table = StringTable::new();
+        let mut tables = FrameTables::new();
+        let mut is_symbolicated = true;
+        for (frame, &(address, _)) in stack_table.frames.iter().enumerate() {
+            let lib = stack_table.libs.as_ref().and_then(|libs| {
+                libs.lib_index_for_address(address).map(|index| (index, libs.lib_at_index(index)))
+            });
+            let frame_info = resolved_stacks.frame_info(frame);
+            match lib {
+                Some((lib_index, lib)) => {
+                    if frame_info.is_none() {
+                        is_symbolicated = false;
+                    }
+                    let relative_address = address - lib.start;
+                    let resource = tables.resource_for_lib(lib_index, lib, &mut string_table);
+                    let native_symbol = resolved_stacks.symbol_name(address).map(|name| {
+                        tables.native_symbol(resource, name, relative_address, &mut string_table)
+                    });
+                    let func = match frame_info {
+                        Some(info) => tables.func(Some(resource), &info.function_name, Some(&info.file_path_str), &mut string_table),
+                        None => tables.func(Some(resource), &format!("0x{:x}", relative_address), None, &mut string_table),
+                    };
+                    tables.add_frame(Some(relative_address), resolved_stacks.inline_depths[frame], func, native_symbol, frame_info);
+                }
+                None => {
+                    let func = tables.func(None, &format!("0x{:x}", address), None, &mut string_table);
+                    tables.add_frame(None, resolved_stacks.inline_depths[frame], func, None, frame_info);
+                }
+            }
+        }
+
+        // The stacks get the default category. A sample with a different
+        // category gets a copy of its stack with that category instead.
+        let mut stack_frames: Vec<usize> = stack_table.stacks.iter().map(|entry| entry.frame).collect();
+        let mut stack_prefixes: Vec<Option<usize>> = stack_table
             .stacks
             .iter()
             .enumerate()
-            .map(|(stack,
-              &StackEntry {
-                  parent_stack,
-                  frame,
-              })| {
+            .map(|(stack, &StackEntry { parent_stack, .. })| {
                 if stack == 0 && parent_stack == 0 {
-                    json!([null, frame])
+                    None
                 } else {
-                    json!([parent_stack, frame])
+                    Some(parent_stack)
                 }
             })
             .collect();
-        let samples_data: Vec<Value> = self.samples
+        let mut stack_categories = vec![0; stack_frames.len()];
+        let mut stack_for_category: HashMap<(usize, usize), usize> = HashMap::new();
+        let sample_stacks: Vec<usize> = self.samples
             .iter()
-            .enumerate()
-            .map(|(_, &(stack, time))| {
-                json!(
-                    [
-                        non_inline_to_inline_stack[*old_stack_to_new_stack.get(&stack).expect(
-                            "Found untranslated stack",
-                        )],
-                        time,
-                        0,
-                    ]
-                )
+            .zip(&resolved_stacks.sample_stacks)
+            .map(|(sample, &stack)| {
+                if sample.category == 0 {
+                    return stack;
+                }
+                *stack_for_category.entry((stack, sample.category)).or_insert_with(|| {
+                    stack_frames.push(stack_frames[stack]);
+                    stack_prefixes.push(stack_prefixes[stack]);
+                    stack_categories.push(sample.category);
+                    stack_frames.len() - 1
+                })
             })
             .collect();
-        let string_table: Vec<Value> = stack_table
-            .frames
+        let stack_count = stack_frames.len();
+        let stack_table_data = json!({
+            "frame": stack_frames,
+            "prefix": stack_prefixes,
+            "category": stack_categories,
+            "subcategory": vec![0; stack_count],
+            "length": stack_count
+        });
+
+        let marker_count = self.markers.len();
+        let markers = json!({
+            "data": vec![Value::Null; marker_count],
+            "name": self.markers
+                .iter()
+                .map(|marker| string_table.index_for_string(&marker.name))
+                .collect::<Vec<usize>>(),
+            "startTime": self.markers.iter().map(|marker| marker.start_time).collect::<Vec<f64>>(),
+            "endTime": self.markers.iter().map(|marker| marker.end_time).collect::<Vec<f64>>(),
+            // 1 means "interval".
+            "phase": vec![1; marker_count],
+            "category": vec![0; marker_count],
+            "length": marker_count
+        });
+
+        let (libs, frame_table, func_table, resource_table, native_symbols) = tables.into_json();
+        let string_array = string_table.into_strings();
+
+        let threads: Vec<Value> = self.track_names
             .iter()
             .enumerate()
-            .map(|(_, &(address, ref frame_info_vec))| {
-                if let &Some(ref frame_info_vec) = frame_info_vec {
-                    if !frame_info_vec.is_empty() {
-                        let &StackFrameInfo {
-                            ref function_name,
-                            ref file_path_str,
-                            line_number,
-                        } = &frame_info_vec[0];
-                        return Value::String(format!(
-                            "{} ({}:{})",
-                            function_name,
-                            file_path_str,
-                            line_number
-                        ));
+            .map(|(track, track_name)| {
+                let mut track_sample_stacks = Vec::new();
+                let mut sample_times = Vec::new();
+                let mut sample_weights = Vec::new();
+                for (sample, &stack) in self.samples.iter().zip(&sample_stacks) {
+                    if sample.weights[track] == 0 {
+                        continue;
                     }
+                    track_sample_stacks.push(stack);
+                    sample_times.push(sample.time);
+                    sample_weights.push(sample.weights[track]);
                 }
-                Value::String(format!("0x{:x}", address))
+                json!({
+                    "name": track_name,
+                    "processType": "default",
+                    "processName": "cache-log-parser",
+                    "processStartupTime": 0,
+                    "processShutdownTime": null,
+                    "registerTime": 0,
+                    "unregisterTime": null,
+                    "pausedRanges": [],
+                    "isMainThread": track == 0,
+                    "pid": "0",
+                    "tid": track,
+                    "samples": {
+                        "stack": track_sample_stacks,
+                        "time": sample_times,
+                        "weight": sample_weights,
+                        "weightType": "bytes",
+                        "length": sample_weights.len()
+                    },
+                    "markers": markers,
+                    "stackTable": stack_table_data,
+                    "frameTable": frame_table,
+                    "funcTable": func_table,
+                    "resourceTable": resource_table,
+                    "nativeSymbols": native_symbols,
+                    "stringArray": string_array
+                })
+            })
+            .collect();
 
+        let categories: Vec<Value> = self.category_names
+            .iter()
+            .enumerate()
+            .map(|(category, name)| {
+                let color = if category == 0 {
+                    "grey"
+                } else {
+                    CATEGORY_COLORS[(category - 1) % CATEGORY_COLORS.len()]
+                };
+                json!({
+                    "name": name,
+                    "color": color,
+                    "subcategories": ["Other"]
+                })
             })
             .collect();
-        let profile = json!({
+
+        json!({
             "meta": {
-                "version": 4,
+                "version": GECKO_PROFILE_VERSION,
+                "preprocessedProfileVersion": PROCESSED_PROFILE_VERSION,
+                "product": "cache-log-parser",
                 "processType": 0,
-                "interval": self.interval
+                "interval": self.interval,
+                "startTime": 0,
+                "stackwalk": 1,
+                "symbolicated": is_symbolicated,
+                "categories": categories,
+                "markerSchema": []
             },
-            "libs": [],
-            "threads": [
-                {
-                    "name": "All",
-                    "processType": "default",
-                    "frameTable": {
-                        "schema": {
-                            "location": 0,
-                            "implementation": 1,
-                            "optimizations": 2,
-                            "line": 3,
-                            "category": 4
-                        },
-                        "data": frame_table_data
-                    },
-                    "stackTable": {
-                        "schema": {
-                            "prefix": 0,
-                            "frame": 1,
-                        },
-                        "data": stack_table_data
-                    },
-                    "samples": {
-                        "schema": {
-                            "stack": 0,
-                            "time": 1,
-                            "responsiveness": 2,
-                            "rss": 3,
-                            "uss": 4
-                        },
-                        "data": samples_data
-                    },
-                    "markers": {
-                        "schema": {
-                            "name": 0,
-                            "time": 1,
-                            "data": 2
-                        },
-                        "data": []
-                    },
-                    "stringTable": string_table
-                }
-            ]
-        });
-        let file = File::create(filename)?;
-        to_writer(file, &profile).expect("Couldn't write JSON");
-        Ok(())
+            "libs": libs,
+            "threads": threads
+        })
+    }
+}
+
+/// A stack table for the tests of the profile formats. Stack i has frame i,
+/// at address 0x1000 + i * 0x10, and the parent stack parents[i]. Only the
+/// first two frames are in a library, libtest.so.
+#[cfg(test)]
+pub fn test_stack_table(parents: &[usize]) -> StackTable {
+    let mut stack_table = StackTable::new();
+    for (stack, &parent) in parents.iter().enumerate() {
+        stack_table.add_frame(stack, 0x1000 + stack as u64 * 0x10);
+        stack_table.add_stack(stack, parent, stack);
+    }
+    let libs = ::shared_libraries::SharedLibraries::from_json_string(
+        r#"[{"start": 4096, "end": 4128, "offset": 0, "name": "libtest.so", "path": "/nonexistent/libtest.so",
+             "debugName": "libtest.so", "debugPath": "/nonexistent/libtest.so", "breakpadId": "ABCD", "arch": ""}]"#
+            .to_owned(),
+    ).unwrap();
+    stack_table.set_libs(libs);
+    stack_table
+}
+
+#[cfg(test)]
+fn build_test_profile() -> String {
+    // Stack 0 is the root, stacks 1 to 3 have it as their parent.
+    let stack_table = test_stack_table(&[0, 0, 0, 0]);
+    let mut profile_builder = ProfileBuilder::new(stack_table, 0.0625, &["Read bytes", "Used bytes"]);
+    let arena = profile_builder.category("nsPresArena");
+    for (i, &stack) in [3, 1, 1, 2, 3, 2].iter().enumerate() {
+        let category = if i == 5 { arena } else { 0 };
+        profile_builder.add_sample(stack, i as f64 * 0.0625, category, &[64, i as u64 * 10]);
     }
+    profile_builder.add_marker("Paint", 0.0625, 0.25);
+    ::serde_json::to_string(&profile_builder.to_json()).unwrap()
+}
+
+#[test]
+fn test_profile_is_reproducible() {
+    assert_eq!(build_test_profile(), build_test_profile());
+}
+
+#[test]
+fn test_profile_sample_weights() {
+    let profile: Value = ::serde_json::from_str(&build_test_profile()).unwrap();
+    let read_bytes = &profile["threads"][0]["samples"];
+    // The two consecutive samples with stack 1 were merged.
+    assert_eq!(read_bytes["length"], json!(5));
+    assert_eq!(read_bytes["weight"], json!([64, 128, 64, 64, 64]));
+    assert_eq!(read_bytes["weightType"], json!("bytes"));
+    let used_bytes = &profile["threads"][1]["samples"];
+    // The first sample didn't use any bytes, so it's not on this track.
+    assert_eq!(used_bytes["weight"], json!([30, 30, 40, 50]));
+    assert_eq!(used_bytes["time"], json!([0.0625, 0.1875, 0.25, 0.3125]));
+}
+
+#[test]
+fn test_profile_categories_and_markers() {
+    let profile: Value = ::serde_json::from_str(&build_test_profile()).unwrap();
+    assert_eq!(profile["meta"]["categories"][0]["name"], json!("Other"));
+    assert_eq!(profile["meta"]["categories"][1]["name"], json!("nsPresArena"));
+    let thread = &profile["threads"][0];
+    // The last sample has stack 2 like the fourth one, but a different
+    // category, so it gets a copy of that stack.
+    assert_eq!(thread["samples"]["stack"], json!([3, 1, 2, 3, 4]));
+    assert_eq!(thread["stackTable"]["frame"][4], thread["stackTable"]["frame"][2]);
+    assert_eq!(thread["stackTable"]["category"], json!([0, 0, 0, 0, 1]));
+    let markers = &thread["markers"];
+    assert_eq!(markers["length"], json!(1));
+    assert_eq!(thread["stringArray"][markers["name"][0].as_u64().unwrap() as usize], json!("Paint"));
+    assert_eq!(markers["startTime"], json!([0.0625]));
+    assert_eq!(markers["endTime"], json!([0.25]));
+}
+
+#[test]
+fn test_profile_libs_and_addresses() {
+    let profile: Value = ::serde_json::from_str(&build_test_profile()).unwrap();
+    assert_eq!(profile["libs"].as_array().unwrap().len(), 1);
+    assert_eq!(profile["libs"][0]["debugName"], json!("libtest.so"));
+    // The library has no debug information, so the profiler should try.
+    assert_eq!(profile["meta"]["symbolicated"], json!(false));
+    let thread = &profile["threads"][0];
+    assert_eq!(thread["frameTable"]["address"], json!([0x0, 0x10, -1, -1]));
+    assert_eq!(thread["funcTable"]["resource"], json!([0, 0, -1, -1]));
+    assert_eq!(thread["resourceTable"]["lib"], json!([0]));
+    let func_names: Vec<&str> = thread["funcTable"]["name"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|name| thread["stringArray"][name.as_u64().unwrap() as usize].as_str().unwrap())
+        .collect();
+    assert_eq!(func_names, vec!["0x0", "0x10", "0x1020", "0x1030"]);
 }
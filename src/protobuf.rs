@@ -0,0 +1,96 @@
+/// Encodes protocol buffer messages, for the few messages we write. Fields
+/// are written in the order in which they're added, and, like in proto3,
+/// fields that have their default value are left out.
+pub struct ProtobufWriter {
+    buffer: Vec<u8>,
+}
+
+const WIRE_TYPE_VARINT: u64 = 0;
+const WIRE_TYPE_LENGTH_DELIMITED: u64 = 2;
+
+impl ProtobufWriter {
+    pub fn new() -> ProtobufWriter {
+        ProtobufWriter { buffer: Vec::new() }
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buffer.push((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+        self.buffer.push(value as u8);
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.varint((field << 3) | wire_type);
+    }
+
+    pub fn uint64(&mut self, field: u64, value: u64) {
+        if value != 0 {
+            self.key(field, WIRE_TYPE_VARINT);
+            self.varint(value);
+        }
+    }
+
+    /// Negative values take ten bytes, as in every protobuf implementation.
+    pub fn int64(&mut self, field: u64, value: i64) {
+        self.uint64(field, value as u64);
+    }
+
+    pub fn bool(&mut self, field: u64, value: bool) {
+        self.uint64(field, value as u64);
+    }
+
+    /// Writes a bytes or string field. Unlike the other fields, this is
+    /// written even if it's empty, because it may be an element of a
+    /// repeated field.
+    pub fn bytes(&mut self, field: u64, value: &[u8]) {
+        self.key(field, WIRE_TYPE_LENGTH_DELIMITED);
+        self.varint(value.len() as u64);
+        self.buffer.extend_from_slice(value);
+    }
+
+    pub fn message(&mut self, field: u64, message: &ProtobufWriter) {
+        self.bytes(field, &message.buffer);
+    }
+
+    pub fn packed_uint64(&mut self, field: u64, values: &[u64]) {
+        if values.is_empty() {
+            return;
+        }
+        let mut packed = ProtobufWriter::new();
+        for &value in values {
+            packed.varint(value);
+        }
+        self.bytes(field, &packed.buffer);
+    }
+
+    pub fn packed_int64(&mut self, field: u64, values: &[i64]) {
+        let values: Vec<u64> = values.iter().map(|&value| value as u64).collect();
+        self.packed_uint64(field, &values);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+#[test]
+fn test_protobuf_writer() {
+    let mut inner = ProtobufWriter::new();
+    inner.uint64(1, 150);
+    inner.bool(2, false);
+    let mut writer = ProtobufWriter::new();
+    writer.message(3, &inner);
+    writer.bytes(2, b"testing");
+    writer.packed_int64(4, &[3, 270, -1]);
+    assert_eq!(
+        writer.into_bytes(),
+        vec![
+            0x1a, 0x03, 0x08, 0x96, 0x01,
+            0x12, 0x07, b't', b'e', b's', b't', b'i', b'n', b'g',
+            0x22, 0x0d, 0x03, 0x8e, 0x02,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01,
+        ]
+    );
+}
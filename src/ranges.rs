@@ -1,3 +1,4 @@
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Ranges {
     r: Vec<(u64, u64)>,
 }
@@ -0,0 +1,286 @@
+/// Decides which line of a full set a cache evicts. Sets and ways are
+/// indexes; the cache tells the policy about every line it fills and every
+/// hit, and asks for a victim when a set is full.
+pub trait ReplacementPolicy {
+    fn on_hit(&mut self, set: usize, way: usize);
+    fn on_fill(&mut self, set: usize, way: usize);
+    /// The way to evict from the full set.
+    fn victim(&mut self, set: usize) -> usize;
+}
+
+/// The replacement policies that the cache simulation can use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplacementPolicyKind {
+    /// Least recently used, like cachegrind.
+    Lru,
+    /// Tree pseudo-LRU, which most CPUs use for their L1 caches.
+    Plru,
+    Random,
+    /// Static re-reference interval prediction with two bits per line, which
+    /// doesn't let lines that were only used once push out the others.
+    Srrip,
+    Fifo,
+}
+
+impl ReplacementPolicyKind {
+    pub fn parse(s: &str) -> Option<ReplacementPolicyKind> {
+        match s {
+            "lru" => Some(ReplacementPolicyKind::Lru),
+            "plru" => Some(ReplacementPolicyKind::Plru),
+            "random" => Some(ReplacementPolicyKind::Random),
+            "srrip" => Some(ReplacementPolicyKind::Srrip),
+            "fifo" => Some(ReplacementPolicyKind::Fifo),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ReplacementPolicyKind::Lru => "lru",
+            ReplacementPolicyKind::Plru => "plru",
+            ReplacementPolicyKind::Random => "random",
+            ReplacementPolicyKind::Srrip => "srrip",
+            ReplacementPolicyKind::Fifo => "fifo",
+        }
+    }
+
+    pub fn check(&self, assoc: u32) -> Result<(), String> {
+        if *self == ReplacementPolicyKind::Plru && !assoc.is_power_of_two() {
+            return Err(format!("PLRU needs the associativity to be a power of two, not {}.", assoc));
+        }
+        Ok(())
+    }
+
+    pub fn create(&self, sets: usize, assoc: usize) -> Box<dyn ReplacementPolicy> {
+        match *self {
+            ReplacementPolicyKind::Lru => Box::new(Lru::new(sets, assoc)),
+            ReplacementPolicyKind::Plru => Box::new(TreePlru::new(sets, assoc)),
+            ReplacementPolicyKind::Random => Box::new(Random::new(assoc)),
+            ReplacementPolicyKind::Srrip => Box::new(Srrip::new(sets, assoc)),
+            ReplacementPolicyKind::Fifo => Box::new(Fifo::new(sets, assoc)),
+        }
+    }
+}
+
+/// The way with the smallest timestamp in the set.
+fn oldest_way(timestamps: &[u64], set: usize, assoc: usize) -> usize {
+    let set_timestamps = &timestamps[set * assoc..(set + 1) * assoc];
+    (0..assoc).min_by_key(|&way| set_timestamps[way]).unwrap_or(0)
+}
+
+pub struct Lru {
+    assoc: usize,
+    clock: u64,
+    last_use: Vec<u64>,
+}
+
+impl Lru {
+    pub fn new(sets: usize, assoc: usize) -> Lru {
+        Lru {
+            assoc,
+            clock: 0,
+            last_use: vec![0; sets * assoc],
+        }
+    }
+}
+
+impl ReplacementPolicy for Lru {
+    fn on_hit(&mut self, set: usize, way: usize) {
+        self.clock += 1;
+        self.last_use[set * self.assoc + way] = self.clock;
+    }
+
+    fn on_fill(&mut self, set: usize, way: usize) {
+        self.on_hit(set, way);
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        oldest_way(&self.last_use, set, self.assoc)
+    }
+}
+
+pub struct Fifo {
+    assoc: usize,
+    clock: u64,
+    fill_time: Vec<u64>,
+}
+
+impl Fifo {
+    pub fn new(sets: usize, assoc: usize) -> Fifo {
+        Fifo {
+            assoc,
+            clock: 0,
+            fill_time: vec![0; sets * assoc],
+        }
+    }
+}
+
+impl ReplacementPolicy for Fifo {
+    fn on_hit(&mut self, _set: usize, _way: usize) {}
+
+    fn on_fill(&mut self, set: usize, way: usize) {
+        self.clock += 1;
+        self.fill_time[set * self.assoc + way] = self.clock;
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        oldest_way(&self.fill_time, set, self.assoc)
+    }
+}
+
+/// Evicts a random way, with a fixed seed so that runs are reproducible.
+pub struct Random {
+    assoc: usize,
+    state: u64,
+}
+
+impl Random {
+    pub fn new(assoc: usize) -> Random {
+        Random {
+            assoc,
+            state: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+impl ReplacementPolicy for Random {
+    fn on_hit(&mut self, _set: usize, _way: usize) {}
+
+    fn on_fill(&mut self, _set: usize, _way: usize) {}
+
+    fn victim(&mut self, _set: usize) -> usize {
+        // xorshift64
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state % self.assoc as u64) as usize
+    }
+}
+
+/// A binary tree over the ways of each set, whose nodes point to the half
+/// that was used less recently. Needs a power of two ways.
+pub struct TreePlru {
+    assoc: usize,
+    /// assoc - 1 nodes per set, in heap order. true means the right half
+    /// is the one to evict from.
+    nodes: Vec<bool>,
+}
+
+impl TreePlru {
+    pub fn new(sets: usize, assoc: usize) -> TreePlru {
+        TreePlru {
+            assoc,
+            nodes: vec![false; sets * (assoc - 1)],
+        }
+    }
+}
+
+impl ReplacementPolicy for TreePlru {
+    fn on_hit(&mut self, set: usize, way: usize) {
+        let nodes = &mut self.nodes[set * (self.assoc - 1)..(set + 1) * (self.assoc - 1)];
+        let (mut node, mut start, mut end) = (0, 0, self.assoc);
+        while end - start > 1 {
+            let middle = (start + end) / 2;
+            let is_left = way < middle;
+            // Point away from the way that was just used.
+            nodes[node] = is_left;
+            if is_left {
+                node = 2 * node + 1;
+                end = middle;
+            } else {
+                node = 2 * node + 2;
+                start = middle;
+            }
+        }
+    }
+
+    fn on_fill(&mut self, set: usize, way: usize) {
+        self.on_hit(set, way);
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        let nodes = &self.nodes[set * (self.assoc - 1)..(set + 1) * (self.assoc - 1)];
+        let (mut node, mut start, mut end) = (0, 0, self.assoc);
+        while end - start > 1 {
+            let middle = (start + end) / 2;
+            if nodes[node] {
+                node = 2 * node + 2;
+                start = middle;
+            } else {
+                node = 2 * node + 1;
+                end = middle;
+            }
+        }
+        start
+    }
+}
+
+const SRRIP_MAX_RRPV: u8 = 3;
+
+/// Every line has a re-reference prediction value: new lines are predicted
+/// to be re-referenced in a long time, lines that are hit in the near
+/// future, and the victim is a line that's predicted to be re-referenced in
+/// the distant future. If there is none, all lines of the set age.
+pub struct Srrip {
+    assoc: usize,
+    rrpv: Vec<u8>,
+}
+
+impl Srrip {
+    pub fn new(sets: usize, assoc: usize) -> Srrip {
+        Srrip {
+            assoc,
+            rrpv: vec![SRRIP_MAX_RRPV; sets * assoc],
+        }
+    }
+}
+
+impl ReplacementPolicy for Srrip {
+    fn on_hit(&mut self, set: usize, way: usize) {
+        self.rrpv[set * self.assoc + way] = 0;
+    }
+
+    fn on_fill(&mut self, set: usize, way: usize) {
+        self.rrpv[set * self.assoc + way] = SRRIP_MAX_RRPV - 1;
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        let rrpv = &mut self.rrpv[set * self.assoc..(set + 1) * self.assoc];
+        loop {
+            if let Some(way) = rrpv.iter().position(|&value| value == SRRIP_MAX_RRPV) {
+                return way;
+            }
+            for value in rrpv.iter_mut() {
+                *value += 1;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_replacement_policies() {
+    // Fill ways 0 to 3 of set 1, hit way 0, and see who goes first.
+    let victim_after_hit = |kind: ReplacementPolicyKind| {
+        let mut policy = kind.create(2, 4);
+        for way in 0..4 {
+            policy.on_fill(1, way);
+        }
+        policy.on_hit(1, 0);
+        policy.victim(1)
+    };
+    assert_eq!(victim_after_hit(ReplacementPolicyKind::Lru), 1);
+    assert_eq!(victim_after_hit(ReplacementPolicyKind::Fifo), 0);
+    // The root points to the right half, which was used before way 0, and
+    // in the right half, way 2 was used before way 3.
+    assert_eq!(victim_after_hit(ReplacementPolicyKind::Plru), 2);
+    // Ways 1 to 3 were never hit, so they age the same, and way 1 is the
+    // first of them.
+    assert_eq!(victim_after_hit(ReplacementPolicyKind::Srrip), 1);
+    assert!(victim_after_hit(ReplacementPolicyKind::Random) < 4);
+
+    assert_eq!(ReplacementPolicyKind::parse("srrip"), Some(ReplacementPolicyKind::Srrip));
+    assert_eq!(ReplacementPolicyKind::parse("mru"), None);
+    assert!(ReplacementPolicyKind::Plru.check(6).is_err());
+    assert!(ReplacementPolicyKind::Lru.check(6).is_ok());
+}
@@ -0,0 +1,291 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender};
+use hyper::method::Method;
+use hyper::net::Fresh;
+use hyper::server::{Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use serde_json::{self, Value};
+use stack_table::StackTable;
+use symbolication::to_io_error;
+
+const PROFILER_URL: &str = "https://profiler.firefox.com";
+
+/// A request to the symbolication endpoint. The stack table can't be shared
+/// between threads, so the requests are answered on the thread that owns it.
+struct SymbolicationJob {
+    request: String,
+    reply: Sender<Result<Value, String>>,
+}
+
+/// Percent-encodes everything but the unreserved characters of RFC 3986, so
+/// that the result can be a path segment or a query parameter.
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// The link that opens the profile at profile_url in the Firefox profiler,
+/// which gets symbols from symbol_server_url.
+fn profiler_link(profile_url: &str, symbol_server_url: &str) -> String {
+    format!(
+        "{}/from-url/{}/?symbolServer={}",
+        PROFILER_URL,
+        percent_encode(profile_url),
+        percent_encode(symbol_server_url)
+    )
+}
+
+fn send(mut response: Response<Fresh>, status: StatusCode, content_type: &str, body: &[u8]) {
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+    if let Err(e) = response.send(body) {
+        eprintln!("Couldn't send a response: {}", e);
+    }
+}
+
+/// Answers a request to the /symbolicate/v5 endpoint of the Mozilla
+/// symbolication API, which the Firefox profiler uses. Frames are described
+/// by the index of their library in the memory map and the address relative
+/// to the library. The log doesn't say where functions start, so functions
+/// are assumed to start at the lowest of the requested addresses at which
+/// they were found.
+fn symbolicate_v5(stack_table: &StackTable, request: &str) -> Result<Value, String> {
+    let request: Value = serde_json::from_str(request).map_err(|e| e.to_string())?;
+    let jobs = request["jobs"].as_array().ok_or("The request has no jobs.")?;
+    let results = jobs
+        .iter()
+        .map(|job| symbolicate_v5_job(stack_table, job))
+        .collect::<Result<Vec<Value>, String>>()?;
+    Ok(json!({ "results": results }))
+}
+
+fn symbolicate_v5_job(stack_table: &StackTable, job: &Value) -> Result<Value, String> {
+    let memory_map: Vec<(&str, &str)> = job["memoryMap"]
+        .as_array()
+        .ok_or("A job has no memoryMap.")?
+        .iter()
+        .map(|module| (module[0].as_str().unwrap_or(""), module[1].as_str().unwrap_or("")))
+        .collect();
+    let stacks: Vec<Vec<(usize, u64)>> = job["stacks"]
+        .as_array()
+        .ok_or("A job has no stacks.")?
+        .iter()
+        .map(|stack| {
+            stack
+                .as_array()
+                .map(|frames| {
+                    frames
+                        .iter()
+                        .filter_map(|frame| Some((frame[0].as_u64()? as usize, frame[1].as_u64()?)))
+                        .collect()
+                })
+                .unwrap_or_default()
+        })
+        .collect();
+
+    // Symbolicate every address once, and all addresses of a library at once.
+    let mut addresses_per_module: BTreeMap<usize, Vec<u64>> = BTreeMap::new();
+    for &(module, address) in stacks.iter().flat_map(|stack| stack.iter()) {
+        addresses_per_module.entry(module).or_default().push(address);
+    }
+    let mut found_modules = serde_json::Map::new();
+    let mut frame_infos = HashMap::new();
+    let mut function_start = HashMap::new();
+    for (module, mut addresses) in addresses_per_module {
+        let (debug_name, breakpad_id) = match memory_map.get(module) {
+            Some(&module) => module,
+            None => continue,
+        };
+        let lib = stack_table
+            .libs
+            .as_ref()
+            .and_then(|libs| libs.lib_for_debug_name_and_breakpad_id(debug_name, breakpad_id));
+        let mut found = false;
+        if let Some(lib) = lib {
+            addresses.sort();
+            addresses.dedup();
+            for (address, infos) in addresses.iter().zip(stack_table.symbolicate_addresses(lib, &addresses)) {
+                let infos = match infos {
+                    Some(infos) => infos,
+                    None => continue,
+                };
+                if let Some(function_name) = infos.first().map(|info| info.function_name.clone()) {
+                    if function_name != "??" {
+                        found = true;
+                        // The addresses are sorted, so the first one is the lowest.
+                        function_start.entry((module, function_name)).or_insert(*address);
+                    }
+                }
+                frame_infos.insert((module, *address), infos);
+            }
+        }
+        found_modules.insert(format!("{}/{}", debug_name, breakpad_id), Value::Bool(found));
+    }
+
+    let stacks: Vec<Value> = stacks
+        .iter()
+        .map(|stack| {
+            let frames: Vec<Value> = stack
+                .iter()
+                .enumerate()
+                .map(|(frame, &(module, address))| {
+                    let mut result = json!({
+                        "frame": frame,
+                        "module_offset": format!("0x{:x}", address),
+                    });
+                    if let Some(&(debug_name, _)) = memory_map.get(module) {
+                        result["module"] = json!(debug_name);
+                    }
+                    let infos = match frame_infos.get(&(module, address)) {
+                        Some(infos) if !infos.is_empty() && infos[0].function_name != "??" => infos,
+                        _ => return result,
+                    };
+                    let outer = &infos[0];
+                    let start = function_start[&(module, outer.function_name.clone())];
+                    result["function"] = json!(outer.function_name);
+                    result["function_offset"] = json!(format!("0x{:x}", address - start));
+                    if outer.file_path_str != "??" {
+                        result["file"] = json!(outer.file_path_str);
+                    }
+                    if outer.line_number != 0 {
+                        result["line"] = json!(outer.line_number);
+                    }
+                    // The API lists inlined functions innermost first.
+                    let inlines: Vec<Value> = infos[1..]
+                        .iter()
+                        .rev()
+                        .map(|info| {
+                            json!({
+                                "function": info.function_name,
+                                "file": info.file_path_str,
+                                "line": info.line_number,
+                            })
+                        })
+                        .collect();
+                    if !inlines.is_empty() {
+                        result["inlines"] = json!(inlines);
+                    }
+                    result
+                })
+                .collect();
+            json!(frames)
+        })
+        .collect();
+    Ok(json!({
+        "stacks": stacks,
+        "found_modules": found_modules,
+    }))
+}
+
+/// Serves the given profiles, keyed by file name, on localhost, with the
+/// CORS headers that the Firefox profiler needs for loading them, and prints
+/// links that open them in the profiler. The profiler gets symbols from the
+/// /symbolicate/v5 endpoint, which uses the stack table's symbolicator and
+/// symbol cache. Runs until the process is killed.
+pub fn serve_profiles(profiles: BTreeMap<String, Vec<u8>>, stack_table: &StackTable, port: u16) -> Result<(), io::Error> {
+    let (job_sender, job_receiver) = channel();
+    let job_sender = Mutex::new(job_sender);
+    let file_names: Vec<String> = profiles.keys().cloned().collect();
+    let server = Server::http(("127.0.0.1", port)).map_err(to_io_error)?;
+    let listening = server
+        .handle(move |mut request: Request, mut response: Response<Fresh>| {
+            {
+                let headers = response.headers_mut();
+                headers.set_raw("Access-Control-Allow-Origin", vec![b"*".to_vec()]);
+                headers.set_raw("Access-Control-Allow-Methods", vec![b"GET, POST, OPTIONS".to_vec()]);
+                headers.set_raw("Access-Control-Allow-Headers", vec![b"Content-Type".to_vec()]);
+            }
+            let path = match request.uri {
+                RequestUri::AbsolutePath(ref path) => path.split('?').next().unwrap_or("").to_owned(),
+                _ => String::new(),
+            };
+            match (&request.method, path.as_str()) {
+                (&Method::Options, _) => send(response, StatusCode::NoContent, "text/plain", b""),
+                (&Method::Get, path) => match profiles.get(path.trim_start_matches('/')) {
+                    Some(profile) => send(response, StatusCode::Ok, "application/json", profile),
+                    None => send(response, StatusCode::NotFound, "text/plain", b"Not found"),
+                },
+                (&Method::Post, "/symbolicate/v5") => {
+                    let mut body = String::new();
+                    if let Err(e) = request.read_to_string(&mut body) {
+                        return send(response, StatusCode::BadRequest, "text/plain", e.to_string().as_bytes());
+                    }
+                    let (reply_sender, reply_receiver) = channel();
+                    let job = SymbolicationJob {
+                        request: body,
+                        reply: reply_sender,
+                    };
+                    let sent = job_sender.lock().map(|job_sender| job_sender.send(job).is_ok());
+                    match (sent, reply_receiver.recv()) {
+                        (Ok(true), Ok(Ok(result))) => send(
+                            response,
+                            StatusCode::Ok,
+                            "application/json",
+                            result.to_string().as_bytes(),
+                        ),
+                        (Ok(true), Ok(Err(e))) => send(response, StatusCode::BadRequest, "text/plain", e.as_bytes()),
+                        _ => send(response, StatusCode::InternalServerError, "text/plain", b"Symbolication failed"),
+                    }
+                }
+                _ => send(response, StatusCode::NotFound, "text/plain", b"Not found"),
+            }
+        })
+        .map_err(to_io_error)?;
+
+    let server_url = format!("http://{}", listening.socket);
+    for file_name in file_names {
+        let profile_url = format!("{}/{}", server_url, file_name);
+        println!("Serving {}", profile_url);
+        println!("Open it in the Firefox profiler: {}", profiler_link(&profile_url, &server_url));
+    }
+    println!("Press Ctrl+C to stop the server.");
+    for job in job_receiver {
+        let _ = job.reply.send(symbolicate_v5(stack_table, &job.request));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_profiler_link() {
+    assert_eq!(percent_encode("a-b_c.d~e f/g:h"), "a-b_c.d~e%20f%2Fg%3Ah");
+    assert_eq!(
+        profiler_link("http://127.0.0.1:3000/cache_line_usage_profile.json", "http://127.0.0.1:3000"),
+        "https://profiler.firefox.com/from-url/http%3A%2F%2F127.0.0.1%3A3000%2Fcache_line_usage_profile.json/\
+         ?symbolServer=http%3A%2F%2F127.0.0.1%3A3000"
+    );
+}
+
+#[test]
+fn test_symbolicate_v5_unknown_library() {
+    let mut stack_table = StackTable::new();
+    let libs = ::shared_libraries::SharedLibraries::from_json_string(
+        r#"[{"start": 4096, "end": 8192, "offset": 0, "name": "libtest.so", "path": "/nonexistent/libtest.so",
+             "debugName": "libtest.so", "debugPath": "/nonexistent/libtest.so", "breakpadId": "ABCD", "arch": ""}]"#
+            .to_owned(),
+    ).unwrap();
+    stack_table.set_libs(libs);
+    let request = r#"{"jobs": [{"memoryMap": [["libtest.so", "ABCD"], ["libother.so", "0"]],
+                                "stacks": [[[0, 16], [1, 32]]]}]}"#;
+    let response = symbolicate_v5(&stack_table, request).unwrap();
+    let result = &response["results"][0];
+    assert_eq!(
+        result["stacks"],
+        json!([[
+            {"frame": 0, "module_offset": "0x10", "module": "libtest.so"},
+            {"frame": 1, "module_offset": "0x20", "module": "libother.so"}
+        ]])
+    );
+    assert_eq!(result["found_modules"], json!({"libtest.so/ABCD": false, "libother.so/0": false}));
+    assert!(symbolicate_v5(&stack_table, "{}").is_err());
+}
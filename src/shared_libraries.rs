@@ -15,7 +15,7 @@ pub struct SharedLibrary {
     pub arch: String,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SharedLibraries {
     l: Vec<SharedLibrary>,
 }
@@ -26,6 +26,12 @@ impl SharedLibraries {
     }
 
     pub fn lib_for_address<'a>(&'a self, addr: u64) -> Option<&'a SharedLibrary> {
+        self.lib_index_for_address(addr).map(|index| &self.l[index])
+    }
+
+    /// The index of the library containing addr, in the order of the libraries'
+    /// start addresses.
+    pub fn lib_index_for_address(&self, addr: u64) -> Option<usize> {
         if let Ok(index) = self.l
                .binary_search_by(|ref lib| {
             // Return a statement about lib. (Is lib less / equal / greater than addr?)
@@ -39,9 +45,21 @@ impl SharedLibraries {
                 Ordering::Greater
             }
         }) {
-            Some(&self.l[index])
+            Some(index)
         } else {
             None
         }
     }
+
+    pub fn lib_at_index(&self, index: usize) -> &SharedLibrary {
+        &self.l[index]
+    }
+
+    /// The library with the given debug name and breakpad ID, which together
+    /// identify one build of a library.
+    pub fn lib_for_debug_name_and_breakpad_id(&self, debug_name: &str, breakpad_id: &str) -> Option<&SharedLibrary> {
+        self.l
+            .iter()
+            .find(|lib| lib.debug_name == debug_name && lib.breakpad_id == breakpad_id)
+    }
 }
@@ -1,9 +1,13 @@
-use std::collections::{HashMap, HashSet};
-use addr2line_cmd::{get_addr2line_stack, get_addr2line_symbols_with_inline, StackFrameInfo};
-use shared_libraries::SharedLibraries;
+use std::collections::{BTreeSet, HashMap};
+use std::cell::RefCell;
+use std::rc::Rc;
+use addr2line_cmd::StackFrameInfo;
+use shared_libraries::{SharedLibraries, SharedLibrary};
+use symbolication::{DwarfSymbolicator, Symbolicator};
+use symbol_cache::SymbolCache;
 use std::iter;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct StackEntry {
     pub parent_stack: usize,
     pub frame: usize,
@@ -22,6 +26,8 @@ impl<'b> StackTableConverter<'b> {
         if let Some(libs) = stack_table.libs.clone() {
             new_stack_table.set_libs(libs);
         }
+        new_stack_table.symbolicator = stack_table.symbolicator.clone();
+        new_stack_table.symbol_cache = stack_table.symbol_cache.clone();
 
         StackTableConverter {
             old_frame_to_new_frame: HashMap::new(),
@@ -75,11 +81,27 @@ impl<'b> StackTableConverter<'b> {
     }
 }
 
-#[derive(Clone)]
+fn default_symbolicator() -> Rc<RefCell<Box<dyn Symbolicator>>> {
+    Rc::new(RefCell::new(Box::new(DwarfSymbolicator::new())))
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct StackTable {
     pub frames: Vec<(u64, Option<Vec<StackFrameInfo>>)>,
     pub stacks: Vec<StackEntry>,
     pub libs: Option<SharedLibraries>,
+    /// Shared between clones of this table, so that debug information that
+    /// was loaded once can be reused.
+    #[serde(skip, default = "default_symbolicator")]
+    symbolicator: Rc<RefCell<Box<dyn Symbolicator>>>,
+    #[serde(skip)]
+    symbol_cache: Option<Rc<RefCell<SymbolCache>>>,
+}
+
+impl Default for StackTable {
+    fn default() -> StackTable {
+        StackTable::new()
+    }
 }
 
 impl StackTable {
@@ -88,9 +110,19 @@ impl StackTable {
             frames: Vec::new(),
             stacks: Vec::new(),
             libs: None,
+            symbolicator: default_symbolicator(),
+            symbol_cache: None,
         }
     }
 
+    pub fn set_symbolicator(&mut self, symbolicator: Box<dyn Symbolicator>) {
+        self.symbolicator = Rc::new(RefCell::new(symbolicator));
+    }
+
+    pub fn set_symbol_cache(&mut self, symbol_cache: SymbolCache) {
+        self.symbol_cache = Some(Rc::new(RefCell::new(symbol_cache)));
+    }
+
     pub fn add_frame(&mut self, index: usize, address: u64) {
         assert_eq!(index, self.frames.len(), "unexpected frame index");
         self.frames.push((address, None));
@@ -112,9 +144,19 @@ impl StackTable {
         });
     }
 
+    /// Drops all frames and stacks that were added after the first frame_count
+    /// frames and stack_count stacks, i.e. restores the table to the state it
+    /// had at an earlier point in the log.
+    pub fn truncate(&mut self, frame_count: usize, stack_count: usize) {
+        self.frames.truncate(frame_count);
+        self.stacks.truncate(stack_count);
+    }
+
+    /// Converts the stacks in ascending order, so that the reduced table only
+    /// depends on which stacks are used.
     pub fn create_reduced_table_containing_stacks(
         &self,
-        stacks: &HashSet<usize>,
+        stacks: &BTreeSet<usize>,
     ) -> (StackTable, HashMap<usize, usize>) {
         println!("creating reduced stack table with {} stacks.", stacks.len());
         let mut converter = StackTableConverter::new(self);
@@ -125,20 +167,26 @@ impl StackTable {
         converter.into_new_stack_table()
     }
 
-    pub fn resolve_inline_symbols(&mut self) -> Vec<usize> {
+    /// Gives every inlined function its own frame, so that each frame has at
+    /// most one StackFrameInfo. Returns the new stack for each old stack, and
+    /// the inline depth of each frame, which is 0 for the outermost function
+    /// at an address.
+    pub fn resolve_inline_symbols(&mut self) -> (Vec<usize>, Vec<usize>) {
         // Every frame that has multiple StackFrameInfos will now have multiple frames,
         // and every stack that uses such a frame needs to be changed to multiple stacks
         // and all references to that stack need to be changed to point to the leaf stack.
         let mut old_frame_to_additional_frames: HashMap<usize, Vec<usize>> = HashMap::new();
         let mut frames_to_add: Vec<(u64, Option<Vec<StackFrameInfo>>)> = Vec::new();
         let old_frames_len = self.frames.len();
+        let mut inline_depths = vec![0; old_frames_len];
         for (frame, &mut (address, ref mut frame_infos)) in self.frames.iter_mut().enumerate() {
             if let &mut Some(ref mut frame_infos) = frame_infos {
                 if frame_infos.len() > 1 {
                     let mut additional_frames_for_this_frame = Vec::new();
-                    for frame_info in frame_infos.drain(1..) {
+                    for (i, frame_info) in frame_infos.drain(1..).enumerate() {
                         additional_frames_for_this_frame.push(old_frames_len + frames_to_add.len());
-                        frames_to_add.push((address, Some(vec![frame_info])))
+                        frames_to_add.push((address, Some(vec![frame_info])));
+                        inline_depths.push(i + 1);
                     }
                     old_frame_to_additional_frames.insert(frame, additional_frames_for_this_frame);
                 }
@@ -171,7 +219,7 @@ impl StackTable {
         }
 
         self.stacks = new_stacks;
-        old_stack_to_new_stack
+        (old_stack_to_new_stack, inline_depths)
     }
 
     fn frame_index_list_for_stack(&self, stack: usize) -> Vec<usize> {
@@ -188,12 +236,51 @@ impl StackTable {
         result
     }
 
+    /// Looks up function names and source lines for the given addresses,
+    /// which are relative to the library's start, outermost function first.
+    /// Addresses that are in the symbol cache don't need to go through the
+    /// symbolicator. If the symbolicator fails, all addresses that weren't in
+    /// the cache get None.
+    pub fn symbolicate_addresses(&self, lib: &SharedLibrary, addresses: &[u64]) -> Vec<Option<Vec<StackFrameInfo>>> {
+        let mut results = Vec::with_capacity(addresses.len());
+        let mut uncached = Vec::new();
+        for &address in addresses {
+            let cached = self.symbol_cache
+                .as_ref()
+                .and_then(|symbol_cache| symbol_cache.borrow_mut().get(lib, address));
+            if cached.is_none() {
+                uncached.push((results.len(), address));
+            }
+            results.push(cached);
+        }
+        if uncached.is_empty() {
+            return results;
+        }
+        let uncached_addresses: Vec<u64> = uncached.iter().map(|&(_, address)| address).collect();
+        if let Ok(frame_infos) = self.symbolicator.borrow_mut().symbolicate_addresses(lib, &uncached_addresses) {
+            for ((index, address), frame_info) in uncached.into_iter().zip(frame_infos) {
+                if let Some(ref symbol_cache) = self.symbol_cache {
+                    symbol_cache.borrow_mut().insert(lib, address, frame_info.clone());
+                }
+                results[index] = Some(frame_info);
+            }
+        }
+        results
+    }
+
+    /// Looks up function names and source lines for the given frames, if
+    /// they don't have them yet. Each frame ends up with the list of its
+    /// (possibly inlined) functions, outermost first.
     pub fn symbolicate_frames<T>(&mut self, frames: T)
         where T: iter::Iterator<Item = usize> {
+        let mut symbolicated_frames = Vec::new();
         if let &Some(ref libs) = &self.libs {
             let mut frames_by_lib_index = HashMap::new();
             for frame in frames {
-                let (address, _) = self.frames[frame];
+                let (address, ref frame_info) = self.frames[frame];
+                if frame_info.is_some() {
+                    continue;
+                }
                 if let Some(lib) = libs.lib_for_address(address) {
                     frames_by_lib_index
                         .entry(lib)
@@ -202,23 +289,21 @@ impl StackTable {
                 }
             }
             for (lib, frames_with_addresses) in frames_by_lib_index.into_iter() {
-                if let Ok(symbolicated_addresses) =
-                    get_addr2line_symbols_with_inline(
-                        &lib.debug_path,
-                        &frames_with_addresses
-                            .iter()
-                            .map(|&(_, address)| address)
-                            .collect(),
-                    )
-                {
-                    for (i, frame_info) in symbolicated_addresses.into_iter().enumerate() {
-                        let (frame, _) = frames_with_addresses[i];
-                        self.frames[frame].1 = Some(frame_info);
+                let addresses: Vec<u64> = frames_with_addresses
+                    .iter()
+                    .map(|&(_, address)| address)
+                    .collect();
+                let frame_infos = self.symbolicate_addresses(lib, &addresses);
+                for (&(frame, _), frame_info) in frames_with_addresses.iter().zip(frame_infos) {
+                    if frame_info.is_some() {
+                        symbolicated_frames.push((frame, frame_info));
                     }
                 }
             }
         }
-
+        for (frame, frame_info) in symbolicated_frames {
+            self.frames[frame].1 = frame_info;
+        }
     }
 
     pub fn symbolicate_all(&mut self) {
@@ -227,60 +312,46 @@ impl StackTable {
         self.symbolicate_frames(0..frame_count);
     }
 
-    pub fn print_stack(&mut self, stack: usize, indent: usize) {
-        for frame in self.frame_index_list_for_stack(stack) {
-            let &mut (address, ref mut stack_frame_info) = &mut self.frames[frame];
+    /// Describes the frames of the given stack, innermost first, with one
+    /// line per (possibly inlined) function. Frames that can't be symbolicated
+    /// are described by their address.
+    pub fn describe_stack(&mut self, stack: usize) -> Vec<String> {
+        let frames = self.frame_index_list_for_stack(stack);
+        self.symbolicate_frames(frames.iter().cloned());
+        let mut lines = Vec::new();
+        for frame in frames {
+            let &(address, ref stack_frame_info) = &self.frames[frame];
             if let &Some(ref libs) = &self.libs {
                 if let Some(lib) = libs.lib_for_address(address) {
                     let relative_address = address - lib.start;
 
-                    if let None = *stack_frame_info {
-                        if let Ok(mut stack_fragment) =
-                            get_addr2line_stack(&lib.debug_path, relative_address)
-                        {
-                            stack_fragment.reverse();
-                            *stack_frame_info = Some(stack_fragment);
-                        }
-                    }
-
-                    if let &mut Some(ref stack_fragment) = stack_frame_info {
+                    if let &Some(ref stack_fragment) = stack_frame_info {
+                        // Innermost first, like the rest of the stack.
                         for &StackFrameInfo {
                             ref function_name,
                             ref file_path_str,
                             ref line_number,
-                        } in stack_fragment
+                        } in stack_fragment.iter().rev()
                         {
-                            println!(
-                                "{e:indent$}{} ({}:{})",
-                                function_name,
-                                file_path_str,
-                                line_number,
-                                e = "",
-                                indent = indent
-                            );
+                            lines.push(format!("{} ({}:{})", function_name, file_path_str, line_number));
                         }
                     } else {
-                        println!(
-                            "{e:indent$}0x{:016x} [{} + 0x{:x}]",
-                            address,
-                            lib.name,
-                            relative_address,
-                            e = "",
-                            indent = indent
-                        );
+                        lines.push(format!("0x{:016x} [{} + 0x{:x}]", address, lib.name, relative_address));
                     }
                 } else {
-                    println!(
-                        "{e:indent$}0x{:016x} [unknown binary]",
-                        address,
-                        e = "",
-                        indent = indent
-                    );
+                    lines.push(format!("0x{:016x} [unknown binary]", address));
                 }
             } else {
-                println!("{e:indent$}0x{:016x}", address, e = "", indent = indent);
+                lines.push(format!("0x{:016x}", address));
             }
         }
+        lines
+    }
+
+    pub fn print_stack(&mut self, stack: usize, indent: usize) {
+        for line in self.describe_stack(stack) {
+            println!("{e:indent$}{}", line, e = "", indent = indent);
+        }
     }
 
     pub fn set_libs(&mut self, libs: SharedLibraries) {
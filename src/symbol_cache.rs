@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use serde_json;
+use addr2line_cmd::StackFrameInfo;
+use shared_libraries::SharedLibrary;
+
+/// The symbols we know for one build of one library, keyed by the address
+/// relative to the library's start.
+struct LibrarySymbols {
+    path: PathBuf,
+    symbols: BTreeMap<u64, Vec<StackFrameInfo>>,
+    has_unsaved_changes: bool,
+}
+
+impl LibrarySymbols {
+    fn load(path: PathBuf) -> LibrarySymbols {
+        let symbols = match File::open(&path) {
+            Ok(file) => match serde_json::from_reader(BufReader::new(file)) {
+                Ok(symbols) => symbols,
+                Err(e) => {
+                    eprintln!("Ignoring the symbol cache file {}: {}", path.display(), e);
+                    BTreeMap::new()
+                }
+            },
+            Err(_) => BTreeMap::new(),
+        };
+        LibrarySymbols {
+            path,
+            symbols,
+            has_unsaved_changes: false,
+        }
+    }
+
+    fn save(&mut self) -> Result<(), io::Error> {
+        if !self.has_unsaved_changes {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first so that a crash can't leave a
+        // truncated cache file behind.
+        let temp_path = self.path.with_extension("json.tmp");
+        {
+            let writer = BufWriter::new(File::create(&temp_path)?);
+            serde_json::to_writer(writer, &self.symbols)?;
+        }
+        fs::rename(&temp_path, &self.path)?;
+        self.has_unsaved_changes = false;
+        Ok(())
+    }
+}
+
+/// Remembers symbolication results across runs. Results are stored per
+/// library build, identified by its breakpad ID, in
+/// <dir>/<debug name>/<breakpad ID>.json. Libraries without a breakpad ID
+/// are not cached. Changes are written back to disk when the cache is saved
+/// or dropped.
+pub struct SymbolCache {
+    dir: PathBuf,
+    libs: HashMap<(String, String), LibrarySymbols>,
+}
+
+impl SymbolCache {
+    pub fn new(dir: &Path) -> SymbolCache {
+        SymbolCache {
+            dir: dir.to_owned(),
+            libs: HashMap::new(),
+        }
+    }
+
+    /// $XDG_CACHE_HOME/cache-log-parser/symbols, or
+    /// ~/.cache/cache-log-parser/symbols if XDG_CACHE_HOME isn't set.
+    pub fn default_dir() -> Option<PathBuf> {
+        let cache_dir = match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+        };
+        Some(cache_dir.join("cache-log-parser").join("symbols"))
+    }
+
+    fn symbols_for_lib(&mut self, lib: &SharedLibrary) -> Option<&mut LibrarySymbols> {
+        if lib.breakpad_id.is_empty() {
+            return None;
+        }
+        let dir = &self.dir;
+        let debug_name = Path::new(&lib.debug_name)
+            .file_name()
+            .map(|name| name.to_owned())
+            .unwrap_or_else(|| "unknown".into());
+        Some(
+            self.libs
+                .entry((lib.debug_name.clone(), lib.breakpad_id.clone()))
+                .or_insert_with(|| {
+                    LibrarySymbols::load(dir.join(debug_name).join(format!("{}.json", lib.breakpad_id)))
+                }),
+        )
+    }
+
+    pub fn get(&mut self, lib: &SharedLibrary, relative_address: u64) -> Option<Vec<StackFrameInfo>> {
+        self.symbols_for_lib(lib)?
+            .symbols
+            .get(&relative_address)
+            .cloned()
+    }
+
+    /// Addresses that couldn't be symbolicated, i.e. whose frames are all
+    /// "??", aren't cached, so that they're tried again next time.
+    pub fn insert(&mut self, lib: &SharedLibrary, relative_address: u64, frame_infos: Vec<StackFrameInfo>) {
+        if frame_infos.iter().all(|frame_info| frame_info.function_name == "??") {
+            return;
+        }
+        if let Some(lib_symbols) = self.symbols_for_lib(lib) {
+            lib_symbols.symbols.insert(relative_address, frame_infos);
+            lib_symbols.has_unsaved_changes = true;
+        }
+    }
+
+    pub fn save(&mut self) -> Result<(), io::Error> {
+        for lib_symbols in self.libs.values_mut() {
+            lib_symbols.save()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SymbolCache {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            eprintln!("Couldn't save the symbol cache in {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+#[test]
+fn test_symbol_cache_round_trip() {
+    let dir = env::temp_dir().join(format!("cache-log-parser-test-symbols-{}", ::std::process::id()));
+    let lib = SharedLibrary {
+        start: 0x1000,
+        end: 0x2000,
+        offset: 0,
+        name: "libxul.so".to_owned(),
+        path: "/build/libxul.so".to_owned(),
+        debug_name: "libxul.so".to_owned(),
+        debug_path: "/build/libxul.so".to_owned(),
+        breakpad_id: "ABCDEF0123456789ABCDEF01234567890".to_owned(),
+        arch: "".to_owned(),
+    };
+    let other_build = SharedLibrary {
+        breakpad_id: "00000000000000000000000000000000".to_owned(),
+        ..lib.clone()
+    };
+    let frame_infos = vec![
+        StackFrameInfo {
+            function_name: "outer".to_owned(),
+            file_path_str: "outer.cpp".to_owned(),
+            line_number: 12,
+        },
+        StackFrameInfo {
+            function_name: "inner".to_owned(),
+            file_path_str: "inner.h".to_owned(),
+            line_number: 3,
+        },
+    ];
+    {
+        let mut cache = SymbolCache::new(&dir);
+        assert_eq!(cache.get(&lib, 0x10), None);
+        cache.insert(&lib, 0x10, frame_infos.clone());
+        let unknown_frame = StackFrameInfo {
+            function_name: "??".to_owned(),
+            file_path_str: "??".to_owned(),
+            line_number: 0,
+        };
+        cache.insert(&lib, 0x30, vec![unknown_frame]);
+    }
+    let mut cache = SymbolCache::new(&dir);
+    assert_eq!(cache.get(&lib, 0x10), Some(frame_infos));
+    assert_eq!(cache.get(&lib, 0x20), None);
+    assert_eq!(cache.get(&lib, 0x30), None);
+    assert_eq!(cache.get(&other_build, 0x10), None);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,145 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use addr2line::{self, Loader};
+use addr2line_cmd::{get_addr2line_symbols_with_inline, StackFrameInfo};
+use shared_libraries::SharedLibrary;
+
+/// Something that can map library-relative addresses to functions and source
+/// lines. For each address, the returned frames start with the outermost
+/// function and end with the innermost inlined function.
+pub trait Symbolicator {
+    fn symbolicate_addresses(
+        &mut self,
+        lib: &SharedLibrary,
+        addresses: &[u64],
+    ) -> Result<Vec<Vec<StackFrameInfo>>, io::Error>;
+}
+
+/// Runs the addr2line command line tool from binutils.
+pub struct Addr2lineCommand;
+
+impl Symbolicator for Addr2lineCommand {
+    fn symbolicate_addresses(
+        &mut self,
+        lib: &SharedLibrary,
+        addresses: &[u64],
+    ) -> Result<Vec<Vec<StackFrameInfo>>, io::Error> {
+        get_addr2line_symbols_with_inline(&lib.debug_path, &addresses.to_vec())
+    }
+}
+
+/// Reads the DWARF debug information of each library in-process, using the
+/// addr2line crate. Every library is only loaded once.
+#[derive(Default)]
+pub struct DwarfSymbolicator {
+    loaders: HashMap<String, Option<Loader>>,
+}
+
+pub fn to_io_error<E: ToString>(e: E) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+fn unknown_frame() -> StackFrameInfo {
+    StackFrameInfo {
+        function_name: "??".to_owned(),
+        file_path_str: "??".to_owned(),
+        line_number: 0,
+    }
+}
+
+fn symbolicate_address(loader: &Loader, address: u64) -> Result<Vec<StackFrameInfo>, io::Error> {
+    let probe = loader.relative_address_base() + address;
+    let mut frame_infos = Vec::new();
+    let mut frames = loader.find_frames(probe).map_err(to_io_error)?;
+    while let Some(frame) = frames.next().map_err(to_io_error)? {
+        let function_name = match frame.function {
+            Some(ref function) => function.demangle().map_err(to_io_error)?.into_owned(),
+            None => "??".to_owned(),
+        };
+        let (file_path_str, line_number) = match frame.location {
+            Some(ref location) => (
+                location.file.unwrap_or("??").to_owned(),
+                location.line.unwrap_or(0) as usize,
+            ),
+            None => ("??".to_owned(), 0),
+        };
+        frame_infos.push(StackFrameInfo {
+            function_name,
+            file_path_str,
+            line_number,
+        });
+    }
+    if frame_infos.is_empty() {
+        // No line information for this address; fall back to the symbol table.
+        let mut frame_info = unknown_frame();
+        if let Some(symbol) = loader.find_symbol(probe) {
+            frame_info.function_name = addr2line::demangle_auto(Cow::from(symbol), None).into_owned();
+        }
+        frame_infos.push(frame_info);
+    }
+    // find_frames returns the innermost frame first.
+    frame_infos.reverse();
+    Ok(frame_infos)
+}
+
+impl DwarfSymbolicator {
+    pub fn new() -> DwarfSymbolicator {
+        DwarfSymbolicator { loaders: HashMap::new() }
+    }
+}
+
+impl Symbolicator for DwarfSymbolicator {
+    fn symbolicate_addresses(
+        &mut self,
+        lib: &SharedLibrary,
+        addresses: &[u64],
+    ) -> Result<Vec<Vec<StackFrameInfo>>, io::Error> {
+        let loader = self.loaders
+            .entry(lib.debug_path.clone())
+            .or_insert_with(|| match Loader::new(&lib.debug_path) {
+                Ok(loader) => Some(loader),
+                Err(e) => {
+                    eprintln!("Couldn't load debug information from {}: {}", lib.debug_path, e);
+                    None
+                }
+            });
+        match *loader {
+            // An address that can't be symbolicated only loses its own
+            // frames, not those of the whole library.
+            Some(ref loader) => Ok(addresses
+                .iter()
+                .map(|&address| match symbolicate_address(loader, address) {
+                    Ok(frame_infos) => frame_infos,
+                    Err(e) => {
+                        eprintln!("Couldn't symbolicate {:#x} in {}: {}", address, lib.debug_path, e);
+                        vec![unknown_frame()]
+                    }
+                })
+                .collect()),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no debug information for {}", lib.debug_path),
+            )),
+        }
+    }
+}
+
+#[test]
+fn test_dwarf_symbolicator_missing_library() {
+    let lib = SharedLibrary {
+        start: 0x1000,
+        end: 0x2000,
+        offset: 0,
+        name: "libnonexistent.so".to_owned(),
+        path: "/nonexistent/libnonexistent.so".to_owned(),
+        debug_name: "libnonexistent.so".to_owned(),
+        debug_path: "/nonexistent/libnonexistent.so".to_owned(),
+        breakpad_id: "0".to_owned(),
+        arch: "".to_owned(),
+    };
+    let mut symbolicator = DwarfSymbolicator::new();
+    assert!(symbolicator.symbolicate_addresses(&lib, &[0x10]).is_err());
+    assert!(symbolicator.symbolicate_addresses(&lib, &[0x20]).is_err());
+    assert_eq!(symbolicator.loaders.len(), 1);
+}